@@ -0,0 +1,398 @@
+// Deny and allow lists of hbbs, denylist.txt and allowlist.txt in its working
+// directory. Each line is `<ip|cidr|id> [<expiry>]` as in hbbr's lists, where
+// an ID may contain `*` (any run of characters) and `?` (one character).
+// An IP or ID matching the deny list is refused unless it also matches the
+// allow list, so allow entries carve exceptions out of deny entries, e.g.
+// `10.0.0.0/8` denied but `10.1.2.3` allowed, or `*` denied but `acme-*`
+// allowed. IPs are checked against IP entries and IDs against ID entries.
+
+use crate::ip_list::{self, Edit, IpList};
+use hbb_common::{log, tokio};
+use std::{collections::HashMap, net::IpAddr, sync::RwLock};
+
+pub(crate) const DENYLIST_FILE: &str = "denylist.txt";
+pub(crate) const ALLOWLIST_FILE: &str = "allowlist.txt";
+const MAX_ID_LEN: usize = 100;
+
+lazy_static::lazy_static! {
+    static ref DENY: RwLock<AccessList> = Default::default();
+    static ref ALLOW: RwLock<AccessList> = Default::default();
+    // held while an edit is written to a list file
+    static ref SAVING: tokio::sync::Mutex<()> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum List {
+    Deny,
+    Allow,
+}
+
+impl List {
+    pub(crate) const ALL: [List; 2] = [List::Deny, List::Allow];
+
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "deny" => Some(List::Deny),
+            "allow" => Some(List::Allow),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn file(self) -> &'static str {
+        match self {
+            List::Deny => DENYLIST_FILE,
+            List::Allow => ALLOWLIST_FILE,
+        }
+    }
+
+    fn lock(self) -> &'static RwLock<AccessList> {
+        match self {
+            List::Deny => &DENY,
+            List::Allow => &ALLOW,
+        }
+    }
+}
+
+#[derive(Default)]
+struct AccessList {
+    ips: IpList,
+    ids: HashMap<String, Option<u64>>, // ID pattern -> expiry
+}
+
+/// Whether `s` can be an ID pattern: letters, digits, `-`, `_` and wildcards.
+fn is_id_pattern(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= MAX_ID_LEN
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*' | '?'))
+}
+
+/// Matches `id` against `pattern` with `*` and `?` wildcards.
+fn glob_match(pattern: &str, id: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), id.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    // position after the last `*` in the pattern, and where it matched up to
+    let mut star = None;
+    while j < s.len() {
+        if i < p.len() && (p[i] == b'?' || p[i] == s[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == b'*' {
+            i += 1;
+            star = Some((i, j));
+        } else if let Some((si, sj)) = star {
+            i = si;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|x| *x == b'*')
+}
+
+/// The key of an entry in the files: a range as in hbbr's lists, or an ID
+/// pattern as is.
+pub(crate) fn entry_key(s: &str) -> Option<String> {
+    ip_list::net_key(s).or_else(|| is_id_pattern(s).then(|| s.to_owned()))
+}
+
+/// Parses `|` separated entries to their keys.
+pub(crate) fn parse_entries<'a>(
+    entries: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<String>, String> {
+    entries
+        .into_iter()
+        .map(|x| entry_key(x).ok_or_else(|| format!("invalid IP, CIDR or ID: {x}")))
+        .collect()
+}
+
+impl AccessList {
+    fn parse(content: &str) -> Self {
+        let mut list = Self::default();
+        for line in content.lines() {
+            let mut fds = line.split_whitespace();
+            let Some(entry) = fds.next().filter(|x| !x.starts_with('#')) else {
+                continue;
+            };
+            let expires = fds.next().and_then(ip_list::parse_time);
+            if !list.insert(entry, expires) {
+                log::warn!("Invalid IP, CIDR or ID: {}", entry);
+            }
+        }
+        list
+    }
+
+    fn insert(&mut self, entry: &str, expires: Option<u64>) -> bool {
+        if let Some(net) = ip_list::parse_net(entry) {
+            self.ips.insert(net, expires);
+        } else if is_id_pattern(entry) {
+            self.ids.insert(entry.to_owned(), expires);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn remove(&mut self, entry: &str) -> bool {
+        match ip_list::parse_net(entry) {
+            Some(net) => self.ips.remove(net),
+            None => self.ids.remove(entry).is_some(),
+        }
+    }
+
+    fn contains_id(&self, id: &str, now: u64) -> bool {
+        self.ids
+            .iter()
+            .any(|(pattern, expires)| ip_list::alive(expires, now) && glob_match(pattern, id))
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        self.ips.remove_expired(now);
+        self.ids.retain(|_, expires| ip_list::alive(expires, now));
+    }
+
+    fn len(&self) -> usize {
+        self.ips.len() + self.ids.len()
+    }
+
+    /// Ranges first, then ID patterns, each sorted.
+    fn entries(&self) -> Vec<(String, Option<u64>)> {
+        let mut ids: Vec<_> = self.ids.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        ids.sort_unstable();
+        let mut res = self.ips.entries();
+        res.extend(ids);
+        res
+    }
+}
+
+fn read(file: &str) -> AccessList {
+    AccessList::parse(&std::fs::read_to_string(file).unwrap_or_default())
+}
+
+/// Loads both lists at startup.
+pub(crate) fn load() {
+    for list in List::ALL {
+        let n = {
+            let mut lock = list.lock().write().unwrap();
+            *lock = read(list.file());
+            lock.len()
+        };
+        log::info!("#{}: {}", list.file(), n);
+    }
+}
+
+/// Re-reads both lists, dropping edits not saved, returns a line per changed
+/// list.
+pub(crate) fn reload() -> Vec<String> {
+    let mut res = Vec::new();
+    for list in List::ALL {
+        let new = read(list.file());
+        let mut lock = list.lock().write().unwrap();
+        let keys = |x: &AccessList| {
+            x.entries()
+                .into_iter()
+                .map(|x| x.0)
+                .collect::<std::collections::HashSet<_>>()
+        };
+        let (a, b) = (keys(&new), keys(&lock));
+        let added = a.difference(&b).count();
+        let removed = b.difference(&a).count();
+        *lock = new;
+        if added + removed > 0 {
+            let x = format!("{}: {} added, {} removed", list.file(), added, removed);
+            log::info!("{}", x);
+            res.push(x);
+        }
+    }
+    res
+}
+
+/// Whether a request from `ip` about `id` is refused, an empty `id` is not
+/// checked.
+pub(crate) fn is_denied(ip: IpAddr, id: &str) -> bool {
+    let now = crate::common::now();
+    let (deny, allow) = (DENY.read().unwrap(), ALLOW.read().unwrap());
+    let ip_denied = deny.ips.contains_at(ip, now) && !allow.ips.contains_at(ip, now);
+    ip_denied || (!id.is_empty() && deny.contains_id(id, now) && !allow.contains_id(id, now))
+}
+
+/// Whether `id` matches an ID entry of the allow list, which provisions it in
+/// PRIVATE_MODE.
+pub(crate) fn is_allowed_id(id: &str) -> bool {
+    ALLOW.read().unwrap().contains_id(id, crate::common::now())
+}
+
+/// Whether an entry of `list` not expired matches `s`, an IP or an ID.
+pub(crate) fn matches(list: List, s: &str) -> bool {
+    let now = crate::common::now();
+    let lock = list.lock().read().unwrap();
+    match s.parse::<IpAddr>() {
+        Ok(ip) => lock.ips.contains_at(ip, now),
+        Err(_) => lock.contains_id(s, now),
+    }
+}
+
+/// The entries of `list` not expired, with their expiry.
+pub(crate) fn entries(list: List) -> Vec<(String, Option<u64>)> {
+    let mut lock = list.lock().write().unwrap();
+    lock.remove_expired(crate::common::now());
+    lock.entries()
+}
+
+/// Applies `edit` to the file of `list` and then to `list`, in memory only if
+/// the file cannot be written. Returns the number of entries added or removed,
+/// and the error writing the file. `list` is only locked once the file is
+/// written, so that `is_denied` does not wait for the disk.
+pub(crate) async fn edit(list: List, edit: Edit) -> (usize, std::io::Result<()>) {
+    let file = list.file();
+    let _saving = SAVING.lock().await;
+    let saved = {
+        let edit = edit.clone();
+        tokio::task::spawn_blocking(move || ip_list::save(file, &edit, entry_key)).await
+    };
+    let res = saved.map_err(std::io::Error::from).and_then(|x| x);
+    if let Err(err) = &res {
+        log::error!("Failed to write {}: {}", file, err);
+    }
+    let mut lock = list.lock().write().unwrap();
+    let n = match edit {
+        Edit::Add(keys, expires, _) => keys.iter().filter(|x| lock.insert(x, expires)).count(),
+        Edit::Remove(keys) => keys.iter().filter(|x| lock.remove(x)).count(),
+        Edit::Clear => std::mem::take(&mut *lock).len(),
+    };
+    (n, res)
+}
+
+async fn console_edit(list: List, e: Edit) -> String {
+    match edit(list, e).await {
+        (_, Ok(_)) => "".to_owned(),
+        (_, Err(err)) => format!("not saved to {}: {}\n", list.file(), err),
+    }
+}
+
+/// Console command adding the `|` separated entries, expiring after a duration
+/// or at an RFC 3339 time if given, with an optional comment after `#`.
+pub(crate) async fn console_add<'a>(
+    list: List,
+    entries: Option<&str>,
+    args: impl Iterator<Item = &'a str>,
+) -> String {
+    let Some(entries) = entries else {
+        return "".to_owned();
+    };
+    let (expires, comment) = match ip_list::parse_add_args(args, crate::common::now()) {
+        Ok(v) => v,
+        Err(err) => return format!("{err}\n"),
+    };
+    match parse_entries(entries.split('|')) {
+        Ok(keys) => console_edit(list, Edit::Add(keys, expires, comment)).await,
+        Err(err) => format!("{err}\n"),
+    }
+}
+
+/// Console command removing the `|` separated entries as added, or `all`.
+pub(crate) async fn console_remove(list: List, entries: Option<&str>) -> String {
+    match entries {
+        Some("all") => console_edit(list, Edit::Clear).await,
+        Some(entries) => match parse_entries(entries.split('|')) {
+            Ok(keys) => console_edit(list, Edit::Remove(keys)).await,
+            Err(err) => format!("{err}\n"),
+        },
+        None => "".to_owned(),
+    }
+}
+
+/// Console command telling whether an IP or ID matches, or listing the entries.
+pub(crate) fn console_show(list: List, s: Option<&str>) -> String {
+    match s {
+        Some(s) => format!("{}\n", matches(list, s)),
+        None => {
+            let mut res = String::new();
+            for (entry, expires) in entries(list) {
+                res.push_str(&entry);
+                if let Some(t) = expires {
+                    res.push(' ');
+                    res.push_str(&ip_list::format_time(t));
+                }
+                res.push('\n');
+            }
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_entries() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("acme-*", "acme-01"));
+        assert!(glob_match("1?3*9", "1234569"));
+        assert!(glob_match("*a*b", "xxaxxab"));
+        assert!(!glob_match("acme-*", "acm-01"));
+        assert!(!glob_match("12?", "12"));
+        assert!(!glob_match("123", "1234"));
+        assert_eq!(entry_key("123456789").as_deref(), Some("123456789"));
+        assert_eq!(entry_key("10.1.2.3/8").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(entry_key("1.2.3"), None);
+        assert_eq!(entry_key("a/b"), None);
+
+        let deny = AccessList::parse(
+            "# comment\n\
+             10.0.0.0/8\n\
+             * 2000-01-01T00:00:00Z\n\
+             bad-* 4000000000\n\
+             evil??\n\
+             not/valid\n",
+        );
+        assert_eq!(deny.len(), 4);
+        let now = 1_000_000_000;
+        assert!(deny.ips.contains_at("10.1.2.3".parse().unwrap(), now));
+        assert!(deny.contains_id("bad-host", now));
+        assert!(deny.contains_id("evil01", now));
+        assert!(!deny.contains_id("evil1", now));
+        // `*` expired
+        assert!(deny.contains_id("anything", 0));
+        assert!(!deny.contains_id("anything", now));
+        let mut deny = deny;
+        deny.remove_expired(now);
+        assert_eq!(
+            deny.entries(),
+            vec![
+                ("10.0.0.0/8".to_owned(), None),
+                ("bad-*".to_owned(), None),
+                ("evil??".to_owned(), None),
+            ]
+        );
+        assert!(deny.remove("10.0.0.0/8"));
+        assert!(deny.remove("evil??"));
+        assert!(!deny.remove("evil?"));
+
+        let content = ip_list::edit(
+            "# header\nbad-*\n",
+            &Edit::Add(
+                parse_entries(["10.0.0.1", "acme-*"]).unwrap(),
+                None,
+                "x".to_owned(),
+            ),
+            now,
+            entry_key,
+        );
+        let content = ip_list::edit(
+            &content,
+            &Edit::Remove(parse_entries(["bad-*"]).unwrap()),
+            now,
+            entry_key,
+        );
+        assert_eq!(
+            content,
+            "# header\n\
+             10.0.0.1 # 2001-09-09T01:46:40Z x\n\
+             acme-* # 2001-09-09T01:46:40Z x\n"
+        );
+        assert!(parse_entries(["1.2.3.4", "a b"]).is_err());
+    }
+}
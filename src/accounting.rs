@@ -0,0 +1,141 @@
+// Accounting records of finished relay sessions, for billing and capacity
+// planning. The records are appended in batches as JSON lines to
+// RELAY_ACCOUNTING_FILE and, with RELAY_ACCOUNTING_DB=Y, written to the
+// `relay_session` table.
+
+use crate::batch::{clip, Batch};
+use crate::common::{get_arg, get_arg_opt};
+use crate::database::{Database, RelaySession};
+use hbb_common::{log, tokio, ResultType};
+use std::io::Write;
+
+static LINES: Batch<String, String> = Batch::new("relay accounting lines", |file, v| {
+    Box::pin(append(file, v))
+});
+static SESSIONS: Batch<RelaySession> = Batch::new("relay accounting records", |db, v| {
+    db.insert_relay_sessions(v)
+});
+
+/// Whether RELAY_ACCOUNTING_DB is Y.
+pub(crate) fn to_database() -> bool {
+    get_arg("RELAY_ACCOUNTING_DB").to_uppercase() == "Y"
+}
+
+/// Starts appending the records to RELAY_ACCOUNTING_FILE, if set.
+pub(crate) fn start_file() {
+    if let Some(file) = get_arg_opt("RELAY_ACCOUNTING_FILE").filter(|x| !x.is_empty()) {
+        log::info!("RELAY_ACCOUNTING_FILE={}", file);
+        LINES.start(file);
+    }
+}
+
+/// Starts writing the records to `db`, if RELAY_ACCOUNTING_DB is Y.
+pub(crate) fn start(db: Database) {
+    if to_database() && SESSIONS.start(db) {
+        log::info!("RELAY_ACCOUNTING_DB=Y");
+    }
+}
+
+fn format_time(t: &chrono::NaiveDateTime) -> String {
+    t.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn to_json(v: &RelaySession) -> String {
+    serde_json::json!({
+        "start_time": format_time(&v.start_time),
+        "end_time": format_time(&v.end_time),
+        "uuid": v.uuid,
+        "target_id": v.target_id,
+        "addr": v.addr,
+        "peer_addr": v.peer_addr,
+        "bytes_to_peer": v.bytes_to_peer,
+        "bytes_from_peer": v.bytes_from_peer,
+        "peak_speed": v.peak_speed,
+        "downgraded": v.downgraded,
+        "blacklisted": v.blacklisted,
+        "throttled": v.throttled,
+    })
+    .to_string()
+}
+
+/// Opened for each batch, so that the file can be rotated by moving it.
+async fn append(file: &str, lines: &[String]) -> ResultType<()> {
+    let (file, mut text) = (file.to_owned(), lines.join("\n"));
+    text.push('\n');
+    tokio::task::spawn_blocking(move || {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?
+            .write_all(text.as_bytes())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Queues the record of a finished session, a no-op if accounting is off.
+pub(crate) fn record(mut v: RelaySession) {
+    LINES.push(to_json(&v));
+    if SESSIONS.sink().is_none() {
+        return;
+    }
+    clip(&mut v.uuid);
+    clip(&mut v.target_id);
+    SESSIONS.push(v);
+}
+
+/// Writes the queued records, also called on shutdown.
+pub(crate) async fn flush() {
+    LINES.flush().await;
+    SESSIONS.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_records() {
+        let time = chrono::NaiveDate::from_ymd_opt(2024, 5, 1)
+            .and_then(|x| x.and_hms_milli_opt(12, 0, 0, 250))
+            .unwrap();
+        let v = RelaySession {
+            start_time: time,
+            end_time: time + chrono::Duration::seconds(90),
+            uuid: "uuid".to_owned(),
+            target_id: "123456789".to_owned(),
+            addr: "192.0.2.1:50000".to_owned(),
+            peer_addr: "[2001:db8::1]:50001".to_owned(),
+            bytes_to_peer: 1 << 40,
+            bytes_from_peer: 10,
+            peak_speed: 8000,
+            downgraded: true,
+            blacklisted: false,
+            throttled: false,
+        };
+        let line = to_json(&v);
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["start_time"], "2024-05-01T12:00:00.250Z");
+        assert_eq!(json["end_time"], "2024-05-01T12:01:30.250Z");
+        assert_eq!(json["peer_addr"], "[2001:db8::1]:50001");
+        assert_eq!(json["bytes_to_peer"], 1u64 << 40);
+        assert_eq!(json["downgraded"], true);
+    }
+
+    #[test]
+    fn appends_lines() {
+        append_lines();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn append_lines() {
+        let file = std::env::temp_dir().join(format!("hbbr-accounting-{}", std::process::id()));
+        let file = file.to_str().unwrap();
+        let lines = ["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        append(file, &lines[..2]).await.unwrap();
+        append(file, &lines[2..]).await.unwrap();
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "a\nb\nc\n");
+        std::fs::remove_file(file).ok();
+    }
+}
@@ -0,0 +1,666 @@
+// Admin REST API of hbbs, only started when API_SECRET is set.
+// Requests must carry `Authorization: Bearer <token>`, where the token is a
+// HS256 JWT signed with API_SECRET, e.g. from `rustdesk-utils genapitoken`.
+
+use crate::access::{self, List};
+use crate::common::*;
+use crate::peer::*;
+use crate::rendezvous_server::*;
+use axum::{
+    async_trait,
+    extract::{FromRequest, Path, Query, RequestParts},
+    headers::{authorization::Bearer, Authorization},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, put},
+    Extension, Json, Router, TypedHeader,
+};
+use hbb_common::{log, tokio::sync::oneshot, ResultType};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 10_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: u64,
+}
+
+pub fn gen_token(secret: &str, sub: &str, days: u64) -> ResultType<String> {
+    let claims = Claims {
+        sub: sub.to_owned(),
+        exp: now() + days * DAY_SECONDS,
+    };
+    Ok(jsonwebtoken::encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?)
+}
+
+fn verify_token(secret: &str, token: &str) -> ResultType<Claims> {
+    Ok(jsonwebtoken::decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )?
+    .claims)
+}
+
+#[derive(Clone)]
+struct ApiState {
+    rs: RendezvousServer,
+    secret: Arc<String>,
+}
+
+enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    NotFound,
+    Internal(String),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = match self {
+            ApiError::BadRequest(err) => (StatusCode::BAD_REQUEST, err),
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized".to_owned()),
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "not found".to_owned()),
+            ApiError::Internal(err) => (StatusCode::INTERNAL_SERVER_ERROR, err),
+        };
+        (status, Json(serde_json::json!({ "error": error }))).into_response()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+struct Auth;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Auth {
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let TypedHeader(Authorization(bearer)) =
+            TypedHeader::<Authorization<Bearer>>::from_request(req)
+                .await
+                .map_err(|_| ApiError::Unauthorized)?;
+        let Extension(state) = Extension::<ApiState>::from_request(req)
+            .await
+            .map_err(|err| ApiError::Internal(err.to_string()))?;
+        match verify_token(&state.secret, bearer.token()) {
+            Ok(claims) => {
+                log::debug!("api request from {}", claims.sub);
+                Ok(Auth)
+            }
+            Err(err) => {
+                log::warn!("api authentication failed: {}", err);
+                Err(ApiError::Unauthorized)
+            }
+        }
+    }
+}
+
+/// The requested `limit` of a list, at most MAX_LIMIT.
+#[inline]
+fn limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+}
+
+#[derive(Deserialize)]
+struct Paging {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl Paging {
+    fn take<T>(&self, it: impl Iterator<Item = T>) -> Vec<T> {
+        it.skip(self.offset).take(limit(self.limit)).collect()
+    }
+}
+
+#[derive(Serialize)]
+struct PeerJson {
+    id: String,
+    online: bool,
+    last_reg_secs: u64,
+    socket_addr: String,
+    ip: String,
+    uuid: String,
+    pk: String,
+    disabled: bool,
+}
+
+async fn peer_json(id: String, peer: LockPeer) -> PeerJson {
+    let peer = peer.read().await;
+    PeerJson {
+        id,
+        online: peer.is_online(),
+        last_reg_secs: peer.last_reg_time.elapsed().as_secs(),
+        socket_addr: peer.socket_addr.to_string(),
+        ip: peer.info.ip.clone(),
+        uuid: base64::encode(&peer.uuid),
+        pk: base64::encode(&peer.pk),
+        disabled: peer.disabled,
+    }
+}
+
+async fn list_peers(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Query(paging): Query<Paging>,
+) -> ApiResult<Vec<PeerJson>> {
+    let mut peers = state.rs.pm.list_in_memory().await;
+    peers.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut res = Vec::new();
+    for (id, peer) in paging.take(peers.into_iter()) {
+        res.push(peer_json(id, peer).await);
+    }
+    Ok(Json(res))
+}
+
+async fn get_peer(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<PeerJson> {
+    match state.rs.pm.get(&id).await {
+        Some(peer) => Ok(Json(peer_json(id, peer).await)),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+#[derive(Serialize)]
+struct PeerActivityJson {
+    id: String,
+    created_at: String,
+    last_reg_time: Option<String>,
+    last_ip: Option<String>,
+    pk_changed_at: Option<String>,
+    disabled: bool,
+    note: String,
+}
+
+fn rfc3339(t: chrono::NaiveDateTime) -> String {
+    t.and_utc()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Persisted registration history, `last_reg_time` may lag behind the
+/// in-memory state by up to LAST_SEEN_FLUSH_INTERVAL.
+async fn get_peer_activity(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<PeerActivityJson> {
+    match state.rs.pm.db.get_activity(&id).await {
+        Ok(Some(v)) => Ok(Json(PeerActivityJson {
+            id,
+            created_at: rfc3339(v.created_at),
+            last_reg_time: v.last_reg_time.map(rfc3339),
+            last_ip: v.last_ip,
+            pk_changed_at: v.pk_changed_at.map(rfc3339),
+            disabled: v.status == Some(crate::database::STATUS_DISABLED),
+            note: v.note.unwrap_or_default(),
+        })),
+        Ok(None) => Err(ApiError::NotFound),
+        Err(err) => Err(ApiError::Internal(err.to_string())),
+    }
+}
+
+fn found(res: ResultType<bool>) -> ApiResult<bool> {
+    match res {
+        Ok(true) => Ok(Json(true)),
+        Ok(false) => Err(ApiError::NotFound),
+        Err(err) => Err(ApiError::Internal(err.to_string())),
+    }
+}
+
+async fn delete_peer(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Path(id): Path<String>,
+) -> ApiResult<bool> {
+    found(state.rs.pm.delete(&id).await)
+}
+
+#[derive(Deserialize)]
+struct ProvisionJson {
+    id: String,
+    /// base64, empty to let the next registration enroll its key
+    pk: Option<String>,
+    note: Option<String>,
+}
+
+async fn import_peers(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Json(body): Json<Vec<ProvisionJson>>,
+) -> ApiResult<ImportSummary> {
+    let items: Vec<_> = body
+        .iter()
+        .map(|x| Provision::new(&x.id, x.pk.as_deref(), x.note.as_deref()))
+        .collect();
+    Ok(Json(state.rs.pm.import(items).await))
+}
+
+#[derive(Deserialize)]
+struct DisabledJson {
+    disabled: bool,
+}
+
+async fn set_peer_disabled(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Path(id): Path<String>,
+    Json(body): Json<DisabledJson>,
+) -> ApiResult<bool> {
+    found(state.rs.pm.set_disabled(&id, body.disabled).await)
+}
+
+#[derive(Deserialize)]
+struct NoteJson {
+    note: String,
+}
+
+async fn set_peer_note(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Path(id): Path<String>,
+    Json(body): Json<NoteJson>,
+) -> ApiResult<bool> {
+    if body.note.chars().count() > MAX_NOTE_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "note longer than {MAX_NOTE_LEN} characters"
+        )));
+    }
+    found(state.rs.pm.db.set_note(&id, &body.note).await)
+}
+
+#[derive(Serialize, Deserialize)]
+struct RelayServersJson {
+    relay_servers: Vec<String>,
+    #[serde(default)]
+    alive: Vec<String>,
+}
+
+async fn get_relay_servers(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+) -> ApiResult<RelayServersJson> {
+    let (tx, rx) = oneshot::channel();
+    state
+        .rs
+        .tx
+        .send(Data::QueryRelayServers(tx))
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
+    let (relay_servers, alive) = rx
+        .await
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
+    Ok(Json(RelayServersJson {
+        relay_servers,
+        alive,
+    }))
+}
+
+async fn set_relay_servers(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Json(body): Json<RelayServersJson>,
+) -> ApiResult<RelayServersJson> {
+    state
+        .rs
+        .tx
+        .send(Data::RelayServers0(body.relay_servers.join(",")))
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
+    get_relay_servers(Auth, Extension(state)).await
+}
+
+#[derive(Serialize)]
+struct IpBlockerJson {
+    ip: String,
+    requests: u32,
+    requests_age_secs: u64,
+    ids: usize,
+    ids_age_secs: u64,
+}
+
+async fn list_ip_blocker(_: Auth, Query(paging): Query<Paging>) -> ApiResult<Vec<IpBlockerJson>> {
+    let lock = IP_BLOCKER.lock().await;
+    Ok(Json(paging.take(lock.iter().map(|(ip, (a, b))| {
+        IpBlockerJson {
+            ip: ip.clone(),
+            requests: a.0,
+            requests_age_secs: a.1.elapsed().as_secs(),
+            ids: b.0.len(),
+            ids_age_secs: b.1.elapsed().as_secs(),
+        }
+    }))))
+}
+
+async fn remove_ip_blocker(_: Auth, Path(ip): Path<String>) -> ApiResult<bool> {
+    Ok(Json(IP_BLOCKER.lock().await.remove(&ip).is_some()))
+}
+
+#[derive(Serialize)]
+struct IpChangesJson {
+    id: String,
+    age_secs: u64,
+    ips: std::collections::HashMap<String, i32>,
+}
+
+async fn list_ip_changes(_: Auth, Query(paging): Query<Paging>) -> ApiResult<Vec<IpChangesJson>> {
+    let lock = IP_CHANGES.lock().await;
+    Ok(Json(paging.take(lock.iter().map(|(id, (tm, ips))| {
+        IpChangesJson {
+            id: id.clone(),
+            age_secs: tm.elapsed().as_secs(),
+            ips: ips.clone(),
+        }
+    }))))
+}
+
+async fn remove_ip_changes(_: Auth, Path(id): Path<String>) -> ApiResult<bool> {
+    Ok(Json(IP_CHANGES.lock().await.remove(&id).is_some()))
+}
+
+#[derive(Serialize)]
+struct PunchRequestJson {
+    time: String,
+    from_ip: String,
+    to_id: String,
+    to_ip: String,
+}
+
+async fn list_punch_requests(
+    _: Auth,
+    Query(paging): Query<Paging>,
+) -> ApiResult<Vec<PunchRequestJson>> {
+    let lock = PUNCH_REQS.lock().await;
+    Ok(Json(paging.take(lock.iter().map(|e| PunchRequestJson {
+        time: e.time_rfc3339(),
+        from_ip: e.from_ip.clone(),
+        to_id: e.to_id.clone(),
+        to_ip: e.to_ip.clone(),
+    }))))
+}
+
+async fn clear_punch_requests(_: Auth) -> ApiResult<usize> {
+    let mut lock = PUNCH_REQS.lock().await;
+    let n = lock.len();
+    lock.clear();
+    Ok(Json(n))
+}
+
+#[derive(Serialize, Deserialize)]
+struct AlwaysUseRelayJson {
+    enabled: bool,
+}
+
+async fn get_always_use_relay(_: Auth) -> ApiResult<AlwaysUseRelayJson> {
+    Ok(Json(AlwaysUseRelayJson {
+        enabled: ALWAYS_USE_RELAY.load(Ordering::SeqCst),
+    }))
+}
+
+async fn set_always_use_relay(
+    _: Auth,
+    Json(body): Json<AlwaysUseRelayJson>,
+) -> ApiResult<AlwaysUseRelayJson> {
+    ALWAYS_USE_RELAY.store(body.enabled, Ordering::SeqCst);
+    log::info!(
+        "ALWAYS_USE_RELAY={} (api)",
+        if body.enabled { "Y" } else { "N" }
+    );
+    get_always_use_relay(Auth).await
+}
+
+#[derive(Serialize)]
+struct AccessEntryJson {
+    entry: String,
+    expires: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AccessAddJson {
+    entries: Vec<String>,
+    /// a duration, e.g. `12h`, or an RFC 3339 time
+    expires: Option<String>,
+    #[serde(default)]
+    comment: String,
+}
+
+#[derive(Deserialize)]
+struct AccessRemoveQuery {
+    entry: Option<String>,
+}
+
+fn access_list(name: &str) -> Result<List, ApiError> {
+    List::parse(name).ok_or(ApiError::NotFound)
+}
+
+async fn access_edit(list: List, edit: crate::ip_list::Edit) -> ApiResult<usize> {
+    match access::edit(list, edit).await {
+        (n, Ok(_)) => Ok(Json(n)),
+        (_, Err(err)) => Err(ApiError::Internal(format!(
+            "applied but not saved to {}: {}",
+            list.file(),
+            err
+        ))),
+    }
+}
+
+async fn list_access(
+    _: Auth,
+    Path(list): Path<String>,
+    Query(paging): Query<Paging>,
+) -> ApiResult<Vec<AccessEntryJson>> {
+    let entries = access::entries(access_list(&list)?);
+    Ok(Json(paging.take(entries.into_iter().map(
+        |(entry, expires)| AccessEntryJson {
+            entry,
+            expires: expires.map(crate::ip_list::format_time),
+        },
+    ))))
+}
+
+async fn add_access(
+    _: Auth,
+    Path(list): Path<String>,
+    Json(body): Json<AccessAddJson>,
+) -> ApiResult<usize> {
+    let list = access_list(&list)?;
+    let keys = access::parse_entries(body.entries.iter().map(|x| x.as_str()))
+        .map_err(ApiError::BadRequest)?;
+    let expires = match body.expires.as_deref() {
+        Some(v) => Some(
+            crate::ip_list::parse_expiry(v, now())
+                .ok_or_else(|| ApiError::BadRequest(format!("invalid duration or expiry: {v}")))?,
+        ),
+        None => None,
+    };
+    if body.comment.contains('\n') {
+        return Err(ApiError::BadRequest(
+            "comment must be a single line".to_owned(),
+        ));
+    }
+    access_edit(
+        list,
+        crate::ip_list::Edit::Add(keys, expires, body.comment.trim().to_owned()),
+    )
+    .await
+}
+
+async fn remove_access(
+    _: Auth,
+    Path(list): Path<String>,
+    Query(query): Query<AccessRemoveQuery>,
+) -> ApiResult<usize> {
+    let list = access_list(&list)?;
+    match query.entry.as_deref() {
+        Some(entry) => {
+            let keys = access::parse_entries([entry]).map_err(ApiError::BadRequest)?;
+            access_edit(list, crate::ip_list::Edit::Remove(keys)).await
+        }
+        None => access_edit(list, crate::ip_list::Edit::Clear).await,
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    /// RFC3339 time or UTC date, inclusive
+    from: Option<String>,
+    /// RFC3339 time or UTC date, exclusive
+    to: Option<String>,
+    kind: Option<String>,
+    id: Option<String>,
+    ip: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct AuditEventJson {
+    time: String,
+    kind: String,
+    source_ip: String,
+    target_id: String,
+    peer_ip: Option<String>,
+    uuid: Option<String>,
+    outcome: String,
+    duration_ms: Option<i64>,
+    bytes: Option<i64>,
+}
+
+fn audit_time(v: Option<&str>) -> Result<Option<chrono::NaiveDateTime>, ApiError> {
+    v.map(|v| {
+        crate::audit::parse_time(v)
+            .ok_or_else(|| ApiError::BadRequest(format!("invalid time: {v}")))
+    })
+    .transpose()
+}
+
+/// Events written to the audit log, lagging behind by up to its flush interval.
+async fn list_audit(
+    _: Auth,
+    Extension(state): Extension<ApiState>,
+    Query(query): Query<AuditQuery>,
+) -> ApiResult<Vec<AuditEventJson>> {
+    if let Some(kind) = query.kind.as_deref() {
+        if !crate::audit::KINDS.contains(&kind) {
+            return Err(ApiError::BadRequest(format!("invalid kind: {kind}")));
+        }
+    }
+    let filter = crate::database::AuditFilter {
+        from: audit_time(query.from.as_deref())?,
+        to: audit_time(query.to.as_deref())?,
+        kind: query.kind,
+        target_id: query.id,
+        source_ip: query.ip,
+        offset: query.offset as _,
+        limit: limit(query.limit) as _,
+    };
+    match state.rs.pm.db.get_audit(&filter).await {
+        Ok(events) => Ok(Json(
+            events
+                .into_iter()
+                .map(|v| AuditEventJson {
+                    time: rfc3339(v.time),
+                    kind: v.kind,
+                    source_ip: v.source_ip,
+                    target_id: v.target_id,
+                    peer_ip: v.peer_ip,
+                    uuid: v.uuid,
+                    outcome: v.outcome,
+                    duration_ms: v.duration_ms,
+                    bytes: v.bytes,
+                })
+                .collect(),
+        )),
+        Err(err) => Err(ApiError::Internal(err.to_string())),
+    }
+}
+
+pub(crate) async fn start(
+    rs: RendezvousServer,
+    bind_addr: Option<IpAddr>,
+    port: u16,
+    secret: String,
+) -> ResultType<()> {
+    let state = ApiState {
+        rs,
+        secret: Arc::new(secret),
+    };
+    let app = Router::new()
+        .route("/api/v1/peers", get(list_peers).post(import_peers))
+        .route("/api/v1/peers/:id", get(get_peer).delete(delete_peer))
+        .route("/api/v1/peers/:id/activity", get(get_peer_activity))
+        .route("/api/v1/peers/:id/disabled", put(set_peer_disabled))
+        .route("/api/v1/peers/:id/note", put(set_peer_note))
+        .route(
+            "/api/v1/relay-servers",
+            get(get_relay_servers).put(set_relay_servers),
+        )
+        .route("/api/v1/ip-blocker", get(list_ip_blocker))
+        .route("/api/v1/ip-blocker/:ip", delete(remove_ip_blocker))
+        .route("/api/v1/ip-changes", get(list_ip_changes))
+        .route("/api/v1/ip-changes/:id", delete(remove_ip_changes))
+        .route(
+            "/api/v1/punch-requests",
+            get(list_punch_requests).delete(clear_punch_requests),
+        )
+        .route(
+            "/api/v1/always-use-relay",
+            get(get_always_use_relay).put(set_always_use_relay),
+        )
+        .route(
+            "/api/v1/access/:list",
+            get(list_access).put(add_access).delete(remove_access),
+        )
+        .route("/api/v1/audit", get(list_audit))
+        .layer(Extension(state));
+    let listener = listen_tcp(bind_addr, port).await?;
+    log::info!("Listening on api {}", listener.local_addr()?);
+    axum::Server::from_tcp(listener.into_std()?)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_roundtrip() {
+        let token = gen_token("secret", "ops", 1).unwrap();
+        assert_eq!(verify_token("secret", &token).unwrap().sub, "ops");
+        assert!(verify_token("other", &token).is_err());
+        let expired = jsonwebtoken::encode(
+            &Header::default(),
+            &Claims {
+                sub: "ops".to_owned(),
+                exp: now() - 3600,
+            },
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(verify_token("secret", &expired).is_err());
+    }
+
+    #[test]
+    fn paging_limit() {
+        let paging = |offset, limit| Paging { offset, limit };
+        assert_eq!(paging(0, None).take(0..1000).len(), DEFAULT_LIMIT);
+        assert_eq!(paging(5, Some(2)).take(0..1000), [5, 6]);
+        assert_eq!(paging(0, Some(usize::MAX)).take(0..).len(), MAX_LIMIT);
+    }
+}
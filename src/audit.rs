@@ -0,0 +1,147 @@
+// Audit trail of connection attempts: punch hole and relay requests handled by
+// hbbs, relay sessions of hbbr. Events are written to the `audit_log` table in
+// batches and purged after AUDIT_RETENTION_DAYS.
+// hbbs and hbbr pointed at the same DB_URL share one trail.
+
+use crate::batch::{clip, Batch};
+use crate::common::get_arg;
+use crate::database::{AuditEvent, Database};
+use chrono::NaiveDateTime;
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        time::{interval, Duration},
+    },
+    try_into_v4,
+};
+use std::net::SocketAddr;
+
+pub(crate) const PUNCH_HOLE: &str = "punch_hole";
+pub(crate) const RELAY_REQUEST: &str = "relay_request";
+pub(crate) const RELAY_PAIR: &str = "relay_pair";
+pub(crate) const RELAY_CLOSE: &str = "relay_close";
+#[allow(dead_code)]
+pub(crate) const KINDS: &[&str] = &[PUNCH_HOLE, RELAY_REQUEST, RELAY_PAIR, RELAY_CLOSE];
+
+const PURGE_INTERVAL: u64 = 3600; // in seconds
+const RETENTION_DAYS: i64 = 90;
+
+static EVENTS: Batch<AuditEvent> = Batch::new("audit events", |db, v| db.insert_audit(v));
+
+/// Starts writing recorded events to `db`, unless AUDIT_LOG is N.
+pub(crate) fn start(db: Database) {
+    if get_arg("AUDIT_LOG").to_uppercase() == "N" {
+        log::info!("AUDIT_LOG=N");
+        return;
+    }
+    if !EVENTS.start(db) {
+        return;
+    }
+    log::info!("AUDIT_LOG=Y, AUDIT_RETENTION_DAYS={}", retention_days());
+    tokio::spawn(async {
+        let mut timer = interval(Duration::from_secs(PURGE_INTERVAL));
+        loop {
+            timer.tick().await;
+            purge().await;
+        }
+    });
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    EVENTS.sink().is_some()
+}
+
+/// An event of `kind` from `addr` happening now, the optional fields unset.
+pub(crate) fn event(kind: &str, addr: SocketAddr, target_id: &str, outcome: &str) -> AuditEvent {
+    AuditEvent {
+        time: chrono::Utc::now().naive_utc(),
+        kind: kind.to_owned(),
+        source_ip: try_into_v4(addr).ip().to_string(),
+        target_id: target_id.to_owned(),
+        peer_ip: None,
+        uuid: None,
+        outcome: outcome.to_owned(),
+        duration_ms: None,
+        bytes: None,
+    }
+}
+
+/// Queues `event` for the next flush, a no-op if the audit log is off.
+pub(crate) fn record(mut event: AuditEvent) {
+    if !enabled() {
+        return;
+    }
+    clip(&mut event.target_id);
+    clip(&mut event.outcome);
+    if let Some(uuid) = event.uuid.as_mut() {
+        clip(uuid);
+    }
+    EVENTS.push(event);
+}
+
+/// Writes the queued events, also called on shutdown.
+pub(crate) async fn flush() {
+    EVENTS.flush().await;
+}
+
+/// AUDIT_RETENTION_DAYS, 0 keeps the events forever.
+fn retention_days() -> i64 {
+    get_arg("AUDIT_RETENTION_DAYS")
+        .parse()
+        .unwrap_or(RETENTION_DAYS)
+}
+
+async fn purge() {
+    let Some(db) = EVENTS.sink() else {
+        return;
+    };
+    let days = retention_days();
+    if days <= 0 {
+        return;
+    }
+    let time = chrono::Utc::now().naive_utc() - chrono::Duration::days(days);
+    match db.purge_audit(time).await {
+        Ok(0) => {}
+        Ok(n) => log::info!("{} audit events older than {} days purged", n, days),
+        Err(err) => log::error!("db.purge_audit failed: {}", err),
+    }
+}
+
+/// Parses a query bound, an RFC3339 time or a UTC date `YYYY-MM-DD`.
+#[allow(dead_code)]
+pub(crate) fn parse_time(s: &str) -> Option<NaiveDateTime> {
+    if let Ok(v) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(v.naive_utc());
+    }
+    chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|x| x.and_hms_opt(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_and_events() {
+        assert_eq!(
+            parse_time("2024-05-01T12:00:00+02:00").unwrap().to_string(),
+            "2024-05-01 10:00:00"
+        );
+        assert_eq!(
+            parse_time("2024-05-01").unwrap().to_string(),
+            "2024-05-01 00:00:00"
+        );
+        assert!(parse_time("yesterday").is_none());
+        let v = event(
+            PUNCH_HOLE,
+            "[::ffff:192.0.2.1]:21116".parse().unwrap(),
+            "123456789",
+            "ok",
+        );
+        assert_eq!(v.source_ip, "192.0.2.1");
+        assert_eq!(v.kind, "punch_hole");
+    }
+}
@@ -0,0 +1,145 @@
+// Rows queued in memory and written to the database or a file in batches every
+// FLUSH_INTERVAL, e.g. audit events and relay accounting records. A failed
+// batch is retried with the next one.
+
+use crate::database::Database;
+use hbb_common::{
+    futures_util::future::BoxFuture,
+    log,
+    tokio::{
+        self,
+        time::{interval, Duration},
+    },
+    ResultType,
+};
+use once_cell::sync::OnceCell;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+// rows kept while the database is unavailable, newer ones are dropped
+const MAX_QUEUE: usize = 100_000;
+// of the varchar(100) columns, which may hold client input
+const MAX_LEN: usize = 100;
+const FLUSH_INTERVAL: u64 = 5; // in seconds
+
+/// Writes a batch of rows to `S` at once, e.g. in one transaction.
+pub(crate) type Insert<S, T> = for<'a> fn(&'a S, &'a [T]) -> BoxFuture<'a, ResultType<()>>;
+
+pub(crate) struct Batch<T: 'static, S: 'static = Database> {
+    // of the rows, for the log
+    name: &'static str,
+    insert: Insert<S, T>,
+    sink: OnceCell<S>,
+    queue: Mutex<Vec<T>>,
+    dropped: AtomicUsize,
+}
+
+impl<T: Send + Sync, S: Send + Sync> Batch<T, S> {
+    pub(crate) const fn new(name: &'static str, insert: Insert<S, T>) -> Self {
+        Self {
+            name,
+            insert,
+            sink: OnceCell::new(),
+            queue: Mutex::new(Vec::new()),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts writing the pushed rows to `sink`, false if already started.
+    pub(crate) fn start(&'static self, sink: S) -> bool {
+        if self.sink.set(sink).is_err() {
+            return false;
+        }
+        tokio::spawn(async move {
+            let mut timer = interval(Duration::from_secs(FLUSH_INTERVAL));
+            loop {
+                timer.tick().await;
+                self.flush().await;
+            }
+        });
+        true
+    }
+
+    #[inline]
+    pub(crate) fn sink(&self) -> Option<&S> {
+        self.sink.get()
+    }
+
+    /// Queues `v` for the next flush, a no-op if not started.
+    pub(crate) fn push(&self, v: T) {
+        if self.sink().is_none() {
+            return;
+        }
+        let mut lock = self.queue.lock().unwrap();
+        if lock.len() >= MAX_QUEUE {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        lock.push(v);
+    }
+
+    /// Writes the queued rows, also called on shutdown.
+    pub(crate) async fn flush(&self) {
+        let Some(sink) = self.sink() else {
+            return;
+        };
+        let dropped = self.dropped.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            log::warn!("{} {} dropped, queue full", dropped, self.name);
+        }
+        let rows = std::mem::take(&mut *self.queue.lock().unwrap());
+        if rows.is_empty() {
+            return;
+        }
+        if let Err(err) = (self.insert)(sink, &rows).await {
+            log::error!("Failed to write {} {}: {}", rows.len(), self.name, err);
+            self.requeue(rows);
+        } else {
+            log::debug!("{} {} flushed", rows.len(), self.name);
+        }
+    }
+
+    /// Puts back the failed `rows` before those pushed meanwhile.
+    fn requeue(&self, rows: Vec<T>) {
+        let mut lock = self.queue.lock().unwrap();
+        let newer = std::mem::replace(&mut *lock, rows);
+        let room = MAX_QUEUE.saturating_sub(lock.len());
+        self.dropped
+            .fetch_add(newer.len().saturating_sub(room), Ordering::SeqCst);
+        lock.extend(newer.into_iter().take(room));
+    }
+}
+
+/// Truncates `s` to the length of a varchar(100) column.
+pub(crate) fn clip(s: &mut String) {
+    if let Some((i, _)) = s.char_indices().nth(MAX_LEN) {
+        s.truncate(i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requeues_failed_rows() {
+        let batch = Batch::<usize>::new("numbers", |_, _| Box::pin(async { Ok(()) }));
+        // not started
+        batch.push(1);
+        assert!(batch.queue.lock().unwrap().is_empty());
+        batch.queue.lock().unwrap().extend([3, 4]);
+        batch.requeue(vec![1, 2]);
+        assert_eq!(*batch.queue.lock().unwrap(), [1, 2, 3, 4]);
+        // no room left for the newer rows
+        batch.requeue((0..MAX_QUEUE).collect());
+        let lock = batch.queue.lock().unwrap();
+        assert_eq!(lock.len(), MAX_QUEUE);
+        assert_eq!(lock.last(), Some(&(MAX_QUEUE - 1)));
+        assert_eq!(batch.dropped.load(Ordering::SeqCst), 4);
+        let mut s = "é".repeat(MAX_LEN + 1);
+        clip(&mut s);
+        assert_eq!(s.chars().count(), MAX_LEN);
+    }
+}
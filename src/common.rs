@@ -1,16 +1,32 @@
-use clap::App;
+use clap::{App, Arg, ArgMatches};
 use hbb_common::{
-    allow_err, anyhow::{Context, Result}, get_version_number, log, tokio, ResultType
+    allow_err, anyhow::{Context, Result}, bail, get_version_number, log, tokio, ResultType
 };
 use ini::Ini;
 use sodiumoxide::crypto::sign;
 use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
     io::prelude::*,
     io::Read,
     net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
     time::{Instant, SystemTime},
 };
 
+const CONFIG_WATCH_INTERVAL: u64 = 5; // in seconds
+
+lazy_static::lazy_static! {
+    // config files, later ones take precedence
+    static ref CONFIG_FILES: RwLock<Vec<String>> = Default::default();
+    // values applied from CONFIG_FILES and the file each came from, by arg_name
+    static ref FILE_ARGS: RwLock<HashMap<String, (String, String)>> = Default::default();
+    // names given on the command line, config files do not override them
+    static ref CLI_ARGS: RwLock<HashSet<String>> = Default::default();
+    // "hbbs" or "hbbr", selects the settings checked and the TOML section read
+    static ref BINARY: RwLock<String> = Default::default();
+}
+
 pub fn parse_bind_address(value: &str) -> Result<Option<IpAddr>> {
     let value = value.trim();
     if value.is_empty() {
@@ -81,33 +97,295 @@ pub fn set_arg(name: &str, value: &str) {
     std::env::set_var(arg_name(name), value);
 }
 
-#[allow(dead_code)]
-pub fn init_args(args: &str, name: &str, about: &str) {
+/// Parses the command line of binary `name`, applies `.env`, the `--config`
+/// file and the flags, and validates the result. With `--print-config`, prints
+/// the effective configuration and exits.
+pub fn init_args<'a>(args: &'a str, name: &str, about: &'a str) -> ResultType<ArgMatches<'a>> {
     let matches = App::new(name)
         .version(crate::version::VERSION)
         .author("Purslane Ltd. <info@rustdesk.com>")
         .about(about)
         .args_from_usage(args)
+        .arg(Arg::from_usage(
+            "--print-config 'Prints the effective configuration and where each value comes from'",
+        ))
         .get_matches();
-    if let Ok(v) = Ini::load_from_file(".env") {
-        if let Some(section) = v.section(None::<String>) {
-            section
-                .iter()
-                .for_each(|(k, v)| set_arg(k, v));
+    *BINARY.write().unwrap() = name.to_owned();
+    let mut files = vec![".env".to_owned()];
+    if let Some(config) = matches.value_of("config") {
+        files.push(config.to_owned());
+    }
+    load_config_files(files, matches.args.keys().map(|k| arg_name(k)).collect())?;
+    for (k, v) in matches.args.iter() {
+        if let Some(v) = v.vals.first() {
+            set_arg(k, &v.to_string_lossy());
         }
     }
-    if let Some(config) = matches.value_of("config") {
-        if let Ok(v) = Ini::load_from_file(config) {
+    if matches.is_present("print-config") {
+        // don't use log here, since it is async
+        print!("{}", effective_config());
+        std::process::exit(if check_config().is_ok() { 0 } else { 1 });
+    }
+    check_config()?;
+    Ok(matches)
+}
+
+#[inline]
+fn binary() -> String {
+    BINARY.read().unwrap().clone()
+}
+
+/// Reads a TOML file: top-level keys are read by both binaries, those of the
+/// `[hbbs]` and `[hbbr]` tables by one, with the table as part of their source.
+/// Unknown keys are an error.
+fn read_toml_file(file: &str, args: &mut HashMap<String, (String, String)>) -> ResultType<()> {
+    let contents = match std::fs::read_to_string(file) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+    let table: toml::Table = match toml::from_str(&contents) {
+        Ok(v) => v,
+        Err(err) => bail!("Failed to parse {}: {}", file, err),
+    };
+    let bin = binary();
+    let mut read = |k: &str, v: &toml::Value, source: String| -> ResultType<()> {
+        if !crate::settings::is_known(k) {
+            bail!("Unknown key {:?} in {}", k, source);
+        }
+        let Some(v) = crate::settings::from_toml(v) else {
+            bail!("Unsupported value of {:?} in {}", k, source);
+        };
+        args.insert(arg_name(k), (v, source));
+        Ok(())
+    };
+    for (k, v) in table.iter() {
+        match v {
+            toml::Value::Table(t) if k == "hbbs" || k == "hbbr" => {
+                let source = format!("{file} [{k}]");
+                for (k2, v) in t.iter() {
+                    if crate::settings::find(k, k2).is_none() {
+                        bail!("Unknown key {:?} in {}", k2, source);
+                    }
+                    if *k == bin {
+                        read(k2, v, source.clone())?;
+                    }
+                }
+            }
+            toml::Value::Table(_) => bail!("Unknown section [{}] in {}", k, file),
+            _ => read(k, v, file.to_owned())?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads `files`, `*.toml` as TOML and the others as INI, later ones taking
+/// precedence.
+fn read_config_files(files: &[String]) -> ResultType<HashMap<String, (String, String)>> {
+    let mut args = HashMap::new();
+    for file in files {
+        if file.ends_with(".toml") {
+            read_toml_file(file, &mut args)?;
+        } else if let Ok(v) = Ini::load_from_file(file) {
             if let Some(section) = v.section(None::<String>) {
-                section
-                    .iter()
-                    .for_each(|(k, v)| set_arg(k, v));
+                for (k, v) in section.iter() {
+                    args.insert(arg_name(k), (v.to_owned(), file.clone()));
+                }
             }
         }
     }
-    for (k, v) in matches.args {
-        if let Some(v) = v.vals.first() {
-            set_arg(k, &v.to_string_lossy());
+    Ok(args)
+}
+
+/// Applies the config `files`, later ones taking precedence, except the `cli`
+/// names, and remembers them for `reload_config`.
+pub fn load_config_files(files: Vec<String>, cli: HashSet<String>) -> ResultType<()> {
+    let args = read_config_files(&files)?;
+    for (k, (v, _)) in args.iter() {
+        if !cli.contains(k) {
+            set_arg(k, v);
+        }
+    }
+    *CONFIG_FILES.write().unwrap() = files;
+    *FILE_ARGS.write().unwrap() = args;
+    *CLI_ARGS.write().unwrap() = cli;
+    Ok(())
+}
+
+/// Where the effective value of `name` comes from: `command line`, a config
+/// file, `environment` or `default`.
+#[allow(dead_code)]
+pub fn arg_source(name: &str) -> String {
+    let name = arg_name(name);
+    if CLI_ARGS.read().unwrap().contains(&name) {
+        return "command line".to_owned();
+    }
+    if let Some((_, file)) = FILE_ARGS.read().unwrap().get(&name) {
+        return file.clone();
+    }
+    if get_arg_opt(&name).is_some() {
+        "environment".to_owned()
+    } else {
+        "default".to_owned()
+    }
+}
+
+/// Checks the effective value of every setting of this binary, whatever its
+/// source.
+fn check_config() -> ResultType<()> {
+    let bin = binary();
+    let errors: Vec<_> = crate::settings::settings(&bin)
+        .filter_map(|x| {
+            let v = get_arg_opt(x.name)?;
+            let err = crate::settings::check(&bin, x.name, &v).err()?;
+            Some(format!("{} (from {})", err, arg_source(x.name)))
+        })
+        .collect();
+    if !errors.is_empty() {
+        bail!("Invalid configuration: {}", errors.join("; "));
+    }
+    Ok(())
+}
+
+/// The effective settings of this binary as TOML, commented with their
+/// source, secrets hidden.
+fn effective_config() -> String {
+    use std::fmt::Write;
+
+    let bin = binary();
+    let mut out = format!("# {} {}\n", bin, crate::version::VERSION);
+    for x in crate::settings::settings(&bin) {
+        let name = x.name.to_lowercase();
+        let value = get_arg_opt(x.name);
+        let source = arg_source(x.name);
+        let _ = match (&value, x.default) {
+            (Some(v), _) if x.secret && !v.is_empty() => {
+                writeln!(out, "# {} = <hidden> # {}", name, source)
+            }
+            (Some(v), _) => {
+                let _ = write!(out, "{} = {} # {}", name, x.kind.to_toml(v), source);
+                match crate::settings::check(&bin, x.name, v) {
+                    Err(err) => writeln!(out, ", {}", err),
+                    Ok(_) => writeln!(out),
+                }
+            }
+            (None, Some(v)) => writeln!(out, "{} = {} # default", name, x.kind.to_toml(v)),
+            (None, None) => writeln!(out, "# {} is not set", name),
+        };
+    }
+    out
+}
+
+pub fn config_files() -> Vec<String> {
+    CONFIG_FILES.read().unwrap().clone()
+}
+
+pub struct ConfigChange {
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    pub applied: bool,
+    /// why the new value was rejected
+    pub invalid: Option<String>,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secret = crate::settings::find(&binary(), &self.name).is_some_and(|x| x.secret);
+        let show = |v: &Option<String>| match v {
+            None => "(unset)".to_owned(),
+            Some(_) if secret => "(hidden)".to_owned(),
+            Some(v) => format!("{:?}", v),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.name,
+            show(&self.old),
+            show(&self.new)
+        )?;
+        if let Some(err) = &self.invalid {
+            write!(f, ", rejected: {}", err)?;
+        } else if !self.applied {
+            write!(f, ", restart required")?;
+        }
+        Ok(())
+    }
+}
+
+/// Re-reads the config files and applies the values changed since, except
+/// `restart` ones, which only take effect on restart, and invalid ones. A
+/// value removed from the files falls back to the inherited environment. A
+/// file which cannot be parsed leaves the configuration unchanged.
+pub fn reload_config(restart: &[&str]) -> ResultType<Vec<ConfigChange>> {
+    let new = read_config_files(&CONFIG_FILES.read().unwrap())?;
+    let bin = binary();
+    let cli = CLI_ARGS.read().unwrap();
+    let mut args = FILE_ARGS.write().unwrap();
+    let names: BTreeSet<_> = args.keys().chain(new.keys()).cloned().collect();
+    let mut changes = Vec::new();
+    for name in names {
+        let (old, new) = (args.get(&name), new.get(&name));
+        if old.map(|x| &x.0) == new.map(|x| &x.0) || cli.contains(&name) {
+            continue;
+        }
+        let invalid = new.and_then(|(v, _)| crate::settings::check(&bin, &name, v).err());
+        let change = ConfigChange {
+            applied: invalid.is_none() && !restart.iter().any(|x| arg_name(x) == name),
+            old: old.map(|x| x.0.clone()),
+            new: new.map(|x| x.0.clone()),
+            invalid,
+            name,
+        };
+        if change.applied {
+            log::info!("config {}", change);
+            match new {
+                Some(v) => {
+                    set_arg(&change.name, &v.0);
+                    args.insert(change.name.clone(), v.clone());
+                }
+                None => {
+                    std::env::remove_var(&change.name);
+                    args.remove(&change.name);
+                }
+            }
+        } else {
+            log::warn!("config {}", change);
+        }
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+/// Calls `reload` on SIGHUP and when one of `files` is created, modified or
+/// removed.
+pub async fn watch_config(files: Vec<String>, reload: impl Fn() + Send + Sync + 'static) {
+    let reload = Arc::new(reload);
+    #[cfg(unix)]
+    {
+        use hbb_common::tokio::signal::unix::{signal, SignalKind};
+        let reload = reload.clone();
+        tokio::spawn(async move {
+            match signal(SignalKind::hangup()) {
+                Ok(mut s) => {
+                    while s.recv().await.is_some() {
+                        log::info!("signal hangup");
+                        reload();
+                    }
+                }
+                Err(err) => log::error!("Failed to listen for SIGHUP: {}", err),
+            }
+        });
+    }
+    let mtime = |file: &String| std::fs::metadata(file).and_then(|x| x.modified()).ok();
+    let mut last: Vec<_> = files.iter().map(mtime).collect();
+    let mut timer = tokio::time::interval(std::time::Duration::from_secs(CONFIG_WATCH_INTERVAL));
+    loop {
+        timer.tick().await;
+        let now: Vec<_> = files.iter().map(mtime).collect();
+        if now != last {
+            log::info!("config file changed");
+            last = now;
+            reload();
         }
     }
 }
@@ -271,6 +549,50 @@ async fn check_software_update_() -> hbb_common::ResultType<()> {
     Ok(())
 }
 
+/// Load reported by hbbr in the token of its HealthCheck reply, as JSON.
+///
+/// The token is overloaded: hbbs sends its KEY in it and hbbr, after checking
+/// the KEY, replies with this status in its place. A reply whose token is not
+/// this JSON, e.g. the KEY echoed by another build, means the relay is healthy
+/// but its load unknown.
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct RelayStatus {
+    /// paired relay connections
+    pub active: usize,
+    /// relay requests waiting for their peer
+    pub pending: usize,
+    /// current aggregate relay speed in bit/s
+    pub bandwidth: usize,
+    /// TOTAL_BANDWIDTH in bit/s
+    pub total_bandwidth: usize,
+    /// no new relay connections accepted
+    #[serde(default)]
+    pub draining: bool,
+}
+
+#[allow(dead_code)]
+impl RelayStatus {
+    /// The token of the HealthCheck reply.
+    pub(crate) fn to_token(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// The status in the token of a HealthCheck reply, None if there is none.
+    pub(crate) fn from_token(token: &str) -> Option<Self> {
+        serde_json::from_str(token).ok()
+    }
+
+    /// Used share of TOTAL_BANDWIDTH, 0 if unknown.
+    pub(crate) fn load(&self) -> f64 {
+        if self.total_bandwidth == 0 {
+            0.
+        } else {
+            self.bandwidth as f64 / self.total_bandwidth as f64
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -305,6 +627,106 @@ mod tests {
         std::env::remove_var("RUSTDESK_CONFIG_ALIAS_TEST");
     }
 
+    #[test]
+    fn reloads_config_files() {
+        let dir = std::env::temp_dir().join(format!("hbbs-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.ini").to_string_lossy().to_string();
+        std::fs::write(
+            &file,
+            "RELOAD_TEST_A=1\nRELOAD_TEST_PORT=1\nRELOAD_TEST_CLI=1\nRELOAD_TEST_GONE=1\n",
+        )
+        .unwrap();
+        load_config_files(vec![file.clone()], ["RELOAD-TEST-CLI".to_owned()].into()).unwrap();
+        assert_eq!(get_arg("reload_test_a"), "1");
+        assert_eq!(get_arg_opt("reload_test_cli"), None);
+        assert_eq!(arg_source("reload_test_a"), file);
+        assert_eq!(arg_source("reload_test_cli"), "command line");
+        assert!(reload_config(&[]).unwrap().is_empty());
+
+        std::fs::write(
+            &file,
+            "RELOAD_TEST_A=2\nRELOAD_TEST_PORT=2\nRELOAD_TEST_CLI=2\nRELOAD_TEST_NEW=1\n",
+        )
+        .unwrap();
+        let changes = reload_config(&["reload_test_port"]).unwrap();
+        let names: Vec<_> = changes
+            .iter()
+            .map(|x| (x.name.as_str(), x.applied))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("RELOAD-TEST-A", true),
+                ("RELOAD-TEST-GONE", true),
+                ("RELOAD-TEST-NEW", true),
+                ("RELOAD-TEST-PORT", false)
+            ]
+        );
+        assert_eq!(changes[0].to_string(), "RELOAD-TEST-A: \"1\" -> \"2\"");
+        assert_eq!(
+            changes[3].to_string(),
+            "RELOAD-TEST-PORT: \"1\" -> \"2\", restart required"
+        );
+        assert_eq!(get_arg("reload_test_a"), "2");
+        assert_eq!(get_arg("reload_test_port"), "1");
+        assert_eq!(get_arg_opt("reload_test_gone"), None);
+        assert_eq!(get_arg_opt("reload_test_cli"), None);
+        // still pending a restart
+        assert_eq!(reload_config(&["reload_test_port"]).unwrap().len(), 1);
+
+        *BINARY.write().unwrap() = "hbbs".to_owned();
+        let file = dir.join("config.toml").to_string_lossy().to_string();
+        let load = |contents: &str| {
+            std::fs::write(&file, contents).unwrap();
+            load_config_files(vec![file.clone()], Default::default())
+        };
+        let err = |contents: &str| load(contents).unwrap_err().to_string();
+        assert!(err("software_ur = 'x'").starts_with("Unknown key \"software_ur\""));
+        assert!(err("[hbbs]\nlimit_speed = 1").starts_with("Unknown key \"limit_speed\""));
+        assert!(err("[hbbr]\nserial = 1").starts_with("Unknown key \"serial\""));
+        assert!(err("[hbbx]\nserial = 1").starts_with("Unknown section [hbbx]"));
+        assert!(err("serial = 2024-01-01").starts_with("Unsupported value"));
+        assert!(err("serial = ").starts_with("Failed to parse"));
+        load("software-url = 'https://example.com'\n[hbbs]\nserial = 5\n[hbbr]\nlimit_speed = 1\n")
+            .unwrap();
+        assert_eq!(get_arg("software_url"), "https://example.com");
+        assert_eq!(get_arg("serial"), "5");
+        assert_eq!(arg_source("serial"), format!("{file} [hbbs]"));
+        assert!(!FILE_ARGS.read().unwrap().contains_key("LIMIT-SPEED"));
+        assert!(effective_config().contains(&format!("\nserial = 5 # {file} [hbbs]\n")));
+
+        std::fs::write(&file, "[hbbs]\nserial = -1\n").unwrap();
+        let changes = reload_config(&[]).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(changes[1].applied);
+        assert_eq!(
+            changes[1].to_string(),
+            "SOFTWARE-URL: \"https://example.com\" -> (unset)"
+        );
+        let key = ConfigChange {
+            name: "KEY".to_owned(),
+            old: None,
+            new: Some("secret".to_owned()),
+            applied: false,
+            invalid: None,
+        };
+        assert_eq!(
+            key.to_string(),
+            "KEY: (unset) -> (hidden), restart required"
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            "SERIAL: \"5\" -> \"-1\", rejected: invalid SERIAL \"-1\": expected an integer from 0 to 2147483647"
+        );
+        assert_eq!(get_arg_opt("software_url"), None);
+        assert_eq!(get_arg("serial"), "5");
+        std::fs::write(&file, "serial = ").unwrap();
+        assert!(reload_config(&[]).is_err());
+        std::env::remove_var("SERIAL");
+        std::fs::remove_dir_all(dir).ok();
+    }
+
     #[test]
     fn parses_bind_address() {
         assert_eq!(parse_bind_address("").unwrap(), None);
@@ -319,6 +741,20 @@ mod tests {
         assert!(parse_bind_address("not-an-ip").is_err());
     }
 
+    #[test]
+    fn relay_status_token() {
+        let st = RelayStatus {
+            active: 2,
+            draining: true,
+            ..Default::default()
+        };
+        let st = RelayStatus::from_token(&st.to_token()).unwrap();
+        assert_eq!((st.active, st.draining), (2, true));
+        // the KEY of the request
+        assert!(RelayStatus::from_token("OeVuKk5nlHiXp+APNn0Y3pC1Iwpwn44JGqrQCsWqmBw=").is_none());
+        assert!(RelayStatus::from_token("").is_none());
+    }
+
     #[hbb_common::tokio::test]
     async fn tcp_listener_uses_bind_address() {
         let bind_addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
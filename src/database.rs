@@ -1,11 +1,14 @@
+use crate::migration::{self, Dialect, Migration, Schema};
 use async_trait::async_trait;
+use chrono::NaiveDateTime;
 use hbb_common::{log, ResultType};
 use sqlx::{
-    sqlite::SqliteConnectOptions, ConnectOptions, Connection, Error as SqlxError, SqliteConnection,
+    mysql::{MySqlPool, MySqlPoolOptions, MySqlRow},
+    postgres::{PgPool, PgPoolOptions, PgRow},
+    sqlite::SqliteConnectOptions,
+    ConnectOptions, Connection, Error as SqlxError, Executor, Row, SqliteConnection,
 };
-use std::{ops::DerefMut, str::FromStr};
-//use sqlx::postgres::PgPoolOptions;
-//use sqlx::mysql::MySqlPoolOptions;
+use std::{ops::Deref, ops::DerefMut, str::FromStr, sync::Arc};
 
 type Pool = deadpool::managed::Pool<DbPool>;
 
@@ -30,11 +33,6 @@ impl deadpool::managed::Manager for DbPool {
     }
 }
 
-#[derive(Clone)]
-pub struct Database {
-    pool: Pool,
-}
-
 #[derive(Default)]
 pub struct Peer {
     pub guid: Vec<u8>,
@@ -46,15 +44,219 @@ pub struct Peer {
     pub status: Option<i64>,
 }
 
+/// Registration of a peer, see [`Storage::update_last_seen`].
+#[derive(Debug, Clone)]
+pub struct LastSeen {
+    pub time: NaiveDateTime,
+    pub ip: String,
+}
+
+/// Timestamps are UTC.
+#[derive(Debug)]
+pub struct PeerActivity {
+    pub created_at: NaiveDateTime,
+    pub last_reg_time: Option<NaiveDateTime>,
+    pub last_ip: Option<String>,
+    pub pk_changed_at: Option<NaiveDateTime>,
+    pub status: Option<i64>,
+    pub note: Option<String>,
+}
+
+/// A row of `audit_log`, see `crate::audit`. Timestamps are UTC.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub time: NaiveDateTime,
+    pub kind: String,
+    pub source_ip: String,
+    pub target_id: String,
+    pub peer_ip: Option<String>,
+    pub uuid: Option<String>,
+    pub outcome: String,
+    pub duration_ms: Option<i64>,
+    pub bytes: Option<i64>,
+}
+
+/// Conditions of [`Storage::get_audit`], None matches anything.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub kind: Option<String>,
+    pub target_id: Option<String>,
+    pub source_ip: Option<String>,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+/// A row of `relay_usage`, the bytes relayed for a subject of a bandwidth rule
+/// in a UTC day `YYYY-MM-DD` or month `YYYY-MM`, see `crate::quota` of hbbr.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct RelayUsage {
+    pub subject: String,
+    pub period: String,
+    pub bytes: i64,
+}
+
+/// A row of `relay_session`, the accounting record of a finished relay session,
+/// see `crate::accounting` of hbbr. `addr` is the client that completed the
+/// pair, timestamps are UTC.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct RelaySession {
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+    pub uuid: String,
+    pub target_id: String,
+    pub addr: String,
+    pub peer_addr: String,
+    pub bytes_to_peer: i64,
+    pub bytes_from_peer: i64,
+    /// the highest speed in a second, in kbit/s
+    pub peak_speed: i64,
+    pub downgraded: bool,
+    pub blacklisted: bool,
+    pub throttled: bool,
+}
+
+/// `peer.status` of a disabled ID, NULL or any other value means enabled.
+pub const STATUS_DISABLED: i64 = 0;
+pub const STATUS_ENABLED: i64 = 1;
+
+/// Persistence of the peer table. One implementation per database backend,
+/// see [`Database::new`] for how it is chosen.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>>;
+
+    async fn insert_peer(
+        &self,
+        id: &str,
+        uuid: &[u8],
+        pk: &[u8],
+        info: &str,
+    ) -> ResultType<Vec<u8>>;
+
+    /// Also sets `pk_changed_at` if `pk` differs from the stored one.
+    async fn update_pk(&self, guid: &[u8], id: &str, pk: &[u8], info: &str) -> ResultType<()>;
+
+    /// Writes a batch of registrations keyed by ID, in one transaction.
+    async fn update_last_seen(&self, seen: &[(String, LastSeen)]) -> ResultType<()>;
+
+    async fn get_activity(&self, id: &str) -> ResultType<Option<PeerActivity>>;
+
+    /// The following return false if `id` does not exist.
+    async fn set_status(&self, id: &str, status: i64) -> ResultType<bool>;
+
+    async fn set_note(&self, id: &str, note: &str) -> ResultType<bool>;
+
+    async fn delete_peer(&self, id: &str) -> ResultType<bool>;
+
+    /// Appends a batch of events to `audit_log`, in one transaction.
+    async fn insert_audit(&self, events: &[AuditEvent]) -> ResultType<()>;
+
+    /// Events matching `filter` in chronological order, `from` inclusive and
+    /// `to` exclusive.
+    async fn get_audit(&self, filter: &AuditFilter) -> ResultType<Vec<AuditEvent>>;
+
+    /// Deletes the events before `time`, returns how many.
+    async fn purge_audit(&self, time: NaiveDateTime) -> ResultType<u64>;
+
+    /// Adds the bytes of a batch to `relay_usage`, in one transaction.
+    #[allow(dead_code)]
+    async fn add_relay_usage(&self, usage: &[RelayUsage]) -> ResultType<()>;
+
+    /// The usage of every subject in `period`.
+    #[allow(dead_code)]
+    async fn get_relay_usage(&self, period: &str) -> ResultType<Vec<RelayUsage>>;
+
+    /// Appends a batch of records to `relay_session`, in one transaction.
+    #[allow(dead_code)]
+    async fn insert_relay_sessions(&self, sessions: &[RelaySession]) -> ResultType<()>;
+}
+
+#[derive(Clone)]
+pub struct Database {
+    storage: Arc<dyn Storage>,
+}
+
+impl Deref for Database {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        self.storage.as_ref()
+    }
+}
+
 impl Database {
+    /// `postgres://` / `postgresql://` and `mysql://` URLs select the
+    /// corresponding server backend, anything else is a SQLite file.
     pub async fn new(url: &str) -> ResultType<Database> {
-        if !std::path::Path::new(url).exists() {
-            std::fs::File::create(url).ok();
-        }
         let n: usize = crate::common::get_arg_or("MAX_DATABASE_CONNECTIONS", "1".to_owned())
             .parse()
             .unwrap_or(1);
         log::debug!("MAX_DATABASE_CONNECTIONS={}", n);
+        let storage: Arc<dyn Storage> =
+            if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+                Arc::new(PgStorage::new(url, n).await?)
+            } else if url.starts_with("mysql://") {
+                Arc::new(MySqlStorage::new(url, n).await?)
+            } else {
+                Arc::new(SqliteStorage::new(url, n).await?)
+            };
+        Ok(Database { storage })
+    }
+}
+
+/// DB_URL, by default `db_v2.sqlite3` in the working directory, or next to
+/// the executable on Windows.
+pub fn url() -> String {
+    crate::common::get_arg_opt("DB_URL").unwrap_or_else(|| {
+        let mut db = "db_v2.sqlite3".to_owned();
+        #[cfg(all(windows, not(debug_assertions)))]
+        {
+            if let Some(path) = hbb_common::config::Config::icon_path().parent() {
+                db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
+            }
+        }
+        #[cfg(not(windows))]
+        {
+            db = format!("./{db}");
+        }
+        db
+    })
+}
+
+/// Hides the password of a database URL so it can be logged.
+pub fn redact_url(url: &str) -> String {
+    if let Some((scheme, rest)) = url.split_once("://") {
+        if let Some((credentials, host)) = rest.rsplit_once('@') {
+            if let Some((user, _)) = credentials.split_once(':') {
+                return format!("{scheme}://{user}:***@{host}");
+            }
+        }
+    }
+    url.to_owned()
+}
+
+fn new_guid() -> Vec<u8> {
+    uuid::Uuid::new_v4().as_bytes().to_vec()
+}
+
+#[inline]
+fn utc_now() -> NaiveDateTime {
+    chrono::Utc::now().naive_utc()
+}
+
+pub struct SqliteStorage {
+    pool: Pool,
+}
+
+impl SqliteStorage {
+    async fn new(url: &str, n: usize) -> ResultType<Self> {
+        if !std::path::Path::new(url).exists() {
+            std::fs::File::create(url).ok();
+        }
         let pool = Pool::new(
             DbPool {
                 url: url.to_owned(),
@@ -62,37 +264,15 @@ impl Database {
             n,
         );
         let _ = pool.get().await?; // test
-        let db = Database { pool };
-        db.create_tables().await?;
+        let db = SqliteStorage { pool };
+        migration::run(&db).await?;
         Ok(db)
     }
+}
 
-    async fn create_tables(&self) -> ResultType<()> {
-        sqlx::query!(
-            "
-            create table if not exists peer (
-                guid blob primary key not null,
-                id varchar(100) not null,
-                uuid blob not null,
-                pk blob not null,
-                created_at datetime not null default(current_timestamp),
-                user blob,
-                status tinyint,
-                note varchar(300),
-                info text not null
-            ) without rowid;
-            create unique index if not exists index_peer_id on peer (id);
-            create index if not exists index_peer_user on peer (user);
-            create index if not exists index_peer_created_at on peer (created_at);
-            create index if not exists index_peer_status on peer (status);
-        "
-        )
-        .execute(self.pool.get().await?.deref_mut())
-        .await?;
-        Ok(())
-    }
-
-    pub async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
         Ok(sqlx::query_as!(
             Peer,
             "select guid, id, uuid, pk, user, status, info from peer where id = ?",
@@ -102,36 +282,36 @@ impl Database {
         .await?)
     }
 
-    pub async fn insert_peer(
+    async fn insert_peer(
         &self,
         id: &str,
         uuid: &[u8],
         pk: &[u8],
         info: &str,
     ) -> ResultType<Vec<u8>> {
-        let guid = uuid::Uuid::new_v4().as_bytes().to_vec();
+        let guid = new_guid();
+        let now = utc_now();
         sqlx::query!(
-            "insert into peer(guid, id, uuid, pk, info) values(?, ?, ?, ?, ?)",
+            "insert into peer(guid, id, uuid, pk, info, pk_changed_at) values(?, ?, ?, ?, ?, ?)",
             guid,
             id,
             uuid,
             pk,
-            info
+            info,
+            now
         )
         .execute(self.pool.get().await?.deref_mut())
         .await?;
         Ok(guid)
     }
 
-    pub async fn update_pk(
-        &self,
-        guid: &Vec<u8>,
-        id: &str,
-        pk: &[u8],
-        info: &str,
-    ) -> ResultType<()> {
+    async fn update_pk(&self, guid: &[u8], id: &str, pk: &[u8], info: &str) -> ResultType<()> {
+        let now = utc_now();
         sqlx::query!(
-            "update peer set id=?, pk=?, info=? where guid=?",
+            "update peer set pk_changed_at=(case when pk=? then pk_changed_at else ? end),
+                id=?, pk=?, info=? where guid=?",
+            pk,
+            now,
             id,
             pk,
             info,
@@ -141,6 +321,844 @@ impl Database {
         .await?;
         Ok(())
     }
+
+    async fn update_last_seen(&self, seen: &[(String, LastSeen)]) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        for (id, v) in seen {
+            sqlx::query!(
+                "update peer set last_reg_time=?, last_ip=? where id=?",
+                v.time,
+                v.ip,
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_activity(&self, id: &str) -> ResultType<Option<PeerActivity>> {
+        Ok(sqlx::query_as!(
+            PeerActivity,
+            "select created_at, last_reg_time, last_ip, pk_changed_at, status, note
+                from peer where id = ?",
+            id
+        )
+        .fetch_optional(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    async fn set_status(&self, id: &str, status: i64) -> ResultType<bool> {
+        let res = sqlx::query!("update peer set status=? where id=?", status, id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_note(&self, id: &str, note: &str) -> ResultType<bool> {
+        let res = sqlx::query!("update peer set note=? where id=?", note, id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn delete_peer(&self, id: &str) -> ResultType<bool> {
+        let res = sqlx::query!("delete from peer where id=?", id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn insert_audit(&self, events: &[AuditEvent]) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        for v in events {
+            sqlx::query!(
+                "insert into audit_log(time, kind, source_ip, target_id, peer_ip, uuid, outcome,
+                    duration_ms, bytes) values(?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                v.time,
+                v.kind,
+                v.source_ip,
+                v.target_id,
+                v.peer_ip,
+                v.uuid,
+                v.outcome,
+                v.duration_ms,
+                v.bytes
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_audit(&self, filter: &AuditFilter) -> ResultType<Vec<AuditEvent>> {
+        Ok(sqlx::query_as!(
+            AuditEvent,
+            "select time, kind, source_ip, target_id, peer_ip, uuid, outcome, duration_ms, bytes
+                from audit_log
+                where (?1 is null or time >= ?1) and (?2 is null or time < ?2)
+                    and (?3 is null or kind = ?3) and (?4 is null or target_id = ?4)
+                    and (?5 is null or source_ip = ?5)
+                order by time, seq limit ?6 offset ?7",
+            filter.from,
+            filter.to,
+            filter.kind,
+            filter.target_id,
+            filter.source_ip,
+            filter.limit,
+            filter.offset
+        )
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    async fn purge_audit(&self, time: NaiveDateTime) -> ResultType<u64> {
+        let res = sqlx::query!("delete from audit_log where time < ?", time)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn add_relay_usage(&self, usage: &[RelayUsage]) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        for v in usage {
+            sqlx::query!(
+                "insert into relay_usage(subject, period, bytes) values(?, ?, ?)
+                    on conflict(subject, period) do update set bytes = bytes + excluded.bytes",
+                v.subject,
+                v.period,
+                v.bytes
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_relay_usage(&self, period: &str) -> ResultType<Vec<RelayUsage>> {
+        Ok(sqlx::query_as!(
+            RelayUsage,
+            "select subject, period, bytes from relay_usage where period = ?",
+            period
+        )
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    async fn insert_relay_sessions(&self, sessions: &[RelaySession]) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        for v in sessions {
+            sqlx::query!(
+                "insert into relay_session(start_time, end_time, uuid, target_id, addr, peer_addr,
+                    bytes_to_peer, bytes_from_peer, peak_speed, downgraded, blacklisted, throttled)
+                    values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                v.start_time,
+                v.end_time,
+                v.uuid,
+                v.target_id,
+                v.addr,
+                v.peer_addr,
+                v.bytes_to_peer,
+                v.bytes_from_peer,
+                v.peak_speed,
+                v.downgraded,
+                v.blacklisted,
+                v.throttled
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Schema for SqliteStorage {
+    fn dialect(&self) -> Dialect {
+        Dialect::Sqlite
+    }
+
+    async fn schema_version(&self) -> ResultType<i64> {
+        let mut conn = self.pool.get().await?;
+        let exists = sqlx::query_scalar::<_, i64>(
+            "select count(*) from sqlite_master where type = 'table' and name = 'schema_version'",
+        )
+        .fetch_one(conn.deref_mut())
+        .await?;
+        if exists == 0 {
+            return Ok(0);
+        }
+        let version =
+            sqlx::query_scalar::<_, Option<i64>>("select max(version) from schema_version")
+                .fetch_one(conn.deref_mut())
+                .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    async fn apply(&self, migration: &Migration) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.deref_mut().begin().await?;
+        tx.execute(migration::version_table_sql(Dialect::Sqlite))
+            .await?;
+        for sql in migration.sql(Dialect::Sqlite) {
+            tx.execute(*sql).await?;
+        }
+        sqlx::query("insert into schema_version(version, name) values(?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub struct PgStorage {
+    pool: PgPool,
+}
+
+impl PgStorage {
+    async fn new(url: &str, n: usize) -> ResultType<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(n as _)
+            .connect(url)
+            .await?;
+        let db = PgStorage { pool };
+        migration::run(&db).await?;
+        Ok(db)
+    }
+
+    fn peer_from_row(row: PgRow) -> ResultType<Peer> {
+        Ok(Peer {
+            guid: row.try_get("guid")?,
+            id: row.try_get("id")?,
+            uuid: row.try_get("uuid")?,
+            pk: row.try_get("pk")?,
+            user: row.try_get("user")?,
+            info: row.try_get("info")?,
+            status: row.try_get::<Option<i16>, _>("status")?.map(|x| x as _),
+        })
+    }
+
+    fn audit_from_row(row: PgRow) -> ResultType<AuditEvent> {
+        Ok(AuditEvent {
+            time: row.try_get("time")?,
+            kind: row.try_get("kind")?,
+            source_ip: row.try_get("source_ip")?,
+            target_id: row.try_get("target_id")?,
+            peer_ip: row.try_get("peer_ip")?,
+            uuid: row.try_get("uuid")?,
+            outcome: row.try_get("outcome")?,
+            duration_ms: row.try_get("duration_ms")?,
+            bytes: row.try_get("bytes")?,
+        })
+    }
+
+    #[allow(dead_code)]
+    fn usage_from_row(row: PgRow) -> ResultType<RelayUsage> {
+        Ok(RelayUsage {
+            subject: row.try_get("subject")?,
+            period: row.try_get("period")?,
+            bytes: row.try_get("bytes")?,
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
+        sqlx::query(r#"select guid, id, uuid, pk, "user", status, info from peer where id = $1"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(Self::peer_from_row)
+            .transpose()
+    }
+
+    async fn insert_peer(
+        &self,
+        id: &str,
+        uuid: &[u8],
+        pk: &[u8],
+        info: &str,
+    ) -> ResultType<Vec<u8>> {
+        let guid = new_guid();
+        sqlx::query(
+            "insert into peer(guid, id, uuid, pk, info, pk_changed_at)
+                values($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(&guid)
+        .bind(id)
+        .bind(uuid)
+        .bind(pk)
+        .bind(info)
+        .bind(utc_now())
+        .execute(&self.pool)
+        .await?;
+        Ok(guid)
+    }
+
+    async fn update_pk(&self, guid: &[u8], id: &str, pk: &[u8], info: &str) -> ResultType<()> {
+        sqlx::query(
+            "update peer set pk_changed_at=(case when pk=$2 then pk_changed_at else $5 end),
+                id=$1, pk=$2, info=$3 where guid=$4",
+        )
+        .bind(id)
+        .bind(pk)
+        .bind(info)
+        .bind(guid)
+        .bind(utc_now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, seen: &[(String, LastSeen)]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for (id, v) in seen {
+            sqlx::query("update peer set last_reg_time=$1, last_ip=$2 where id=$3")
+                .bind(v.time)
+                .bind(&v.ip)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_activity(&self, id: &str) -> ResultType<Option<PeerActivity>> {
+        sqlx::query(
+            "select created_at, last_reg_time, last_ip, pk_changed_at, status, note
+                from peer where id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| {
+            Ok(PeerActivity {
+                created_at: row.try_get("created_at")?,
+                last_reg_time: row.try_get("last_reg_time")?,
+                last_ip: row.try_get("last_ip")?,
+                pk_changed_at: row.try_get("pk_changed_at")?,
+                status: row.try_get::<Option<i16>, _>("status")?.map(|x| x as _),
+                note: row.try_get("note")?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn set_status(&self, id: &str, status: i64) -> ResultType<bool> {
+        let res = sqlx::query("update peer set status=$1 where id=$2")
+            .bind(status as i16)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_note(&self, id: &str, note: &str) -> ResultType<bool> {
+        let res = sqlx::query("update peer set note=$1 where id=$2")
+            .bind(note)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn delete_peer(&self, id: &str) -> ResultType<bool> {
+        let res = sqlx::query("delete from peer where id=$1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn insert_audit(&self, events: &[AuditEvent]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for v in events {
+            sqlx::query(
+                "insert into audit_log(time, kind, source_ip, target_id, peer_ip, uuid, outcome,
+                    duration_ms, bytes) values($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            )
+            .bind(v.time)
+            .bind(&v.kind)
+            .bind(&v.source_ip)
+            .bind(&v.target_id)
+            .bind(&v.peer_ip)
+            .bind(&v.uuid)
+            .bind(&v.outcome)
+            .bind(v.duration_ms)
+            .bind(v.bytes)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_audit(&self, filter: &AuditFilter) -> ResultType<Vec<AuditEvent>> {
+        sqlx::query(
+            "select time, kind, source_ip, target_id, peer_ip, uuid, outcome, duration_ms, bytes
+                from audit_log
+                where ($1::timestamp is null or time >= $1) and ($2::timestamp is null or time < $2)
+                    and ($3::varchar is null or kind = $3) and ($4::varchar is null or target_id = $4)
+                    and ($5::varchar is null or source_ip = $5)
+                order by time, seq limit $6 offset $7",
+        )
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(&filter.kind)
+        .bind(&filter.target_id)
+        .bind(&filter.source_ip)
+        .bind(filter.limit)
+        .bind(filter.offset)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Self::audit_from_row)
+        .collect()
+    }
+
+    async fn purge_audit(&self, time: NaiveDateTime) -> ResultType<u64> {
+        let res = sqlx::query("delete from audit_log where time < $1")
+            .bind(time)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn add_relay_usage(&self, usage: &[RelayUsage]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for v in usage {
+            sqlx::query(
+                "insert into relay_usage(subject, period, bytes) values($1, $2, $3)
+                    on conflict(subject, period) do update
+                    set bytes = relay_usage.bytes + excluded.bytes",
+            )
+            .bind(&v.subject)
+            .bind(&v.period)
+            .bind(v.bytes)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_relay_usage(&self, period: &str) -> ResultType<Vec<RelayUsage>> {
+        sqlx::query("select subject, period, bytes from relay_usage where period = $1")
+            .bind(period)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(Self::usage_from_row)
+            .collect()
+    }
+
+    async fn insert_relay_sessions(&self, sessions: &[RelaySession]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for v in sessions {
+            sqlx::query(
+                "insert into relay_session(start_time, end_time, uuid, target_id, addr, peer_addr,
+                    bytes_to_peer, bytes_from_peer, peak_speed, downgraded, blacklisted, throttled)
+                    values($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            )
+            .bind(v.start_time)
+            .bind(v.end_time)
+            .bind(&v.uuid)
+            .bind(&v.target_id)
+            .bind(&v.addr)
+            .bind(&v.peer_addr)
+            .bind(v.bytes_to_peer)
+            .bind(v.bytes_from_peer)
+            .bind(v.peak_speed)
+            .bind(v.downgraded)
+            .bind(v.blacklisted)
+            .bind(v.throttled)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Schema for PgStorage {
+    fn dialect(&self) -> Dialect {
+        Dialect::Postgres
+    }
+
+    async fn schema_version(&self) -> ResultType<i64> {
+        let exists = sqlx::query_scalar::<_, i64>(
+            "select count(*) from information_schema.tables
+                where table_schema = current_schema() and table_name = 'schema_version'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if exists == 0 {
+            return Ok(0);
+        }
+        let version =
+            sqlx::query_scalar::<_, Option<i64>>("select max(version) from schema_version")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    async fn apply(&self, migration: &Migration) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(migration::version_table_sql(Dialect::Postgres))
+            .await?;
+        for sql in migration.sql(Dialect::Postgres) {
+            tx.execute(*sql).await?;
+        }
+        sqlx::query("insert into schema_version(version, name) values($1, $2)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub struct MySqlStorage {
+    pool: MySqlPool,
+}
+
+impl MySqlStorage {
+    async fn new(url: &str, n: usize) -> ResultType<Self> {
+        let pool = MySqlPoolOptions::new()
+            .max_connections(n as _)
+            .connect(url)
+            .await?;
+        let db = MySqlStorage { pool };
+        migration::run(&db).await?;
+        Ok(db)
+    }
+
+    async fn exists(&self, id: &str) -> ResultType<bool> {
+        Ok(sqlx::query("select 1 from peer where id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some())
+    }
+
+    fn peer_from_row(row: MySqlRow) -> ResultType<Peer> {
+        Ok(Peer {
+            guid: row.try_get("guid")?,
+            id: row.try_get("id")?,
+            uuid: row.try_get("uuid")?,
+            pk: row.try_get("pk")?,
+            user: row.try_get("user")?,
+            info: row.try_get("info")?,
+            status: row.try_get("status")?,
+        })
+    }
+
+    fn audit_from_row(row: MySqlRow) -> ResultType<AuditEvent> {
+        Ok(AuditEvent {
+            time: row.try_get("time")?,
+            kind: row.try_get("kind")?,
+            source_ip: row.try_get("source_ip")?,
+            target_id: row.try_get("target_id")?,
+            peer_ip: row.try_get("peer_ip")?,
+            uuid: row.try_get("uuid")?,
+            outcome: row.try_get("outcome")?,
+            duration_ms: row.try_get("duration_ms")?,
+            bytes: row.try_get("bytes")?,
+        })
+    }
+
+    #[allow(dead_code)]
+    fn usage_from_row(row: MySqlRow) -> ResultType<RelayUsage> {
+        Ok(RelayUsage {
+            subject: row.try_get("subject")?,
+            period: row.try_get("period")?,
+            bytes: row.try_get("bytes")?,
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for MySqlStorage {
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
+        sqlx::query("select guid, id, uuid, pk, `user`, status, info from peer where id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(Self::peer_from_row)
+            .transpose()
+    }
+
+    async fn insert_peer(
+        &self,
+        id: &str,
+        uuid: &[u8],
+        pk: &[u8],
+        info: &str,
+    ) -> ResultType<Vec<u8>> {
+        let guid = new_guid();
+        sqlx::query(
+            "insert into peer(guid, id, uuid, pk, info, pk_changed_at) values(?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&guid)
+        .bind(id)
+        .bind(uuid)
+        .bind(pk)
+        .bind(info)
+        .bind(utc_now())
+        .execute(&self.pool)
+        .await?;
+        Ok(guid)
+    }
+
+    async fn update_pk(&self, guid: &[u8], id: &str, pk: &[u8], info: &str) -> ResultType<()> {
+        // MySQL evaluates the assignments from left to right, pk_changed_at
+        // must be compared against the old pk
+        sqlx::query(
+            "update peer set pk_changed_at=(case when pk=? then pk_changed_at else ? end),
+                id=?, pk=?, info=? where guid=?",
+        )
+        .bind(pk)
+        .bind(utc_now())
+        .bind(id)
+        .bind(pk)
+        .bind(info)
+        .bind(guid)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, seen: &[(String, LastSeen)]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for (id, v) in seen {
+            sqlx::query("update peer set last_reg_time=?, last_ip=? where id=?")
+                .bind(v.time)
+                .bind(&v.ip)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_activity(&self, id: &str) -> ResultType<Option<PeerActivity>> {
+        sqlx::query(
+            "select created_at, last_reg_time, last_ip, pk_changed_at, status, note
+                from peer where id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| {
+            Ok(PeerActivity {
+                created_at: row.try_get("created_at")?,
+                last_reg_time: row.try_get("last_reg_time")?,
+                last_ip: row.try_get("last_ip")?,
+                pk_changed_at: row.try_get("pk_changed_at")?,
+                status: row.try_get("status")?,
+                note: row.try_get("note")?,
+            })
+        })
+        .transpose()
+    }
+
+    // rows_affected() of MySQL only counts rows actually changed
+    async fn set_status(&self, id: &str, status: i64) -> ResultType<bool> {
+        sqlx::query("update peer set status=? where id=?")
+            .bind(status)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.exists(id).await
+    }
+
+    async fn set_note(&self, id: &str, note: &str) -> ResultType<bool> {
+        sqlx::query("update peer set note=? where id=?")
+            .bind(note)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.exists(id).await
+    }
+
+    async fn delete_peer(&self, id: &str) -> ResultType<bool> {
+        let res = sqlx::query("delete from peer where id=?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn insert_audit(&self, events: &[AuditEvent]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for v in events {
+            sqlx::query(
+                "insert into audit_log(time, kind, source_ip, target_id, peer_ip, uuid, outcome,
+                    duration_ms, bytes) values(?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(v.time)
+            .bind(&v.kind)
+            .bind(&v.source_ip)
+            .bind(&v.target_id)
+            .bind(&v.peer_ip)
+            .bind(&v.uuid)
+            .bind(&v.outcome)
+            .bind(v.duration_ms)
+            .bind(v.bytes)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // no numbered parameters in MySQL, each one is bound twice
+    async fn get_audit(&self, filter: &AuditFilter) -> ResultType<Vec<AuditEvent>> {
+        sqlx::query(
+            "select time, kind, source_ip, target_id, peer_ip, uuid, outcome, duration_ms, bytes
+                from audit_log
+                where (? is null or time >= ?) and (? is null or time < ?)
+                    and (? is null or kind = ?) and (? is null or target_id = ?)
+                    and (? is null or source_ip = ?)
+                order by time, seq limit ? offset ?",
+        )
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(&filter.kind)
+        .bind(&filter.kind)
+        .bind(&filter.target_id)
+        .bind(&filter.target_id)
+        .bind(&filter.source_ip)
+        .bind(&filter.source_ip)
+        .bind(filter.limit)
+        .bind(filter.offset)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Self::audit_from_row)
+        .collect()
+    }
+
+    async fn purge_audit(&self, time: NaiveDateTime) -> ResultType<u64> {
+        let res = sqlx::query("delete from audit_log where time < ?")
+            .bind(time)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn add_relay_usage(&self, usage: &[RelayUsage]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for v in usage {
+            sqlx::query(
+                "insert into relay_usage(subject, period, bytes) values(?, ?, ?)
+                    on duplicate key update bytes = bytes + values(bytes)",
+            )
+            .bind(&v.subject)
+            .bind(&v.period)
+            .bind(v.bytes)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_relay_usage(&self, period: &str) -> ResultType<Vec<RelayUsage>> {
+        sqlx::query("select subject, period, bytes from relay_usage where period = ?")
+            .bind(period)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(Self::usage_from_row)
+            .collect()
+    }
+
+    async fn insert_relay_sessions(&self, sessions: &[RelaySession]) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        for v in sessions {
+            sqlx::query(
+                "insert into relay_session(start_time, end_time, uuid, target_id, addr, peer_addr,
+                    bytes_to_peer, bytes_from_peer, peak_speed, downgraded, blacklisted, throttled)
+                    values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(v.start_time)
+            .bind(v.end_time)
+            .bind(&v.uuid)
+            .bind(&v.target_id)
+            .bind(&v.addr)
+            .bind(&v.peer_addr)
+            .bind(v.bytes_to_peer)
+            .bind(v.bytes_from_peer)
+            .bind(v.peak_speed)
+            .bind(v.downgraded)
+            .bind(v.blacklisted)
+            .bind(v.throttled)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+// MySQL commits DDL statements implicitly, the transaction only covers the
+// version record.
+#[async_trait]
+impl Schema for MySqlStorage {
+    fn dialect(&self) -> Dialect {
+        Dialect::MySql
+    }
+
+    async fn schema_version(&self) -> ResultType<i64> {
+        let exists = sqlx::query_scalar::<_, i64>(
+            "select count(*) from information_schema.tables
+                where table_schema = database() and table_name = 'schema_version'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if exists == 0 {
+            return Ok(0);
+        }
+        let version =
+            sqlx::query_scalar::<_, Option<i64>>("select max(version) from schema_version")
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    async fn apply(&self, migration: &Migration) -> ResultType<()> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(migration::version_table_sql(Dialect::MySql))
+            .await?;
+        for sql in migration.sql(Dialect::MySql) {
+            tx.execute(*sql).await?;
+        }
+        sqlx::query("insert into schema_version(version, name) values(?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -177,4 +1195,206 @@ mod tests {
         }
         hbb_common::futures::future::join_all(jobs).await;
     }
+
+    #[test]
+    fn test_last_seen() {
+        last_seen();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn last_seen() {
+        let path = "test_last_seen.sqlite3";
+        std::fs::remove_file(path).ok();
+        let db = super::Database::new(path).await.unwrap();
+        let guid = db.insert_peer("a", b"uuid", b"pk1", "").await.unwrap();
+        let v = db.get_activity("a").await.unwrap().unwrap();
+        assert!(v.last_reg_time.is_none());
+        let pk_changed_at = v.pk_changed_at.unwrap();
+        let time = super::utc_now();
+        let seen = super::LastSeen {
+            time,
+            ip: "1.2.3.4".to_owned(),
+        };
+        db.update_last_seen(&[("a".to_owned(), seen)])
+            .await
+            .unwrap();
+        db.update_pk(&guid, "a", b"pk1", "").await.unwrap();
+        let v = db.get_activity("a").await.unwrap().unwrap();
+        assert_eq!(v.last_reg_time, Some(time));
+        assert_eq!(v.last_ip.as_deref(), Some("1.2.3.4"));
+        assert_eq!(v.pk_changed_at, Some(pk_changed_at));
+        db.update_pk(&guid, "a", b"pk2", "").await.unwrap();
+        let v = db.get_activity("a").await.unwrap().unwrap();
+        assert!(v.pk_changed_at.unwrap() > pk_changed_at);
+        assert!(db.get_activity("b").await.unwrap().is_none());
+        drop(db);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_status_note_delete() {
+        status_note_delete();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn status_note_delete() {
+        let path = "test_status_note_delete.sqlite3";
+        std::fs::remove_file(path).ok();
+        let db = super::Database::new(path).await.unwrap();
+        db.insert_peer("a", b"uuid", b"pk", "").await.unwrap();
+        assert!(db.set_status("a", super::STATUS_DISABLED).await.unwrap());
+        assert!(db.set_note("a", "stolen").await.unwrap());
+        let v = db.get_activity("a").await.unwrap().unwrap();
+        assert_eq!(v.status, Some(super::STATUS_DISABLED));
+        assert_eq!(v.note.as_deref(), Some("stolen"));
+        assert_eq!(
+            db.get_peer("a").await.unwrap().unwrap().status,
+            Some(super::STATUS_DISABLED)
+        );
+        assert!(!db.set_status("b", super::STATUS_ENABLED).await.unwrap());
+        assert!(db.delete_peer("a").await.unwrap());
+        assert!(!db.delete_peer("a").await.unwrap());
+        assert!(db.get_peer("a").await.unwrap().is_none());
+        drop(db);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_audit() {
+        audit();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn audit() {
+        let path = "test_audit.sqlite3";
+        std::fs::remove_file(path).ok();
+        let db = super::Database::new(path).await.unwrap();
+        let time = super::utc_now();
+        let event = |secs: i64, id: &str, outcome: &str| super::AuditEvent {
+            time: time - chrono::Duration::seconds(secs),
+            kind: "punch_hole".to_owned(),
+            source_ip: "1.2.3.4".to_owned(),
+            target_id: id.to_owned(),
+            peer_ip: None,
+            uuid: None,
+            outcome: outcome.to_owned(),
+            duration_ms: None,
+            bytes: None,
+        };
+        let relay = super::AuditEvent {
+            kind: "relay_close".to_owned(),
+            peer_ip: Some("5.6.7.8".to_owned()),
+            uuid: Some("uuid".to_owned()),
+            duration_ms: Some(1500),
+            bytes: Some(1 << 40),
+            ..event(10, "a", "CLOSED")
+        };
+        db.insert_audit(&[event(30, "a", "OK"), event(20, "b", "OFFLINE"), relay])
+            .await
+            .unwrap();
+        let filter = |from: i64, id: Option<&str>| super::AuditFilter {
+            from: Some(time - chrono::Duration::seconds(from)),
+            target_id: id.map(str::to_owned),
+            limit: 100,
+            ..Default::default()
+        };
+        let v = db.get_audit(&filter(60, Some("a"))).await.unwrap();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[0].outcome, "OK");
+        assert_eq!(v[1].bytes, Some(1 << 40));
+        assert_eq!(v[1].peer_ip.as_deref(), Some("5.6.7.8"));
+        assert_eq!(db.get_audit(&filter(25, None)).await.unwrap().len(), 2);
+        assert_eq!(
+            db.purge_audit(time - chrono::Duration::seconds(15))
+                .await
+                .unwrap(),
+            2
+        );
+        let v = db.get_audit(&filter(60, None)).await.unwrap();
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0].duration_ms, Some(1500));
+        drop(db);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_relay_usage() {
+        relay_usage();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn relay_usage() {
+        let path = "test_relay_usage.sqlite3";
+        std::fs::remove_file(path).ok();
+        let db = super::Database::new(path).await.unwrap();
+        let usage = |subject: &str, period: &str, bytes: i64| super::RelayUsage {
+            subject: subject.to_owned(),
+            period: period.to_owned(),
+            bytes,
+        };
+        db.add_relay_usage(&[
+            usage("key:a", "2024-05-01", 10),
+            usage("key:a", "2024-05", 10),
+        ])
+        .await
+        .unwrap();
+        db.add_relay_usage(&[
+            usage("key:a", "2024-05", 5),
+            usage("id:1", "2024-05", 1 << 40),
+        ])
+        .await
+        .unwrap();
+        let mut v = db.get_relay_usage("2024-05").await.unwrap();
+        v.sort_by(|a, b| a.subject.cmp(&b.subject));
+        assert_eq!(v.len(), 2);
+        assert_eq!((v[0].subject.as_str(), v[0].bytes), ("id:1", 1 << 40));
+        assert_eq!((v[1].subject.as_str(), v[1].bytes), ("key:a", 15));
+        assert_eq!(db.get_relay_usage("2024-05-01").await.unwrap().len(), 1);
+        assert!(db.get_relay_usage("2024-06").await.unwrap().is_empty());
+        drop(db);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_schema_version() {
+        schema_version();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn schema_version() {
+        use crate::migration::{latest_version, Schema};
+        use sqlx::Executor;
+        use std::ops::DerefMut;
+        let path = "test_schema_version.sqlite3";
+        std::fs::remove_file(path).ok();
+        let db = super::SqliteStorage::new(path, 1).await.unwrap();
+        assert_eq!(db.schema_version().await.unwrap(), latest_version());
+        let mut conn = db.pool.get().await.unwrap();
+        conn.deref_mut()
+            .execute("drop table schema_version")
+            .await
+            .unwrap();
+        assert_eq!(db.schema_version().await.unwrap(), 0);
+        // unreadable, not empty
+        conn.deref_mut()
+            .execute("create table schema_version (x integer)")
+            .await
+            .unwrap();
+        assert!(db.schema_version().await.is_err());
+        drop((conn, db));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn redacts_database_password() {
+        assert_eq!(
+            super::redact_url("postgres://hbbs:secret@db:5432/rustdesk"),
+            "postgres://hbbs:***@db:5432/rustdesk"
+        );
+        assert_eq!(
+            super::redact_url("mysql://hbbs@db/rustdesk"),
+            "mysql://hbbs@db/rustdesk"
+        );
+        assert_eq!(super::redact_url("./db_v2.sqlite3"), "./db_v2.sqlite3");
+    }
 }
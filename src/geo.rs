@@ -0,0 +1,208 @@
+// Region lookup for relay selection, loaded from GEO_FILE.
+// Each line is `<cidr>,<region>[,...]`. Extra columns, blank lines, `#` comments
+// and lines whose first column is not a CIDR (e.g. a CSV header) are ignored, so
+// MaxMind's GeoLite2-*-Blocks-IPv4/IPv6.csv can be used as is, with geoname_id
+// as region. Overlapping networks are allowed, the longest prefix wins.
+
+use crate::{common::get_arg, prefix_table::PrefixTable};
+use hbb_common::{bail, config, log, tokio, ResultType};
+use ipnetwork::IpNetwork;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+lazy_static::lazy_static! {
+    static ref GEO: RwLock<Arc<GeoDb>> = Default::default();
+    // relay server as configured -> its resolved addresses
+    static ref RELAY_ADDRS: RwLock<HashMap<String, Vec<IpAddr>>> = Default::default();
+}
+
+#[derive(Default)]
+pub(crate) struct GeoDb {
+    v4: PrefixTable<Arc<str>>,
+    v6: PrefixTable<Arc<str>>,
+}
+
+impl GeoDb {
+    pub(crate) fn parse(content: &str) -> Self {
+        let mut db = Self::default();
+        let mut regions: HashMap<&str, Arc<str>> = HashMap::new();
+        for line in content.lines() {
+            let mut fds = line.split(',').map(|x| x.trim().trim_matches('"'));
+            let (Some(net), Some(region)) = (fds.next(), fds.next()) else {
+                continue;
+            };
+            if region.is_empty() || net.starts_with('#') {
+                continue;
+            }
+            let Ok(net) = net.parse::<IpNetwork>() else {
+                continue;
+            };
+            let region = regions
+                .entry(region)
+                .or_insert_with(|| Arc::from(region))
+                .clone();
+            match net {
+                IpNetwork::V4(v4) => db
+                    .v4
+                    .insert(32, u32::from(v4.ip()) as _, v4.prefix(), region),
+                IpNetwork::V6(v6) => db.v6.insert(128, u128::from(v6.ip()), v6.prefix(), region),
+            }
+        }
+        db
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub(crate) fn region(&self, ip: IpAddr) -> Option<Arc<str>> {
+        match ip {
+            IpAddr::V4(v4) => self.v4.matches(32, u32::from(v4) as _).next(),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => self.v4.matches(32, u32::from(v4) as _).next(),
+                None => self.v6.matches(128, u128::from(v6)).next(),
+            },
+        }
+        .cloned()
+    }
+}
+
+/// (Re)loads GEO_FILE, returns the number of networks. An empty GEO_FILE
+/// unloads the database, relays are then picked round-robin.
+pub(crate) fn reload() -> ResultType<usize> {
+    let path = get_arg("geo-file");
+    let db = if path.is_empty() {
+        GeoDb::default()
+    } else {
+        match std::fs::read_to_string(&path) {
+            Ok(content) => GeoDb::parse(&content),
+            Err(err) => bail!("Failed to read GEO_FILE {}: {}", path, err),
+        }
+    };
+    let n = db.len();
+    *GEO.write().unwrap() = Arc::new(db);
+    if !path.is_empty() {
+        log::info!("GEO_FILE={}, {} networks loaded", path, n);
+    }
+    Ok(n)
+}
+
+#[inline]
+pub(crate) fn region(ip: IpAddr) -> Option<Arc<str>> {
+    GEO.read().unwrap().region(ip)
+}
+
+/// Resolves relays not resolved yet, their addresses are kept until restart.
+pub(crate) async fn resolve_relays(relays: &[String]) {
+    for relay in relays {
+        if RELAY_ADDRS.read().unwrap().contains_key(relay) {
+            continue;
+        }
+        let mut host = relay.to_owned();
+        if !host.contains(':') {
+            host = format!("{}:{}", host, config::RELAY_PORT);
+        }
+        let res = tokio::net::lookup_host(&host).await;
+        match res {
+            Ok(addrs) => {
+                let addrs: Vec<_> = addrs.map(|x| x.ip()).collect();
+                log::debug!("relay server {} resolved to {:?}", relay, addrs);
+                RELAY_ADDRS.write().unwrap().insert(relay.to_owned(), addrs);
+            }
+            Err(err) => log::warn!("Failed to resolve relay server {}: {}", relay, err),
+        }
+    }
+}
+
+pub(crate) fn relay_region(relay: &str) -> Option<Arc<str>> {
+    let geo = GEO.read().unwrap().clone();
+    RELAY_ADDRS
+        .read()
+        .unwrap()
+        .get(relay)?
+        .iter()
+        .find_map(|ip| geo.region(*ip))
+}
+
+/// Relays in the region of either peer, preferring those in the region both
+/// are in, all relays if none matches.
+pub(crate) fn candidates<'a>(
+    relays: &'a [String],
+    ra: Option<&str>,
+    rb: Option<&str>,
+    relay_region: impl Fn(&str) -> Option<Arc<str>>,
+) -> Vec<&'a String> {
+    let regions: Vec<_> = relays.iter().map(|x| relay_region(x)).collect();
+    let pick = |wanted: &[Option<&str>]| -> Vec<&'a String> {
+        relays
+            .iter()
+            .zip(regions.iter())
+            .filter(|(_, r)| r.is_some() && wanted.contains(&r.as_deref()))
+            .map(|(x, _)| x)
+            .collect()
+    };
+    let mut res = Vec::new();
+    if ra.is_some() && ra == rb {
+        res = pick(&[ra]);
+    } else if ra.is_some() || rb.is_some() {
+        res = pick(&[ra, rb]);
+    }
+    if res.is_empty() {
+        res = relays.iter().collect();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_and_candidates() {
+        let db = GeoDb::parse(
+            "network,geoname_id,registered_country_geoname_id\n\
+             # comment\n\
+             10.0.0.0/8,eu\n\
+             10.1.0.0/16,us,extra\n\
+             0.0.0.0/0,default\n\
+             2001:db8::/32,\"asia\"\n\
+             bad,x\n",
+        );
+        assert_eq!(db.len(), 4);
+        let get = |ip: &str| db.region(ip.parse().unwrap()).map(|x| x.to_string());
+        assert_eq!(get("10.2.3.4").as_deref(), Some("eu"));
+        assert_eq!(get("10.1.3.4").as_deref(), Some("us"));
+        assert_eq!(get("::ffff:10.1.3.4").as_deref(), Some("us"));
+        assert_eq!(get("8.8.8.8").as_deref(), Some("default"));
+        assert_eq!(get("2001:db8::1").as_deref(), Some("asia"));
+        assert_eq!(get("2001:db9::1"), None);
+
+        let relays: Vec<String> = ["eu1", "eu2", "us1", "x"].map(String::from).into();
+        let relay_region = |x: &str| match x {
+            "eu1" | "eu2" => Some(Arc::from("eu")),
+            "us1" => Some(Arc::from("us")),
+            _ => None,
+        };
+        let names = |v: Vec<&String>| v.into_iter().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            names(candidates(&relays, Some("eu"), Some("eu"), relay_region)),
+            ["eu1", "eu2"]
+        );
+        assert_eq!(
+            names(candidates(&relays, Some("us"), Some("eu"), relay_region)),
+            ["eu1", "eu2", "us1"]
+        );
+        assert_eq!(
+            names(candidates(&relays, None, Some("us"), relay_region)),
+            ["us1"]
+        );
+        assert_eq!(
+            candidates(&relays, Some("asia"), None, relay_region).len(),
+            4
+        );
+        assert_eq!(candidates(&relays, None, None, relay_region).len(), 4);
+    }
+}
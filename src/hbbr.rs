@@ -1,6 +1,19 @@
-use clap::App;
+mod accounting;
+mod audit;
+mod batch;
 mod common;
+// hbbr only writes audit events, relay usage and accounting records
+#[allow(dead_code)]
+mod database;
+mod ip_list;
+mod metrics;
+mod migration;
+mod prefix_table;
+mod quota;
 mod relay_server;
+mod settings;
+#[cfg(target_os = "linux")]
+mod splice;
 use flexi_logger::*;
 use hbb_common::{config::RELAY_PORT, ResultType};
 use relay_server::*;
@@ -13,42 +26,30 @@ fn main() -> ResultType<()> {
         .write_mode(WriteMode::Async)
         .start()?;
     let args = format!(
-        "-b, --bind=[IP] 'Sets the IP address to bind to (default: all interfaces)'
+        "-c --config=[FILE] +takes_value 'Sets a custom config file'
+        -b, --bind=[IP] 'Sets the IP address to bind to (default: all interfaces)'
         -p, --port=[NUMBER(default={RELAY_PORT})] 'Sets the listening port'
         -k, --key=[KEY] 'Only allow the client with the same key'
         ",
     );
-    let matches = App::new("hbbr")
-        .version(version::VERSION)
-        .author("Purslane Ltd. <info@rustdesk.com>")
-        .about("RustDesk Relay Server")
-        .args_from_usage(&args)
-        .get_matches();
-    if let Ok(v) = ini::Ini::load_from_file(".env") {
-        if let Some(section) = v.section(None::<String>) {
-            section.iter().for_each(|(k, v)| common::set_arg(k, v));
-        }
-    }
+    let matches = common::init_args(&args, "hbbr", "RustDesk Relay Server")?;
     let mut port = RELAY_PORT;
     if let Some(v) = common::get_arg_opt("PORT") {
         let v: i32 = v.parse().unwrap_or_default();
         if v > 0 {
-            port = v + 1;
+            // a PORT shared with hbbs is its port, unless set for hbbr only
+            port = if common::arg_source("PORT").ends_with("[hbbr]") {
+                v
+            } else {
+                v + 1
+            };
         }
     }
-    let bind = matches
-        .value_of("bind")
-        .map(str::to_owned)
-        .unwrap_or_else(|| common::get_arg("BIND"));
-    let bind_addr = common::parse_bind_address(&bind)?;
-    let key = matches
-        .value_of("key")
-        .map(str::to_owned)
-        .unwrap_or_else(|| common::get_arg("KEY"));
+    let bind_addr = common::parse_bind_address(&common::get_arg("BIND"))?;
     start_with_bind(
         bind_addr,
         matches.value_of("port").unwrap_or(&port.to_string()),
-        &key,
+        &common::get_arg("KEY"),
     )?;
     Ok(())
 }
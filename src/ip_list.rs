@@ -0,0 +1,404 @@
+// IP ranges of hbbr's blacklist.txt and blocklist.txt, also used for the IP
+// entries of hbbs' denylist.txt and allowlist.txt.
+// Each line is `<ip|cidr> [<expiry>]`, the expiry an RFC 3339 time, e.g.
+// `10.0.0.0/8 2030-01-01T00:00:00Z`. Anything else after the first space, blank
+// lines and `#` comments are ignored. IPv4-mapped IPv6 addresses and ranges are
+// handled as IPv4. Overlapping ranges are allowed.
+// Console edits are written back with `save`, as `<ip|cidr> [<expiry>] # <time
+// of the edit> [<comment>]`, keeping the other lines.
+
+use crate::prefix_table::PrefixTable;
+use ipnetwork::{IpNetwork, Ipv4Network};
+use std::net::IpAddr;
+
+// -> expiry, unix time in seconds
+type Table = PrefixTable<Option<u64>>;
+
+#[inline]
+pub(crate) fn alive(expires: &Option<u64>, now: u64) -> bool {
+    match expires {
+        Some(t) => *t > now,
+        None => true,
+    }
+}
+
+/// `ip` or `cidr` as its network address, with IPv4-mapped IPv6 converted to
+/// IPv4.
+pub(crate) fn parse_net(s: &str) -> Option<IpNetwork> {
+    let net = s.parse::<IpNetwork>().ok()?;
+    if let IpNetwork::V6(v6) = net {
+        if let (Some(v4), true) = (v6.ip().to_ipv4_mapped(), v6.prefix() >= 96) {
+            let v4 = Ipv4Network::new(v4, v6.prefix() - 96).ok()?;
+            return Some(IpNetwork::V4(
+                Ipv4Network::new(v4.network(), v4.prefix()).ok()?,
+            ));
+        }
+    }
+    IpNetwork::new(net.network(), net.prefix()).ok()
+}
+
+/// `net` as written to the files, single addresses without prefix.
+pub(crate) fn format_net(net: IpNetwork) -> String {
+    if net.prefix() == if net.is_ipv4() { 32 } else { 128 } {
+        net.ip().to_string()
+    } else {
+        net.to_string()
+    }
+}
+
+/// Parses an RFC 3339 time to unix seconds.
+pub(crate) fn parse_time(s: &str) -> Option<u64> {
+    chrono::DateTime::parse_from_rfc3339(s)
+        .ok()
+        .and_then(|x| u64::try_from(x.timestamp()).ok())
+}
+
+pub(crate) fn format_time(t: u64) -> String {
+    chrono::DateTime::<chrono::Utc>::from(std::time::UNIX_EPOCH + std::time::Duration::from_secs(t))
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Parses a duration like `90`, `30m`, `12h` or `7d` to seconds.
+pub(crate) fn parse_duration(s: &str) -> Option<u64> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    n.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parses a duration from `now`, or an RFC 3339 time, to unix seconds.
+pub(crate) fn parse_expiry(s: &str, now: u64) -> Option<u64> {
+    parse_duration(s)
+        .and_then(|x| now.checked_add(x))
+        .or_else(|| parse_time(s))
+}
+
+/// Parses the arguments of the console commands adding entries, an optional
+/// duration or RFC 3339 time they expire after and a comment after `#`.
+pub(crate) fn parse_add_args<'a>(
+    args: impl Iterator<Item = &'a str>,
+    now: u64,
+) -> Result<(Option<u64>, String), String> {
+    let mut args = args.filter(|x| !x.is_empty());
+    let mut arg = args.next();
+    let mut expires = None;
+    if let Some(v) = arg.filter(|x| !x.starts_with('#')) {
+        match parse_expiry(v, now) {
+            Some(v) => expires = Some(v),
+            None => return Err(format!("invalid duration or expiry: {v}")),
+        }
+        arg = args.next();
+    }
+    let comment = arg.into_iter().chain(args).collect::<Vec<_>>().join(" ");
+    Ok((expires, comment.trim_start_matches('#').trim().to_owned()))
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct IpList {
+    v4: Table,
+    v6: Table,
+}
+
+impl IpList {
+    #[allow(dead_code)]
+    pub(crate) fn parse(content: &str) -> Self {
+        let mut list = Self::default();
+        for line in content.lines() {
+            let mut fds = line.split_whitespace();
+            let Some(net) = fds.next().filter(|x| !x.starts_with('#')) else {
+                continue;
+            };
+            match parse_net(net) {
+                Some(net) => list.insert(net, fds.next().and_then(parse_time)),
+                None => hbb_common::log::warn!("Invalid IP or CIDR: {}", net),
+            }
+        }
+        list
+    }
+
+    /// Adds `net`, or replaces its expiry.
+    pub(crate) fn insert(&mut self, net: IpNetwork, expires: Option<u64>) {
+        match net {
+            IpNetwork::V4(v4) => self
+                .v4
+                .insert(32, u32::from(v4.ip()) as _, v4.prefix(), expires),
+            IpNetwork::V6(v6) => self
+                .v6
+                .insert(128, u128::from(v6.ip()), v6.prefix(), expires),
+        }
+    }
+
+    /// Removes `net` as added, not the ranges it overlaps.
+    pub(crate) fn remove(&mut self, net: IpNetwork) -> bool {
+        match net {
+            IpNetwork::V4(v4) => self.v4.remove(32, u32::from(v4.ip()) as _, v4.prefix()),
+            IpNetwork::V6(v6) => self.v6.remove(128, u128::from(v6.ip()), v6.prefix()),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether a range not expired by `now` contains `ip`.
+    pub(crate) fn contains_at(&self, ip: IpAddr, now: u64) -> bool {
+        match ip {
+            IpAddr::V4(v4) => self.v4.matches(32, u32::from(v4) as _),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => self.v4.matches(32, u32::from(v4) as _),
+                None => self.v6.matches(128, u128::from(v6)),
+            },
+        }
+        .any(|x| alive(x, now))
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.contains_at(ip, crate::common::now())
+    }
+
+    /// Drops the ranges expired by `now`, returns how many are left.
+    pub(crate) fn remove_expired(&mut self, now: u64) -> usize {
+        self.v4.retain(|x| alive(x, now));
+        self.v6.retain(|x| alive(x, now));
+        self.len()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    /// The ranges with their expiry, sorted, single addresses without prefix.
+    pub(crate) fn entries(&self) -> Vec<(String, Option<u64>)> {
+        let mut res: Vec<_> = self
+            .v4
+            .iter()
+            .map(|((len, addr), expires)| {
+                let net = IpNetwork::V4(Ipv4Network::new((*addr as u32).into(), *len).unwrap());
+                (net, *expires)
+            })
+            .chain(self.v6.iter().map(|((len, addr), expires)| {
+                let net = IpNetwork::new(IpAddr::V6((*addr).into()), *len).unwrap();
+                (net, *expires)
+            }))
+            .collect();
+        res.sort_unstable_by_key(|x| x.0);
+        res.into_iter()
+            .map(|(net, expires)| (format_net(net), expires))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    /// One line per range in the file format.
+    pub(crate) fn to_lines(&self) -> String {
+        let mut res = String::new();
+        for (net, expires) in self.entries() {
+            res.push_str(&net);
+            if let Some(t) = expires {
+                res.push(' ');
+                res.push_str(&format_time(t));
+            }
+            res.push('\n');
+        }
+        res
+    }
+}
+
+/// A console edit of a list file, of entries as returned by the key function.
+#[derive(Clone)]
+pub(crate) enum Edit {
+    /// the entries with their expiry, and a comment
+    Add(Vec<String>, Option<u64>, String),
+    Remove(Vec<String>),
+    Clear,
+}
+
+/// The key of a range in the files, `None` if `s` is not one.
+pub(crate) fn net_key(s: &str) -> Option<String> {
+    parse_net(s).map(format_net)
+}
+
+/// Applies `edit` to the file `content` at `now`: drops the lines of the
+/// entries edited and of expired ones, appends the added ones, keeps everything
+/// else. `key` gives the entry of the first field of a line, if it is one.
+pub(crate) fn edit(
+    content: &str,
+    edit: &Edit,
+    now: u64,
+    key: fn(&str) -> Option<String>,
+) -> String {
+    let edited = |k: &String| match edit {
+        Edit::Add(keys, ..) | Edit::Remove(keys) => keys.contains(k),
+        Edit::Clear => true,
+    };
+    let mut res = String::new();
+    for line in content.lines() {
+        let mut fds = line.split_whitespace();
+        if let Some(k) = fds.next().filter(|x| !x.starts_with('#')).and_then(key) {
+            let expired = fds
+                .next()
+                .and_then(parse_time)
+                .map(|t| t <= now)
+                .unwrap_or(false);
+            if expired || edited(&k) {
+                continue;
+            }
+        }
+        res.push_str(line);
+        res.push('\n');
+    }
+    if let Edit::Add(keys, expires, comment) = edit {
+        for k in keys.iter() {
+            res.push_str(k);
+            if let Some(t) = expires {
+                res.push(' ');
+                res.push_str(&format_time(*t));
+            }
+            res.push_str(" # ");
+            res.push_str(&format_time(now));
+            if !comment.is_empty() {
+                res.push(' ');
+                res.push_str(comment);
+            }
+            res.push('\n');
+        }
+    }
+    res
+}
+
+/// Applies `edit` to `file`, replacing it atomically.
+pub(crate) fn save(file: &str, e: &Edit, key: fn(&str) -> Option<String>) -> std::io::Result<()> {
+    let content = match std::fs::read_to_string(file) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => "".to_owned(),
+        res => res?,
+    };
+    let tmp = format!("{file}.tmp");
+    std::fs::write(&tmp, edit(&content, e, crate::common::now(), key))?;
+    std::fs::rename(&tmp, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_ranges() {
+        let mut list = IpList::parse(
+            "# comment\n\
+             1.2.3.4 some note\n\
+             10.0.0.0/8\n\
+             192.168.1.0/24 2000-01-01T00:00:00Z\n\
+             172.16.0.0/12 2999-01-01T00:00:00+01:00\n\
+             ::ffff:100.64.0.0/106\n\
+             2001:db8::/32\n\
+             bad\n",
+        );
+        assert_eq!(list.len(), 6);
+        let has = |list: &IpList, ip: &str| list.contains(ip.parse().unwrap());
+        assert!(has(&list, "1.2.3.4"));
+        assert!(has(&list, "::ffff:1.2.3.4"));
+        assert!(!has(&list, "1.2.3.5"));
+        assert!(has(&list, "10.200.0.1"));
+        assert!(has(&list, "172.31.255.255"));
+        assert!(!has(&list, "192.168.1.1"));
+        assert!(has(&list, "100.100.0.1"));
+        assert!(has(&list, "2001:db8:1::1"));
+        assert!(!has(&list, "2001:db9::1"));
+        assert!(list.contains_at("172.16.0.1".parse().unwrap(), 0));
+        assert!(!list.contains_at("172.16.0.1".parse().unwrap(), 40_000_000_000));
+
+        assert_eq!(list.remove_expired(crate::common::now()), 5);
+        assert!(!list.remove(parse_net("10.1.0.0/16").unwrap()));
+        assert!(list.remove(parse_net("10.1.0.0/8").unwrap()));
+        assert!(!has(&list, "10.200.0.1"));
+        list.insert(parse_net("::ffff:1.2.3.4").unwrap(), Some(4_000_000_000));
+        assert_eq!(
+            list.to_lines(),
+            "1.2.3.4 2096-10-02T07:06:40Z\n\
+             100.64.0.0/10\n\
+             172.16.0.0/12 2998-12-31T23:00:00Z\n\
+             2001:db8::/32\n"
+        );
+        assert_eq!(IpList::parse(&list.to_lines()).to_lines(), list.to_lines());
+    }
+
+    #[test]
+    fn edits_files() {
+        let content = "# header\n\
+                       1.2.3.4 note\n\
+                       10.0.0.0/8 2000-01-01T00:00:00Z\n\
+                       2001:db8::/32\n";
+        let nets = [
+            net_key("1.2.3.0/24").unwrap(),
+            net_key("::ffff:5.6.7.8").unwrap(),
+        ];
+        let res = edit(
+            content,
+            &Edit::Add(nets.to_vec(), Some(4_000_000_000), "spam".to_owned()),
+            1_000_000_000,
+            net_key,
+        );
+        assert_eq!(
+            res,
+            "# header\n\
+             1.2.3.4 note\n\
+             2001:db8::/32\n\
+             1.2.3.0/24 2096-10-02T07:06:40Z # 2001-09-09T01:46:40Z spam\n\
+             5.6.7.8 2096-10-02T07:06:40Z # 2001-09-09T01:46:40Z spam\n"
+        );
+        assert_eq!(IpList::parse(&res).len(), 4);
+        let res = edit(
+            &res,
+            &Edit::Add(nets[1..].to_vec(), None, "".to_owned()),
+            1_000_000_000,
+            net_key,
+        );
+        assert!(res.ends_with("\n5.6.7.8 # 2001-09-09T01:46:40Z\n"));
+        let nets = [
+            net_key("1.2.3.4").unwrap(),
+            net_key("2001:db8::1/32").unwrap(),
+        ];
+        let res = edit(&res, &Edit::Remove(nets.to_vec()), 1_000_000_000, net_key);
+        assert_eq!(
+            res,
+            "# header\n\
+             1.2.3.0/24 2096-10-02T07:06:40Z # 2001-09-09T01:46:40Z spam\n\
+             5.6.7.8 # 2001-09-09T01:46:40Z\n"
+        );
+        assert_eq!(
+            edit(&res, &Edit::Clear, 1_000_000_000, net_key),
+            "# header\n"
+        );
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(1800));
+        assert_eq!(parse_duration("12h"), Some(43200));
+        assert_eq!(parse_duration("7d"), Some(604800));
+        assert_eq!(parse_duration("7w"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_expiry("1d", 100), Some(86500));
+        assert_eq!(
+            parse_expiry("2001-09-09T01:46:40Z", 100),
+            Some(1_000_000_000)
+        );
+        assert_eq!(parse_expiry("soon", 100), None);
+        let args = |s: &'static str| parse_add_args(s.split(' '), 100);
+        assert_eq!(args(""), Ok((None, "".to_owned())));
+        assert_eq!(args("1d  # a  b"), Ok((Some(86500), "a b".to_owned())));
+        assert_eq!(args("#a b"), Ok((None, "a b".to_owned())));
+        assert!(args("soon # a").is_err());
+    }
+}
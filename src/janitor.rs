@@ -0,0 +1,205 @@
+// Periodic pruning of the in-memory state of hbbs which clients can grow at
+// will: the recorded punch hole requests, the registration rate limiter, the IP
+// change tracker and the peer cache. Each has a TTL and a cap, re-read from the
+// settings on every sweep.
+
+use crate::common::get_arg;
+use crate::metrics::Counter;
+use crate::peer::*;
+use crate::rendezvous_server::{PunchReqEntry, PUNCH_REQS};
+use hbb_common::{
+    log,
+    tokio::{self, time::Duration},
+};
+use std::{collections::HashMap, hash::Hash, time::Instant};
+
+const INTERVAL: u64 = 60; // in seconds
+
+pub(crate) static EVICTED_PUNCH_REQS: Counter = Counter::new();
+pub(crate) static EVICTED_IP_BLOCKER: Counter = Counter::new();
+pub(crate) static EVICTED_IP_CHANGES: Counter = Counter::new();
+pub(crate) static EVICTED_PEERS: Counter = Counter::new();
+
+/// TTL in seconds and maximum number of entries of a map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Limit {
+    pub(crate) ttl: u64,
+    pub(crate) max: usize,
+}
+
+impl Limit {
+    fn get(prefix: &str, ttl: u64, max: usize) -> Self {
+        let arg = |name: &str| get_arg(&format!("{prefix}_{name}"));
+        Self {
+            ttl: arg("TTL").parse().unwrap_or(ttl),
+            max: arg("MAX").parse().unwrap_or(max),
+        }
+    }
+
+    #[inline]
+    fn expired(&self, t: Instant) -> bool {
+        t.elapsed().as_secs() > self.ttl
+    }
+}
+
+fn interval() -> u64 {
+    get_arg("JANITOR_INTERVAL")
+        .parse::<u64>()
+        .unwrap_or(INTERVAL)
+        .max(1)
+}
+
+fn peer_cache_limit() -> Limit {
+    Limit::get("PEER_CACHE", 3600, PEER_CACHE_MAX)
+}
+
+pub(crate) fn start(pm: PeerMap) {
+    log::info!("JANITOR_INTERVAL={}", interval());
+    pm.set_capacity(peer_cache_limit().max);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval())).await;
+            sweep(&pm).await;
+        }
+    });
+}
+
+/// Evicted entries of one sweep.
+#[derive(Debug, Default)]
+pub(crate) struct Swept {
+    pub(crate) punch_reqs: usize,
+    pub(crate) ip_blocker: usize,
+    pub(crate) ip_changes: usize,
+    pub(crate) peers: usize,
+}
+
+impl std::fmt::Display for Swept {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "evicted {} punch requests, {} ip blocker, {} ip changes, {} peers",
+            self.punch_reqs, self.ip_blocker, self.ip_changes, self.peers
+        )
+    }
+}
+
+pub(crate) async fn sweep(pm: &PeerMap) -> Swept {
+    let limit = Limit::get("PUNCH_REQS", DAY_SECONDS, 10_000);
+    let punch_reqs = prune_punch_reqs(&mut *PUNCH_REQS.lock().await, limit);
+    warn_full("PUNCH_REQS_MAX", punch_reqs, limit);
+    EVICTED_PUNCH_REQS.add(punch_reqs.0 as _);
+    // the rate limiter counts registrations per minute and IDs per day
+    let limit = Limit::get("IP_BLOCKER", DAY_SECONDS, 100_000);
+    let ip_blocker = prune(&mut *IP_BLOCKER.lock().await, limit, |(a, b)| a.1.max(b.1));
+    warn_full("IP_BLOCKER_MAX", ip_blocker, limit);
+    EVICTED_IP_BLOCKER.add(ip_blocker.0 as _);
+    let limit = Limit::get("IP_CHANGES", IP_CHANGE_DUR_X2, 100_000);
+    let ip_changes = prune(&mut *IP_CHANGES.lock().await, limit, |v| v.0);
+    warn_full("IP_CHANGES_MAX", ip_changes, limit);
+    EVICTED_IP_CHANGES.add(ip_changes.0 as _);
+    let limit = peer_cache_limit();
+    pm.set_capacity(limit.max);
+    // counted by `evict`, which also runs when the cache exceeds its capacity
+    let peers = pm.evict(limit).await;
+    warn_full("PEER_CACHE_MAX", peers, limit);
+    let res = Swept {
+        punch_reqs: punch_reqs.0,
+        ip_blocker: ip_blocker.0,
+        ip_changes: ip_changes.0,
+        peers: peers.0,
+    };
+    log::debug!("janitor {}", res);
+    res
+}
+
+fn warn_full(name: &str, (_, full): (usize, usize), limit: Limit) {
+    if full > 0 {
+        log::warn!(
+            "{}={} reached, {} entries evicted early",
+            name,
+            limit.max,
+            full
+        );
+    }
+}
+
+/// Drops the entries of `map` last updated more than `limit.ttl` seconds ago,
+/// then the least recently updated ones beyond `limit.max`. Returns how many
+/// in total and how many because of the cap.
+pub(crate) fn prune<K: Clone + Eq + Hash, V>(
+    map: &mut HashMap<K, V>,
+    limit: Limit,
+    updated: impl Fn(&V) -> Instant,
+) -> (usize, usize) {
+    let n = map.len();
+    map.retain(|_, v| !limit.expired(updated(v)));
+    let expired = n - map.len();
+    if map.len() <= limit.max {
+        return (expired, 0);
+    }
+    let mut keys: Vec<_> = map.iter().map(|(k, v)| (updated(v), k.clone())).collect();
+    keys.sort_unstable_by_key(|x| x.0);
+    let full = map.len() - limit.max;
+    for (_, k) in keys.into_iter().take(full) {
+        map.remove(&k);
+    }
+    (expired + full, full)
+}
+
+/// Like [`prune`], the entries are in the order they were recorded.
+pub(crate) fn prune_punch_reqs(reqs: &mut Vec<PunchReqEntry>, limit: Limit) -> (usize, usize) {
+    let expired = reqs.iter().take_while(|x| limit.expired(x.tm)).count();
+    let full = (reqs.len() - expired).saturating_sub(limit.max);
+    reqs.drain(..expired + full);
+    (expired + full, full)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn prunes_by_ttl_then_cap() {
+        let mut map: HashMap<_, _> = [
+            ("a", ago(100)),
+            ("b", ago(30)),
+            ("c", ago(20)),
+            ("d", ago(10)),
+        ]
+        .into_iter()
+        .collect();
+        let limit = Limit { ttl: 60, max: 2 };
+        assert_eq!(prune(&mut map, limit, |v| *v), (2, 1));
+        let mut keys: Vec<_> = map.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, ["c", "d"]);
+        assert_eq!(prune(&mut map, limit, |v| *v), (0, 0));
+
+        let entry = |secs, to_id: &str| PunchReqEntry {
+            tm: ago(secs),
+            from_ip: "192.0.2.1".to_owned(),
+            to_ip: "192.0.2.2".to_owned(),
+            to_id: to_id.to_owned(),
+        };
+        let mut reqs = vec![
+            entry(100, "a"),
+            entry(30, "b"),
+            entry(20, "c"),
+            entry(10, "d"),
+        ];
+        assert_eq!(prune_punch_reqs(&mut reqs, limit), (2, 1));
+        assert_eq!(
+            reqs.iter().map(|x| x.to_id.as_str()).collect::<Vec<_>>(),
+            ["c", "d"]
+        );
+        assert_eq!(
+            prune_punch_reqs(&mut reqs, Limit { ttl: 15, max: 0 }),
+            (2, 1)
+        );
+        assert!(reqs.is_empty());
+    }
+}
@@ -1,6 +1,18 @@
 mod rendezvous_server;
 pub use rendezvous_server::*;
 pub mod common;
+pub mod api;
+mod access;
+mod audit;
+mod batch;
 mod database;
+mod geo;
+mod ip_list;
+mod janitor;
+mod metrics;
+mod migration;
 mod peer;
+mod prefix_table;
+mod settings;
+mod sharded;
 mod version;
@@ -0,0 +1,126 @@
+// Prometheus text format exporter shared by hbbs and hbbr.
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use axum::{http::header, routing::get, Router};
+use hbb_common::{log, ResultType};
+use std::{
+    fmt::{Display, Write},
+    future::Future,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline]
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder(String);
+
+impl Encoder {
+    pub(crate) fn header(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+        self
+    }
+
+    pub(crate) fn sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) -> &mut Self {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (k, v)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+                let v = v.replace('\\', "\\\\").replace('"', "\\\"");
+                let _ = write!(self.0, "{k}=\"{v}\"");
+            }
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {value}");
+        self
+    }
+
+    pub(crate) fn counter(&mut self, name: &str, help: &str, value: u64) -> &mut Self {
+        self.header(name, "counter", help).sample(name, &[], value)
+    }
+
+    pub(crate) fn gauge(&mut self, name: &str, help: &str, value: impl Display) -> &mut Self {
+        self.header(name, "gauge", help).sample(name, &[], value)
+    }
+
+    pub(crate) fn finish(&mut self) -> String {
+        std::mem::take(&mut self.0)
+    }
+}
+
+pub(crate) async fn start<F, Fut>(bind_addr: Option<IpAddr>, port: u16, render: F) -> ResultType<()>
+where
+    F: Fn() -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = String> + Send + 'static,
+{
+    let app = Router::new().route(
+        "/metrics",
+        get(move || {
+            let render = render.clone();
+            async move {
+                (
+                    [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    render().await,
+                )
+            }
+        }),
+    );
+    let listener = crate::common::listen_tcp(bind_addr, port).await?;
+    log::info!("Listening on metrics {}", listener.local_addr()?);
+    axum::Server::from_tcp(listener.into_std()?)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_text_format() {
+        let counter = Counter::new();
+        counter.inc();
+        counter.add(2);
+        let mut out = Encoder::default();
+        out.counter("a_total", "A.", counter.get())
+            .header("b", "gauge", "B.")
+            .sample("b", &[("k", "v\"1"), ("x", "y")], 1.5);
+        assert_eq!(
+            out.finish(),
+            "# HELP a_total A.\n# TYPE a_total counter\na_total 3\n\
+             # HELP b B.\n# TYPE b gauge\nb{k=\"v\\\"1\",x=\"y\"} 1.5\n"
+        );
+    }
+}
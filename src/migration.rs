@@ -0,0 +1,356 @@
+// Versioned schema migrations of the peer database.
+// Applied versions are recorded in the `schema_version` table, pending ones are
+// applied in order on startup. Only append to MIGRATIONS, never edit a released
+// entry, otherwise existing databases and new ones end up with different schemas.
+
+use crate::common::get_arg;
+use async_trait::async_trait;
+use hbb_common::{bail, log, ResultType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dialect {
+    Sqlite,
+    Postgres,
+    MySql,
+}
+
+pub(crate) struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sqlite: &'static [&'static str],
+    pub postgres: &'static [&'static str],
+    pub mysql: &'static [&'static str],
+}
+
+impl Migration {
+    pub(crate) fn sql(&self, dialect: Dialect) -> &'static [&'static str] {
+        match dialect {
+            Dialect::Sqlite => self.sqlite,
+            Dialect::Postgres => self.postgres,
+            Dialect::MySql => self.mysql,
+        }
+    }
+}
+
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        // `if not exists` everywhere so databases created before the migrations
+        // existed are adopted as version 1
+        version: 1,
+        name: "create peer table",
+        sqlite: &[
+            "create table if not exists peer (
+                guid blob primary key not null,
+                id varchar(100) not null,
+                uuid blob not null,
+                pk blob not null,
+                created_at datetime not null default(current_timestamp),
+                user blob,
+                status tinyint,
+                note varchar(300),
+                info text not null
+            ) without rowid",
+            "create unique index if not exists index_peer_id on peer (id)",
+            "create index if not exists index_peer_user on peer (user)",
+            "create index if not exists index_peer_created_at on peer (created_at)",
+            "create index if not exists index_peer_status on peer (status)",
+        ],
+        postgres: &[
+            r#"create table if not exists peer (
+                guid bytea primary key not null,
+                id varchar(100) not null,
+                uuid bytea not null,
+                pk bytea not null,
+                created_at timestamp not null default current_timestamp,
+                "user" bytea,
+                status smallint,
+                note varchar(300),
+                info text not null
+            )"#,
+            "create unique index if not exists index_peer_id on peer (id)",
+            r#"create index if not exists index_peer_user on peer ("user")"#,
+            "create index if not exists index_peer_created_at on peer (created_at)",
+            "create index if not exists index_peer_status on peer (status)",
+        ],
+        mysql: &["create table if not exists peer (
+                guid binary(16) primary key not null,
+                id varchar(100) not null,
+                uuid blob not null,
+                pk blob not null,
+                created_at datetime not null default current_timestamp,
+                `user` blob,
+                status tinyint,
+                note varchar(300),
+                info text not null,
+                unique index index_peer_id (id),
+                index index_peer_user (`user`(16)),
+                index index_peer_created_at (created_at),
+                index index_peer_status (status)
+            )"],
+    },
+    Migration {
+        version: 2,
+        name: "peer last seen",
+        sqlite: &[
+            "alter table peer add column last_reg_time datetime",
+            "alter table peer add column last_ip varchar(100)",
+            "alter table peer add column pk_changed_at datetime",
+            "create index if not exists index_peer_last_reg_time on peer (last_reg_time)",
+        ],
+        postgres: &[
+            "alter table peer add column last_reg_time timestamp,
+                add column last_ip varchar(100),
+                add column pk_changed_at timestamp",
+            "create index if not exists index_peer_last_reg_time on peer (last_reg_time)",
+        ],
+        mysql: &["alter table peer add column last_reg_time datetime,
+                add column last_ip varchar(100),
+                add column pk_changed_at datetime,
+                add index index_peer_last_reg_time (last_reg_time)"],
+    },
+    Migration {
+        version: 3,
+        name: "audit log",
+        sqlite: &[
+            "create table if not exists audit_log (
+                seq integer primary key autoincrement,
+                time datetime not null,
+                kind varchar(20) not null,
+                source_ip varchar(100) not null,
+                target_id varchar(100) not null,
+                peer_ip varchar(100),
+                uuid varchar(100),
+                outcome varchar(100) not null,
+                duration_ms bigint,
+                bytes bigint
+            )",
+            "create index if not exists index_audit_log_time on audit_log (time)",
+            "create index if not exists index_audit_log_target_id on audit_log (target_id)",
+            "create index if not exists index_audit_log_source_ip on audit_log (source_ip)",
+        ],
+        postgres: &[
+            "create table if not exists audit_log (
+                seq bigserial primary key not null,
+                time timestamp not null,
+                kind varchar(20) not null,
+                source_ip varchar(100) not null,
+                target_id varchar(100) not null,
+                peer_ip varchar(100),
+                uuid varchar(100),
+                outcome varchar(100) not null,
+                duration_ms bigint,
+                bytes bigint
+            )",
+            "create index if not exists index_audit_log_time on audit_log (time)",
+            "create index if not exists index_audit_log_target_id on audit_log (target_id)",
+            "create index if not exists index_audit_log_source_ip on audit_log (source_ip)",
+        ],
+        mysql: &["create table if not exists audit_log (
+                seq bigint primary key auto_increment not null,
+                time datetime not null,
+                kind varchar(20) not null,
+                source_ip varchar(100) not null,
+                target_id varchar(100) not null,
+                peer_ip varchar(100),
+                uuid varchar(100),
+                outcome varchar(100) not null,
+                duration_ms bigint,
+                bytes bigint,
+                index index_audit_log_time (time),
+                index index_audit_log_target_id (target_id),
+                index index_audit_log_source_ip (source_ip)
+            )"],
+    },
+    Migration {
+        version: 4,
+        name: "relay usage",
+        sqlite: &["create table if not exists relay_usage (
+                subject varchar(100) not null,
+                period varchar(10) not null,
+                bytes bigint not null,
+                primary key (subject, period)
+            ) without rowid"],
+        postgres: &["create table if not exists relay_usage (
+                subject varchar(100) not null,
+                period varchar(10) not null,
+                bytes bigint not null,
+                primary key (subject, period)
+            )"],
+        mysql: &["create table if not exists relay_usage (
+                subject varchar(100) not null,
+                period varchar(10) not null,
+                bytes bigint not null,
+                primary key (subject, period)
+            )"],
+    },
+    Migration {
+        version: 5,
+        name: "relay session",
+        sqlite: &[
+            "create table if not exists relay_session (
+                seq integer primary key autoincrement,
+                start_time datetime not null,
+                end_time datetime not null,
+                uuid varchar(100) not null,
+                target_id varchar(100) not null,
+                addr varchar(100) not null,
+                peer_addr varchar(100) not null,
+                bytes_to_peer bigint not null,
+                bytes_from_peer bigint not null,
+                peak_speed bigint not null,
+                downgraded boolean not null,
+                blacklisted boolean not null,
+                throttled boolean not null
+            )",
+            "create index if not exists index_relay_session_end_time on relay_session (end_time)",
+        ],
+        postgres: &[
+            "create table if not exists relay_session (
+                seq bigserial primary key not null,
+                start_time timestamp not null,
+                end_time timestamp not null,
+                uuid varchar(100) not null,
+                target_id varchar(100) not null,
+                addr varchar(100) not null,
+                peer_addr varchar(100) not null,
+                bytes_to_peer bigint not null,
+                bytes_from_peer bigint not null,
+                peak_speed bigint not null,
+                downgraded boolean not null,
+                blacklisted boolean not null,
+                throttled boolean not null
+            )",
+            "create index if not exists index_relay_session_end_time on relay_session (end_time)",
+        ],
+        mysql: &["create table if not exists relay_session (
+                seq bigint primary key auto_increment not null,
+                start_time datetime not null,
+                end_time datetime not null,
+                uuid varchar(100) not null,
+                target_id varchar(100) not null,
+                addr varchar(100) not null,
+                peer_addr varchar(100) not null,
+                bytes_to_peer bigint not null,
+                bytes_from_peer bigint not null,
+                peak_speed bigint not null,
+                downgraded boolean not null,
+                blacklisted boolean not null,
+                throttled boolean not null,
+                index index_relay_session_end_time (end_time)
+            )"],
+    },
+];
+
+pub(crate) fn version_table_sql(dialect: Dialect) -> &'static str {
+    match dialect {
+        Dialect::Sqlite => {
+            "create table if not exists schema_version (
+                version integer primary key not null,
+                name varchar(100) not null,
+                applied_at datetime not null default(current_timestamp)
+            )"
+        }
+        Dialect::Postgres => {
+            "create table if not exists schema_version (
+                version bigint primary key not null,
+                name varchar(100) not null,
+                applied_at timestamp not null default current_timestamp
+            )"
+        }
+        Dialect::MySql => {
+            "create table if not exists schema_version (
+                version bigint primary key not null,
+                name varchar(100) not null,
+                applied_at datetime not null default current_timestamp
+            )"
+        }
+    }
+}
+
+/// Implemented by every storage backend.
+#[async_trait]
+pub(crate) trait Schema: Send + Sync {
+    fn dialect(&self) -> Dialect;
+
+    /// Highest applied version, 0 for a database without `schema_version`. Any
+    /// other error is returned, a database which cannot be read is not empty.
+    async fn schema_version(&self) -> ResultType<i64>;
+
+    /// Runs `version_table_sql`, the statements of `migration` and records its
+    /// version, in one transaction where the database supports transactional DDL.
+    async fn apply(&self, migration: &Migration) -> ResultType<()>;
+}
+
+#[inline]
+pub(crate) fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+fn pending(current: i64) -> ResultType<&'static [Migration]> {
+    let latest = latest_version();
+    if current > latest {
+        bail!(
+            "database schema version {} is newer than {} supported by this build, please upgrade hbbs and hbbr",
+            current,
+            latest
+        );
+    }
+    let n = MIGRATIONS
+        .iter()
+        .position(|m| m.version > current)
+        .unwrap_or(MIGRATIONS.len());
+    Ok(&MIGRATIONS[n..])
+}
+
+pub(crate) async fn run(db: &impl Schema) -> ResultType<()> {
+    let current = db.schema_version().await?;
+    let pending = pending(current)?;
+    if pending.is_empty() {
+        log::info!("database schema version {}", current);
+        return Ok(());
+    }
+    if get_arg("DB_MIGRATE_DRY_RUN").to_uppercase() == "Y" {
+        for m in pending {
+            log::info!("pending migration {}: {}", m.version, m.name);
+            for sql in m.sql(db.dialect()) {
+                log::info!("{}", sql);
+            }
+        }
+        bail!(
+            "DB_MIGRATE_DRY_RUN=Y, schema version {}, {} migration(s) not applied",
+            current,
+            pending.len()
+        );
+    }
+    for m in pending {
+        log::info!("applying migration {}: {}", m.version, m.name);
+        if let Err(err) = db.apply(m).await {
+            // hbbs and hbbr may share the database and migrate it concurrently
+            let current = db.schema_version().await?;
+            if current < m.version {
+                return Err(err);
+            }
+            // by a newer binary meanwhile
+            self::pending(current)?;
+            log::info!("migration {} already applied", m.version);
+        }
+    }
+    log::info!("database schema version {}", latest_version());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_migrations() {
+        assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+        assert!(MIGRATIONS
+            .iter()
+            .all(|m| !m.sqlite.is_empty() && !m.postgres.is_empty() && !m.mysql.is_empty()));
+        assert_eq!(pending(0).unwrap().len(), MIGRATIONS.len());
+        assert!(pending(latest_version()).unwrap().is_empty());
+        assert!(pending(latest_version() + 1).is_err());
+    }
+}
@@ -1,14 +1,31 @@
 use crate::common::*;
 use crate::database;
+use crate::janitor::{self, Limit};
+use crate::metrics::Counter;
+use crate::sharded::Sharded;
 use hbb_common::{
     bytes::Bytes,
     log,
     rendezvous_proto::*,
-    tokio::sync::{Mutex, RwLock},
+    tokio::{
+        self,
+        sync::{Mutex, RwLock},
+        time::{interval, Duration},
+    },
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, collections::HashSet, net::SocketAddr, sync::Arc, time::Instant};
+use sodiumoxide::crypto::sign;
+use std::{
+    collections::hash_map::Entry,
+    collections::HashMap,
+    collections::HashSet,
+    net::IpAddr,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    sync::Arc,
+    time::Instant,
+};
 
 type IpBlockMap = HashMap<String, ((u32, Instant), (HashSet<String>, Instant))>;
 type UserStatusMap = HashMap<Vec<u8>, Arc<(Option<Vec<u8>>, bool)>>;
@@ -17,11 +34,19 @@ lazy_static::lazy_static! {
     pub(crate) static ref IP_BLOCKER: Mutex<IpBlockMap> = Default::default();
     pub(crate) static ref USER_STATUS: RwLock<UserStatusMap> = Default::default();
     pub(crate) static ref IP_CHANGES: Mutex<IpChangesMap> = Default::default();
+    // origin of `Cached::used`
+    static ref START: Instant = Instant::now();
 }
 pub const IP_CHANGE_DUR: u64 = 180;
 pub const IP_CHANGE_DUR_X2: u64 = IP_CHANGE_DUR * 2;
 pub const DAY_SECONDS: u64 = 3600 * 24;
 pub const IP_BLOCK_DUR: u64 = 60;
+pub(crate) const REG_TIMEOUT: i64 = 30_000;
+const LAST_SEEN_FLUSH_INTERVAL: u64 = 60;
+const PEER_REFRESH_INTERVAL: u64 = 60;
+pub(crate) const PEER_CACHE_MAX: usize = 1_000_000;
+const MAX_ID_LEN: usize = 100; // peer.id is varchar(100)
+pub(crate) const MAX_NOTE_LEN: usize = 300; // peer.note is varchar(300)
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub(crate) struct PeerInfo {
@@ -37,10 +62,17 @@ pub(crate) struct Peer {
     pub(crate) pk: Bytes,
     // pub(crate) user: Option<Vec<u8>>,
     pub(crate) info: PeerInfo,
-    // pub(crate) disabled: bool,
+    pub(crate) disabled: bool,
     pub(crate) reg_pk: (u32, Instant), // how often register_pk
 }
 
+impl Peer {
+    #[inline]
+    pub(crate) fn is_online(&self) -> bool {
+        (self.last_reg_time.elapsed().as_millis() as i64) < REG_TIMEOUT
+    }
+}
+
 impl Default for Peer {
     fn default() -> Self {
         Self {
@@ -51,7 +83,7 @@ impl Default for Peer {
             pk: Bytes::new(),
             info: Default::default(),
             // user: None,
-            // disabled: false,
+            disabled: false,
             reg_pk: (0, get_expired_time()),
         }
     }
@@ -59,36 +91,215 @@ impl Default for Peer {
 
 pub(crate) type LockPeer = Arc<RwLock<Peer>>;
 
+pub(crate) static CACHE_HITS: Counter = Counter::new();
+pub(crate) static CACHE_MISSES: Counter = Counter::new();
+
+/// A peer in the cache of `PeerMap`, when it was last used and when it was
+/// last read from db, in ms since `START`.
+struct Cached {
+    peer: LockPeer,
+    used: AtomicU64,
+    loaded: AtomicU64,
+}
+
+impl Cached {
+    fn new(peer: LockPeer) -> Self {
+        let now = START.elapsed().as_millis() as u64;
+        Self {
+            peer,
+            used: AtomicU64::new(now),
+            loaded: AtomicU64::new(now),
+        }
+    }
+
+    #[inline]
+    fn touch(&self) -> LockPeer {
+        self.used
+            .store(START.elapsed().as_millis() as _, Ordering::Relaxed);
+        self.peer.clone()
+    }
+
+    #[inline]
+    fn used(&self) -> Instant {
+        *START + Duration::from_millis(self.used.load(Ordering::Relaxed))
+    }
+
+    /// Whether it was read from db more than `refresh` ms ago, marking it as
+    /// read now so that only one lookup reads it again.
+    #[inline]
+    fn expire(&self, refresh: u64) -> bool {
+        if refresh == 0 {
+            return false;
+        }
+        let now = START.elapsed().as_millis() as u64;
+        let loaded = self.loaded.load(Ordering::Relaxed);
+        now.saturating_sub(loaded) > refresh
+            && self
+                .loaded
+                .compare_exchange(loaded, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+}
+
+/// An ID pre-provisioned for PRIVATE_MODE, see [`PeerMap::provision`].
+pub(crate) struct Provision {
+    pub(crate) id: String,
+    /// None keeps the stored key, empty lets the next registration enroll its key
+    pub(crate) pk: Option<Vec<u8>>,
+    pub(crate) note: Option<String>,
+}
+
+impl Provision {
+    /// `pk` is base64, empty to reset it.
+    pub(crate) fn new(id: &str, pk: Option<&str>, note: Option<&str>) -> Result<Self, String> {
+        if id.len() < 6
+            || id.len() > MAX_ID_LEN
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("invalid ID: {id}"));
+        }
+        let pk = match pk.map(base64::decode) {
+            Some(Ok(pk)) if pk.is_empty() || pk.len() == sign::PUBLICKEYBYTES => Some(pk),
+            Some(_) => return Err(format!("invalid public key of {id}")),
+            None => None,
+        };
+        if matches!(note, Some(x) if x.chars().count() > MAX_NOTE_LEN) {
+            return Err(format!(
+                "note of {id} longer than {MAX_NOTE_LEN} characters"
+            ));
+        }
+        Ok(Self {
+            id: id.to_owned(),
+            pk,
+            note: note.map(str::to_owned),
+        })
+    }
+
+    /// Parses a line `<id>[,<pk>[,<note>]]` of an import file, `-` as pk resets
+    /// it. Blank lines, `#` comments and an `id,...` header are None.
+    pub(crate) fn parse_line(line: &str) -> Option<Result<Self, String>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut fds = line.splitn(3, ',').map(str::trim);
+        let id = fds.next().unwrap_or_default();
+        if id.eq_ignore_ascii_case("id") {
+            return None;
+        }
+        let pk = fds
+            .next()
+            .filter(|x| !x.is_empty())
+            .map(|x| if x == "-" { "" } else { x });
+        let note = fds.next().filter(|x| !x.is_empty());
+        Some(Self::new(id, pk, note))
+    }
+}
+
+/// Outcome of [`PeerMap::import`].
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct ImportSummary {
+    pub(crate) added: usize,
+    pub(crate) existing: usize,
+    pub(crate) errors: Vec<String>,
+}
+
+// std locks, never held across an await
+type Shard = std::sync::RwLock<HashMap<String, Cached>>;
+type LastSeenShard = std::sync::Mutex<HashMap<String, database::LastSeen>>;
+
 #[derive(Clone)]
 pub(crate) struct PeerMap {
-    map: Arc<RwLock<HashMap<String, LockPeer>>>,
+    map: Arc<Sharded<Shard>>,
+    // entries of `map`
+    len: Arc<AtomicUsize>,
+    // PEER_CACHE_MAX, exceeding it evicts the least recently used offline peers
+    capacity: Arc<AtomicUsize>,
+    // PEER_REFRESH_INTERVAL in ms, 0 if cached peers are never read again
+    refresh: u64,
+    evicting: Arc<AtomicBool>,
     pub(crate) db: database::Database,
+    // registrations not yet written to db, flushed every LAST_SEEN_FLUSH_INTERVAL
+    last_seen: Arc<Sharded<LastSeenShard>>,
+    // held for reading by db writes, for writing by `shutdown`
+    writing: Arc<RwLock<()>>,
 }
 
 impl PeerMap {
     pub(crate) async fn new() -> ResultType<Self> {
-        let db = get_arg_opt("DB_URL").unwrap_or_else(|| {
-            let mut db = "db_v2.sqlite3".to_owned();
-            #[cfg(all(windows, not(debug_assertions)))]
-            {
-                if let Some(path) = hbb_common::config::Config::icon_path().parent() {
-                    db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
-                }
-            }
-            #[cfg(not(windows))]
-            {
-                db = format!("./{db}");
-            }
-            db
-        });
-        log::info!("DB_URL={}", db);
+        let db = database::url();
+        log::info!("DB_URL={}", database::redact_url(&db));
+        let refresh = get_arg("peer-refresh-interval")
+            .parse::<u64>()
+            .unwrap_or(PEER_REFRESH_INTERVAL);
+        log::info!("PEER_REFRESH_INTERVAL={}", refresh);
         let pm = Self {
             map: Default::default(),
+            len: Default::default(),
+            capacity: Arc::new(AtomicUsize::new(PEER_CACHE_MAX)),
+            refresh: refresh.saturating_mul(1000),
+            evicting: Default::default(),
             db: database::Database::new(&db).await?,
+            last_seen: Default::default(),
+            writing: Default::default(),
         };
+        let secs = get_arg("last-seen-flush-interval")
+            .parse::<u64>()
+            .unwrap_or(LAST_SEEN_FLUSH_INTERVAL)
+            .max(1);
+        log::info!("LAST_SEEN_FLUSH_INTERVAL={}", secs);
+        let cloned = pm.clone();
+        tokio::spawn(async move {
+            let mut timer = interval(Duration::from_secs(secs));
+            loop {
+                timer.tick().await;
+                cloned.flush_last_seen().await;
+            }
+        });
         Ok(pm)
     }
 
+    /// Records a registration of `id`, written to db by `flush_last_seen`.
+    #[inline]
+    pub(crate) async fn touch(&self, id: &str, ip: IpAddr) {
+        self.last_seen.get(id).lock().unwrap().insert(
+            id.to_owned(),
+            database::LastSeen {
+                time: chrono::Utc::now().naive_utc(),
+                ip: ip.to_string(),
+            },
+        );
+    }
+
+    pub(crate) async fn flush_last_seen(&self) {
+        let seen: Vec<_> = self
+            .last_seen
+            .iter()
+            .flat_map(|x| std::mem::take(&mut *x.lock().unwrap()))
+            .collect();
+        if seen.is_empty() {
+            return;
+        }
+        if let Err(err) = self.db.update_last_seen(&seen).await {
+            log::error!("db.update_last_seen failed: {}", err);
+            // retry next time, unless a newer registration came in meanwhile
+            for (id, v) in seen {
+                let mut lock = self.last_seen.get(&id).lock().unwrap();
+                lock.entry(id).or_insert(v);
+            }
+        } else {
+            log::debug!("{} last seen flushed", seen.len());
+        }
+    }
+
+    /// Waits for the db writes in flight and flushes `last_seen`.
+    pub(crate) async fn shutdown(&self) {
+        let _writing = self.writing.write().await;
+        self.flush_last_seen().await;
+    }
+
     #[inline]
     pub(crate) async fn update_pk(
         &mut self,
@@ -100,6 +311,7 @@ impl PeerMap {
         ip: String,
     ) -> register_pk_response::Result {
         log::info!("update_pk {} {:?} {:?} {:?}", id, addr, uuid, pk);
+        let _writing = self.writing.read().await;
         let (info_str, guid) = {
             let mut w = peer.write().await;
             w.socket_addr = addr;
@@ -107,6 +319,7 @@ impl PeerMap {
             w.pk = pk.clone();
             w.last_reg_time = Instant::now();
             w.info.ip = ip;
+            self.touch(&id, addr.ip()).await;
             (
                 serde_json::to_string(&w.info).unwrap_or_default(),
                 w.guid.clone(),
@@ -134,47 +347,400 @@ impl PeerMap {
 
     #[inline]
     pub(crate) async fn get(&self, id: &str) -> Option<LockPeer> {
-        let p = self.map.read().await.get(id).cloned();
-        if p.is_some() {
-            return p;
-        } else if let Ok(Some(v)) = self.db.get_peer(id).await {
+        let cached = self.map.get(id).read().unwrap().get(id).map(|x| {
+            let expired = x.expire(self.refresh);
+            (x.touch(), expired)
+        });
+        if let Some((p, expired)) = cached {
+            CACHE_HITS.inc();
+            if expired {
+                return self.refresh(id, p).await;
+            }
+            return Some(p);
+        }
+        CACHE_MISSES.inc();
+        if let Ok(Some(v)) = self.db.get_peer(id).await {
             let peer = Peer {
                 guid: v.guid,
                 uuid: v.uuid.into(),
                 pk: v.pk.into(),
                 // user: v.user,
                 info: serde_json::from_str::<PeerInfo>(&v.info).unwrap_or_default(),
-                // disabled: v.status == Some(0),
+                disabled: v.status == Some(database::STATUS_DISABLED),
                 ..Default::default()
             };
-            let peer = Arc::new(RwLock::new(peer));
-            self.map.write().await.insert(id.to_owned(), peer.clone());
-            return Some(peer);
+            return Some(self.insert(id, peer).await);
         }
         None
     }
 
+    /// Reads the key and status of cached `peer` from db again, to see the
+    /// changes made through other instances sharing it. None if it has been
+    /// deleted meanwhile, then it is also dropped from the cache.
+    async fn refresh(&self, id: &str, peer: LockPeer) -> Option<LockPeer> {
+        let v = match self.db.get_peer(id).await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("db.get_peer failed: {}", err);
+                return Some(peer);
+            }
+        };
+        match v {
+            Some(v) => {
+                let mut w = peer.write().await;
+                if w.guid != v.guid {
+                    // registered through another instance, or deleted and
+                    // registered again as a new peer
+                    w.uuid = v.uuid.into();
+                }
+                w.guid = v.guid;
+                w.pk = v.pk.into();
+                w.disabled = v.status == Some(database::STATUS_DISABLED);
+            }
+            // not registered yet
+            None if peer.read().await.guid.is_empty() => {}
+            None => {
+                log::info!("{} deleted by another instance", id);
+                self.last_seen.get(id).lock().unwrap().remove(id);
+                if self.map.get(id).write().unwrap().remove(id).is_some() {
+                    self.len.fetch_sub(1, Ordering::Relaxed);
+                }
+                return None;
+            }
+        }
+        Some(peer)
+    }
+
+    /// Caches `peer` unless `id` got cached meanwhile, returns the cached one.
+    async fn insert(&self, id: &str, peer: Peer) -> LockPeer {
+        let mut len = self.len.load(Ordering::Relaxed);
+        let peer = match self.map.get(id).write().unwrap().entry(id.to_owned()) {
+            Entry::Occupied(x) => x.get().touch(),
+            Entry::Vacant(x) => {
+                len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+                x.insert(Cached::new(Arc::new(RwLock::new(peer)))).touch()
+            }
+        };
+        let max = self.capacity.load(Ordering::Relaxed);
+        if len > max && !self.evicting.swap(true, Ordering::SeqCst) {
+            let pm = self.clone();
+            tokio::spawn(async move {
+                // to 90% so that the next insert does not evict again
+                let limit = Limit {
+                    ttl: u64::MAX,
+                    max: max - max / 10,
+                };
+                let (n, _) = pm.evict(limit).await;
+                log::warn!("PEER_CACHE_MAX={} reached, {} peers evicted", max, n);
+                // otherwise mostly online, retried by the next `set_capacity`
+                if pm.len.load(Ordering::Relaxed) <= max {
+                    pm.evicting.store(false, Ordering::SeqCst);
+                }
+            });
+        }
+        peer
+    }
+
+    /// Sets PEER_CACHE_MAX, called by the janitor on every sweep.
+    #[inline]
+    pub(crate) fn set_capacity(&self, max: usize) {
+        self.capacity.store(max, Ordering::Relaxed);
+        self.evicting.store(false, Ordering::SeqCst);
+    }
+
+    /// Disables or re-enables `id`, false if it is not in db.
+    pub(crate) async fn set_disabled(&self, id: &str, disabled: bool) -> ResultType<bool> {
+        let status = if disabled {
+            database::STATUS_DISABLED
+        } else {
+            database::STATUS_ENABLED
+        };
+        let _writing = self.writing.read().await;
+        if !self.db.set_status(id, status).await? {
+            return Ok(false);
+        }
+        if let Some(peer) = self.get_in_memory(id).await {
+            peer.write().await.disabled = disabled;
+        }
+        log::info!("{} {}", id, if disabled { "disabled" } else { "enabled" });
+        Ok(true)
+    }
+
+    /// Removes `id` from db and memory, a device still running registers
+    /// again as a new peer, disable it to keep it out.
+    pub(crate) async fn delete(&self, id: &str) -> ResultType<bool> {
+        let _writing = self.writing.read().await;
+        self.last_seen.get(id).lock().unwrap().remove(id);
+        let in_memory = self.map.get(id).write().unwrap().remove(id).is_some();
+        if in_memory {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        let in_db = self.db.delete_peer(id).await?;
+        log::info!("{} deleted", id);
+        Ok(in_memory || in_db)
+    }
+
+    /// Whether `id` may register with `pk` in PRIVATE_MODE: it is in db with
+    /// this key, or without key yet.
+    pub(crate) async fn is_provisioned(&self, id: &str, pk: &[u8]) -> bool {
+        match self.get(id).await {
+            Some(peer) => {
+                let peer = peer.read().await;
+                !peer.guid.is_empty() && (peer.pk.is_empty() || peer.pk == pk)
+            }
+            None => false,
+        }
+    }
+
+    /// Adds `p.id` to db if missing and sets its key and note if given,
+    /// returns whether it was added.
+    pub(crate) async fn provision(&self, p: &Provision) -> ResultType<bool> {
+        let _writing = self.writing.read().await;
+        let (guid, added) = match self.db.get_peer(&p.id).await? {
+            Some(v) => {
+                if let Some(pk) = &p.pk {
+                    self.db.update_pk(&v.guid, &p.id, pk, &v.info).await?;
+                }
+                (v.guid, false)
+            }
+            None => {
+                let pk = p.pk.as_deref().unwrap_or_default();
+                (self.db.insert_peer(&p.id, &[], pk, "{}").await?, true)
+            }
+        };
+        if let Some(note) = &p.note {
+            self.db.set_note(&p.id, note).await?;
+        }
+        if let Some(peer) = self.get_in_memory(&p.id).await {
+            let mut w = peer.write().await;
+            w.guid = guid;
+            if let Some(pk) = &p.pk {
+                w.pk = pk.clone().into();
+            }
+        }
+        log::info!("{} provisioned", p.id);
+        Ok(added)
+    }
+
+    pub(crate) async fn import(
+        &self,
+        items: impl IntoIterator<Item = Result<Provision, String>>,
+    ) -> ImportSummary {
+        let mut res = ImportSummary::default();
+        for item in items {
+            match item {
+                Ok(p) => match self.provision(&p).await {
+                    Ok(true) => res.added += 1,
+                    Ok(false) => res.existing += 1,
+                    Err(err) => res.errors.push(format!("{}: {}", p.id, err)),
+                },
+                Err(err) => res.errors.push(err),
+            }
+        }
+        res
+    }
+
     #[inline]
     pub(crate) async fn get_or(&self, id: &str) -> LockPeer {
         if let Some(p) = self.get(id).await {
             return p;
         }
-        let mut w = self.map.write().await;
-        if let Some(p) = w.get(id) {
-            return p.clone();
-        }
-        let tmp = LockPeer::default();
-        w.insert(id.to_owned(), tmp.clone());
-        tmp
+        self.insert(id, Peer::default()).await
     }
 
     #[inline]
     pub(crate) async fn get_in_memory(&self, id: &str) -> Option<LockPeer> {
-        self.map.read().await.get(id).cloned()
+        self.map.get(id).read().unwrap().get(id).map(Cached::touch)
+    }
+
+    pub(crate) async fn list_in_memory(&self) -> Vec<(String, LockPeer)> {
+        let mut res = Vec::with_capacity(self.len());
+        for shard in self.map.iter() {
+            let shard = shard.read().unwrap();
+            res.extend(shard.iter().map(|(id, x)| (id.clone(), x.peer.clone())));
+        }
+        res
     }
 
     #[inline]
     pub(crate) async fn is_in_memory(&self, id: &str) -> bool {
-        self.map.read().await.contains_key(id)
+        self.map.get(id).read().unwrap().contains_key(id)
+    }
+
+    /// Number of cached peers.
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Drops the offline peers unused for more than `limit.ttl` seconds from
+    /// the cache, then the least recently used ones beyond `limit.max`. They are
+    /// reloaded from db on the next lookup, online peers and peers still
+    /// referenced elsewhere are never dropped. Returns how many in total and how
+    /// many because of the cap.
+    pub(crate) async fn evict(&self, limit: Limit) -> (usize, usize) {
+        let n = self.len();
+        let mut idle = Vec::new();
+        for shard in self.map.iter() {
+            for (id, x) in shard.read().unwrap().iter() {
+                if Arc::strong_count(&x.peer) > 1 {
+                    continue;
+                }
+                let Ok(p) = x.peer.try_read() else {
+                    continue;
+                };
+                if !p.is_online() {
+                    let used = x.used().max(p.last_reg_time).max(p.reg_pk.1);
+                    idle.push((used, id.clone()));
+                }
+            }
+        }
+        idle.sort_unstable_by_key(|x| x.0);
+        let expired = idle
+            .iter()
+            .take_while(|x| x.0.elapsed().as_secs() > limit.ttl)
+            .count();
+        let k = expired.max(n.saturating_sub(limit.max)).min(idle.len());
+        if k == 0 {
+            return (0, 0);
+        }
+        let mut removed = 0;
+        for (_, id) in idle.into_iter().take(k) {
+            let mut map = self.map.get(&id).write().unwrap();
+            // used again meanwhile, no new references while the shard is locked
+            let keep = match map.get(&id) {
+                Some(x) if Arc::strong_count(&x.peer) == 1 => match x.peer.try_read() {
+                    Ok(p) => p.is_online(),
+                    Err(_) => true,
+                },
+                Some(_) => true,
+                None => continue,
+            };
+            if !keep {
+                map.remove(&id);
+                removed += 1;
+            }
+        }
+        self.len.fetch_sub(removed, Ordering::Relaxed);
+        janitor::EVICTED_PEERS.add(removed as _);
+        (removed, removed.saturating_sub(expired))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_provision_lines() {
+        let pk = base64::encode([7u8; 32]);
+        let p = Provision::parse_line(&format!(" acme-01 , {pk} , front desk, 2 ")).unwrap();
+        let p = p.unwrap();
+        assert_eq!(p.id, "acme-01");
+        assert_eq!(p.pk, Some(vec![7u8; 32]));
+        assert_eq!(p.note.as_deref(), Some("front desk, 2"));
+        let p = Provision::parse_line("123456789").unwrap().unwrap();
+        assert_eq!((p.pk, p.note), (None, None));
+        let p = Provision::parse_line("123456789,-").unwrap().unwrap();
+        assert_eq!(p.pk, Some(Vec::new()));
+        assert!(Provision::parse_line("# comment").is_none());
+        assert!(Provision::parse_line("  ").is_none());
+        assert!(Provision::parse_line("id,pk,note").is_none());
+        assert!(Provision::parse_line("12345").unwrap().is_err());
+        assert!(Provision::parse_line("acme 01").unwrap().is_err());
+        assert!(Provision::parse_line("acme-01,AAAA").unwrap().is_err());
+        assert!(Provision::new("acme-01", None, Some(&"x".repeat(301))).is_err());
+    }
+
+    async fn peer_map(path: &str, refresh: u64) -> PeerMap {
+        PeerMap {
+            map: Default::default(),
+            len: Default::default(),
+            capacity: Arc::new(AtomicUsize::new(PEER_CACHE_MAX)),
+            refresh,
+            evicting: Default::default(),
+            db: database::Database::new(path).await.unwrap(),
+            last_seen: Default::default(),
+            writing: Default::default(),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        evict();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn evict() {
+        let path =
+            std::env::temp_dir().join(format!("hbbs-peer-cache-{}.sqlite3", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::remove_file(path).ok();
+        let pm = peer_map(path, 0).await;
+        for id in ["peer-1", "peer-2", "peer-3", "peer-4"] {
+            pm.get_or(id).await;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        pm.get_or("peer-3").await.write().await.last_reg_time = Instant::now();
+        assert_eq!(pm.len(), 4);
+        let _held = pm.get_in_memory("peer-4").await.unwrap();
+        let misses = CACHE_MISSES.get();
+        pm.get("peer-1").await.unwrap();
+        assert_eq!(CACHE_MISSES.get(), misses);
+        // peer-3 is online, peer-4 in use, peer-2 the least recently used
+        let limit = Limit {
+            ttl: u64::MAX,
+            max: 3,
+        };
+        assert_eq!(pm.evict(limit).await, (1, 1));
+        assert!(!pm.is_in_memory("peer-2").await);
+        assert_eq!(pm.evict(Limit { ttl: 3600, max: 0 }).await, (1, 1));
+        assert!(!pm.is_in_memory("peer-1").await);
+        assert!(pm.is_in_memory("peer-3").await && pm.is_in_memory("peer-4").await);
+        assert_eq!(pm.len(), 2);
+        // not in db, so a miss
+        assert!(pm.get("peer-2").await.is_none());
+        assert_eq!(CACHE_MISSES.get(), misses + 1);
+        drop(pm);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn refreshes_from_db() {
+        refresh();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn refresh() {
+        let path =
+            std::env::temp_dir().join(format!("hbbs-peer-refresh-{}.sqlite3", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::remove_file(path).ok();
+        // two instances sharing the database
+        let pm = peer_map(path, 10).await;
+        let other = peer_map(path, 10).await;
+        pm.db
+            .insert_peer("peer-1", b"uuid", b"pk", "{}")
+            .await
+            .unwrap();
+        let peer = pm.get("peer-1").await.unwrap();
+        other.set_disabled("peer-1", true).await.unwrap();
+        other
+            .db
+            .update_pk(&peer.read().await.guid, "peer-1", b"pk2", "{}")
+            .await
+            .unwrap();
+        pm.get("peer-1").await.unwrap();
+        assert!(!peer.read().await.disabled);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pm.get("peer-1").await.unwrap();
+        assert!(peer.read().await.disabled);
+        assert_eq!(&peer.read().await.pk[..], b"pk2");
+        other.delete("peer-1").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pm.get("peer-1").await.is_none());
+        assert!(!pm.is_in_memory("peer-1").await);
+        drop((pm, other));
+        std::fs::remove_file(path).ok();
     }
 }
@@ -0,0 +1,92 @@
+// Networks of one address family keyed by prefix, looked up by trying each
+// prefix length in use, longest first, e.g. the GEO_FILE regions and the
+// ranges of the IP lists. IPv4 addresses are stored as u128 with bits 32.
+
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub(crate) struct PrefixTable<V> {
+    lens: Vec<u8>, // prefix lengths in use, longest first
+    nets: HashMap<(u8, u128), V>,
+}
+
+impl<V> Default for PrefixTable<V> {
+    fn default() -> Self {
+        Self {
+            lens: Vec::new(),
+            nets: HashMap::new(),
+        }
+    }
+}
+
+#[inline]
+fn mask(addr: u128, bits: u8, len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        addr & (!0u128 << (bits - len))
+    }
+}
+
+impl<V> PrefixTable<V> {
+    /// Adds `addr/len`, or replaces its value.
+    pub(crate) fn insert(&mut self, bits: u8, addr: u128, len: u8, v: V) {
+        self.nets.insert((len, mask(addr, bits, len)), v);
+        if !self.lens.contains(&len) {
+            self.lens.push(len);
+            self.lens.sort_unstable_by(|a, b| b.cmp(a));
+        }
+    }
+
+    /// Removes `addr/len` as inserted, not the networks it overlaps.
+    pub(crate) fn remove(&mut self, bits: u8, addr: u128, len: u8) -> bool {
+        self.nets.remove(&(len, mask(addr, bits, len))).is_some()
+    }
+
+    /// The values of the networks containing `addr`, longest prefix first.
+    pub(crate) fn matches(&self, bits: u8, addr: u128) -> impl Iterator<Item = &V> {
+        self.lens
+            .iter()
+            .filter_map(move |&len| self.nets.get(&(len, mask(addr, bits, len))))
+    }
+
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&V) -> bool) {
+        self.nets.retain(|_, v| f(v));
+        let nets = &self.nets;
+        self.lens.retain(|len| nets.keys().any(|x| x.0 == *len));
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.nets.len()
+    }
+
+    /// `(len, network address)` with the value, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&(u8, u128), &V)> {
+        self.nets.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_first() {
+        let mut t = PrefixTable::default();
+        t.insert(32, 0x0a00_0000, 8, "a");
+        t.insert(32, 0x0a01_0203, 16, "b");
+        t.insert(32, 0, 0, "c");
+        assert_eq!(
+            t.matches(32, 0x0a01_ffff).collect::<Vec<_>>(),
+            [&"b", &"a", &"c"]
+        );
+        assert_eq!(t.matches(32, 0x0b00_0000).collect::<Vec<_>>(), [&"c"]);
+        assert!(t.iter().any(|(k, _)| *k == (16, 0x0a01_0000)));
+        assert!(t.remove(32, 0x0a01_ffff, 16));
+        assert!(!t.remove(32, 0x0a01_ffff, 16));
+        t.retain(|v| *v != "c");
+        assert_eq!(t.len(), 1);
+        assert_eq!(t.lens, [8]);
+    }
+}
@@ -0,0 +1,451 @@
+// Bandwidth rules of hbbr, bandwidth_rules.txt in its working directory. Each
+// line is `<key:K|uuid:U|id:I> [speed=<Mb/s>] [daily=<bytes>] [monthly=<bytes>]
+// [over=refuse|throttle]` and applies to the relay sessions whose request
+// carries licence key K, uuid U or the ID I of the controlled peer. Every
+// matching rule applies: the session speed is capped by the lowest speed, and
+// once the bytes relayed for the subject of a rule in the current UTC day or
+// month reach its quota, sessions are refused or throttled to LIMIT_SPEED.
+// Usage is counted in memory and, with a database, added to `relay_usage`
+// every FLUSH_INTERVAL, so that relays sharing DB_URL share the quotas.
+
+use crate::database::{Database, RelayUsage};
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        time::{interval, Duration},
+    },
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, RwLock},
+};
+
+pub(crate) const RULES_FILE: &str = "bandwidth_rules.txt";
+// of the varchar(100) column
+const MAX_SUBJECT_LEN: usize = 100;
+const FLUSH_INTERVAL: u64 = 10; // in seconds
+
+lazy_static::lazy_static! {
+    static ref RULES: RwLock<Vec<Rule>> = Default::default();
+    static ref USED: Mutex<HashMap<String, Used>> = Default::default();
+}
+static DB: OnceCell<Database> = OnceCell::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Rule {
+    /// `key:`, `uuid:` or `id:` and the value to match
+    subject: String,
+    speed: Option<usize>, // in bit/s
+    daily: Option<u64>,   // in bytes
+    monthly: Option<u64>, // in bytes
+    refuse: bool,
+}
+
+/// Bytes relayed for a subject in the current periods.
+#[derive(Debug, Default)]
+struct Used {
+    day: String,
+    daily: u64,
+    month: String,
+    monthly: u64,
+    // not yet added to the database
+    pending: u64,
+}
+
+/// What to do with a session over quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Over {
+    Refuse,
+    Throttle,
+}
+
+/// The current UTC day `YYYY-MM-DD` and month `YYYY-MM`.
+fn periods() -> (String, String) {
+    let now = chrono::Utc::now();
+    (
+        now.format("%Y-%m-%d").to_string(),
+        now.format("%Y-%m").to_string(),
+    )
+}
+
+impl Used {
+    /// Starts over the periods that ended.
+    fn roll(&mut self, (day, month): &(String, String)) {
+        if self.day != *day {
+            self.day = day.clone();
+            self.daily = 0;
+        }
+        if self.month != *month {
+            self.month = month.clone();
+            self.monthly = 0;
+        }
+    }
+}
+
+/// Parses a byte count with an optional K, M, G or T suffix, powers of 1024.
+fn parse_bytes(s: &str) -> Option<u64> {
+    let s = s.to_uppercase();
+    let (v, unit) = match s.char_indices().last()? {
+        (i, 'K') => (&s[..i], 1u64 << 10),
+        (i, 'M') => (&s[..i], 1 << 20),
+        (i, 'G') => (&s[..i], 1 << 30),
+        (i, 'T') => (&s[..i], 1 << 40),
+        _ => (&s[..], 1),
+    };
+    let v: f64 = v.parse().ok()?;
+    (v >= 0.).then_some((v * unit as f64) as u64)
+}
+
+/// Formats a byte count the way [`parse_bytes`] takes it.
+fn format_bytes(v: u64) -> String {
+    let (unit, suffix) = [
+        (1u64 << 40, "T"),
+        (1 << 30, "G"),
+        (1 << 20, "M"),
+        (1 << 10, "K"),
+    ]
+    .into_iter()
+    .find(|x| v >= x.0)
+    .unwrap_or((1, ""));
+    let v = format!("{:.2}", v as f64 / unit as f64);
+    format!(
+        "{}{}",
+        v.trim_end_matches('0').trim_end_matches('.'),
+        suffix
+    )
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut fds = line.split_whitespace();
+        let subject = fds.next().unwrap_or_default();
+        let valid = subject
+            .split_once(':')
+            .map(|(kind, v)| matches!(kind, "key" | "uuid" | "id") && !v.is_empty())
+            .unwrap_or(false);
+        if !valid || subject.len() > MAX_SUBJECT_LEN {
+            return Err(format!("invalid subject: {subject}"));
+        }
+        let mut rule = Rule {
+            subject: subject.to_owned(),
+            speed: None,
+            daily: None,
+            monthly: None,
+            refuse: true,
+        };
+        for fd in fds {
+            let Some((name, v)) = fd.split_once('=') else {
+                return Err(format!("invalid option: {fd}"));
+            };
+            let ok = match name {
+                "speed" => v
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|x| *x > 0.)
+                    .map(|x| rule.speed = Some((x * 1024. * 1024.) as _)),
+                "daily" => parse_bytes(v).map(|x| rule.daily = Some(x)),
+                "monthly" => parse_bytes(v).map(|x| rule.monthly = Some(x)),
+                "over" => match v {
+                    "refuse" | "throttle" => {
+                        rule.refuse = v == "refuse";
+                        Some(())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            if ok.is_none() {
+                return Err(format!("invalid option: {fd}"));
+            }
+        }
+        Ok(rule)
+    }
+
+    fn to_line(&self) -> String {
+        let mut res = self.subject.clone();
+        if let Some(v) = self.speed {
+            res += &format!(" speed={}", v as f64 / 1024. / 1024.);
+        }
+        if let Some(v) = self.daily {
+            res += &format!(" daily={}", format_bytes(v));
+        }
+        if let Some(v) = self.monthly {
+            res += &format!(" monthly={}", format_bytes(v));
+        }
+        res += if self.refuse {
+            " over=refuse"
+        } else {
+            " over=throttle"
+        };
+        res
+    }
+
+    fn over(&self, used: &Used) -> bool {
+        self.daily.map(|x| used.daily >= x).unwrap_or(false)
+            || self.monthly.map(|x| used.monthly >= x).unwrap_or(false)
+    }
+}
+
+/// Parses the rules file, a later rule of the same subject replaces the
+/// earlier one.
+fn parse(content: &str) -> Vec<Rule> {
+    let mut rules: Vec<Rule> = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Rule::parse(line) {
+            Ok(rule) => {
+                rules.retain(|x| x.subject != rule.subject);
+                rules.push(rule);
+            }
+            Err(err) => log::warn!("{}: {}", RULES_FILE, err),
+        }
+    }
+    rules
+}
+
+/// Re-reads the rules file, returns what changed.
+pub(crate) fn reload() -> Option<String> {
+    let rules = parse(&std::fs::read_to_string(RULES_FILE).unwrap_or_default());
+    let mut lock = RULES.write().unwrap();
+    if *lock == rules {
+        return None;
+    }
+    let res = format!("{}: {} rules", RULES_FILE, rules.len());
+    log::info!("{}", res);
+    *lock = rules;
+    Some(res)
+}
+
+/// Whether any rule is set.
+pub(crate) fn enabled() -> bool {
+    !RULES.read().unwrap().is_empty()
+}
+
+/// Starts adding the usage to `db`.
+pub(crate) fn start(db: Database) {
+    if DB.set(db).is_err() {
+        return;
+    }
+    tokio::spawn(async {
+        let mut timer = interval(Duration::from_secs(FLUSH_INTERVAL));
+        loop {
+            timer.tick().await;
+            flush().await;
+        }
+    });
+}
+
+/// The rules of a relay session, fixed when it is paired.
+#[derive(Debug, Default)]
+pub(crate) struct Session {
+    rules: Vec<Rule>,
+}
+
+impl Session {
+    /// The rules matching the licence keys of both sides, the uuid or the ID.
+    pub(crate) fn new(keys: [&str; 2], uuid: &str, id: &str) -> Self {
+        let mut subjects = HashSet::new();
+        for key in keys.iter().filter(|x| !x.is_empty()) {
+            subjects.insert(format!("key:{key}"));
+        }
+        subjects.insert(format!("uuid:{uuid}"));
+        if !id.is_empty() {
+            subjects.insert(format!("id:{id}"));
+        }
+        let rules = RULES
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|x| subjects.contains(&x.subject))
+            .cloned()
+            .collect();
+        Self { rules }
+    }
+
+    /// The lowest speed cap of the rules.
+    pub(crate) fn speed(&self) -> Option<usize> {
+        self.rules.iter().filter_map(|x| x.speed).min()
+    }
+
+    /// Counts `n` relayed bytes for every rule.
+    pub(crate) fn charge(&self, n: u64) {
+        if self.rules.is_empty() || n == 0 {
+            return;
+        }
+        let periods = periods();
+        let mut lock = USED.lock().unwrap();
+        for rule in &self.rules {
+            let used = lock.entry(rule.subject.clone()).or_default();
+            used.roll(&periods);
+            used.daily += n;
+            used.monthly += n;
+            used.pending += n;
+        }
+    }
+
+    /// Refuse if a refusing rule is over quota, otherwise throttle if any is.
+    pub(crate) fn over(&self) -> Option<Over> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let periods = periods();
+        let mut lock = USED.lock().unwrap();
+        let mut res = None;
+        for rule in &self.rules {
+            let used = lock.entry(rule.subject.clone()).or_default();
+            used.roll(&periods);
+            if rule.over(used) {
+                if rule.refuse {
+                    return Some(Over::Refuse);
+                }
+                res = Some(Over::Throttle);
+            }
+        }
+        res
+    }
+}
+
+/// Adds the pending usage to the database and takes the totals from there,
+/// which include the usage of the other relays. Also called on shutdown.
+pub(crate) async fn flush() {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    let periods = periods();
+    let mut usage = Vec::new();
+    for (subject, used) in USED.lock().unwrap().iter_mut() {
+        used.roll(&periods);
+        if used.pending == 0 {
+            continue;
+        }
+        for period in [&used.day, &used.month] {
+            usage.push(RelayUsage {
+                subject: subject.clone(),
+                period: period.clone(),
+                bytes: used.pending as _,
+            });
+        }
+        used.pending = 0;
+    }
+    if !usage.is_empty() {
+        if let Err(err) = db.add_relay_usage(&usage).await {
+            log::error!("db.add_relay_usage failed: {}", err);
+            // retry next time
+            let mut lock = USED.lock().unwrap();
+            for v in usage.iter().step_by(2) {
+                lock.entry(v.subject.clone()).or_default().pending += v.bytes as u64;
+            }
+            return;
+        }
+    }
+    let (daily, monthly) = match (
+        db.get_relay_usage(&periods.0).await,
+        db.get_relay_usage(&periods.1).await,
+    ) {
+        (Ok(daily), Ok(monthly)) => (daily, monthly),
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("db.get_relay_usage failed: {}", err);
+            return;
+        }
+    };
+    let subjects: HashSet<String> = RULES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|x| x.subject.clone())
+        .collect();
+    let mut lock = USED.lock().unwrap();
+    lock.retain(|k, v| v.pending > 0 || subjects.contains(k));
+    for (v, daily) in daily
+        .into_iter()
+        .map(|x| (x, true))
+        .chain(monthly.into_iter().map(|x| (x, false)))
+    {
+        if !subjects.contains(&v.subject) {
+            continue;
+        }
+        let used = lock.entry(v.subject).or_default();
+        used.roll(&periods);
+        // plus what was relayed since the pending usage was taken
+        if daily {
+            used.daily = v.bytes as u64 + used.pending;
+        } else {
+            used.monthly = v.bytes as u64 + used.pending;
+        }
+    }
+}
+
+/// The rules and their usage, for the console.
+pub(crate) fn show() -> String {
+    let periods = periods();
+    let mut lock = USED.lock().unwrap();
+    let mut res = String::new();
+    for rule in RULES.read().unwrap().iter() {
+        let used = lock.entry(rule.subject.clone()).or_default();
+        used.roll(&periods);
+        res += &format!(
+            "{}: {} today, {} this month{}\n",
+            rule.to_line(),
+            format_bytes(used.daily),
+            format_bytes(used.monthly),
+            if rule.over(used) { ", over quota" } else { "" }
+        );
+    }
+    if res.is_empty() {
+        res = "no rules\n".to_owned();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_applies_rules() {
+        assert_eq!(parse_bytes("1.5k"), Some(1536));
+        assert_eq!(parse_bytes("2G"), Some(2 << 30));
+        assert_eq!(parse_bytes("100"), Some(100));
+        assert!(parse_bytes("-1M").is_none());
+        assert!(parse_bytes("lots").is_none());
+        let rules = parse(
+            "# tiers
+            key:gold speed=100 monthly=1T
+            key:free speed=8 daily=1k over=throttle # trial
+            id:123456789 daily=2K
+            id:123456789 daily=3K
+            uuid: speed=1
+            host:example daily=1G
+            key:bronze speed=fast",
+        );
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[2].daily, Some(3072));
+        assert!(!rules[1].refuse);
+        assert_eq!(
+            rules[1].to_line(),
+            "key:free speed=8 daily=1K over=throttle"
+        );
+        *RULES.write().unwrap() = rules;
+        assert!(Session::new(["", ""], "u1", "987654321").rules.is_empty());
+        let session = Session::new(["gold", "free"], "u2", "");
+        assert_eq!(session.speed(), Some(8 * 1024 * 1024));
+        assert_eq!(session.over(), None);
+        session.charge(1024);
+        assert_eq!(session.over(), Some(Over::Throttle));
+        let session = Session::new(["", "free"], "u3", "123456789");
+        assert_eq!(session.speed(), Some(8 * 1024 * 1024));
+        assert_eq!(session.over(), Some(Over::Throttle));
+        session.charge(2048);
+        assert_eq!(session.over(), Some(Over::Throttle));
+        session.charge(1024);
+        assert_eq!(session.over(), Some(Over::Refuse));
+        assert_eq!(format_bytes(5 << 30), "5G");
+        assert!(show()
+            .contains("id:123456789 daily=3K over=refuse: 3K today, 3K this month, over quota"));
+    }
+}
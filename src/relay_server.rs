@@ -1,22 +1,28 @@
+use crate::accounting;
+use crate::audit;
+use crate::common::RelayStatus;
+use crate::database::{self, AuditEvent, Database, RelaySession};
+use crate::ip_list::{self, IpList};
+use crate::metrics::{Counter, Encoder};
+use crate::quota;
 use async_speed_limit::Limiter;
-use async_trait::async_trait;
 use hbb_common::{
     allow_err, bail,
     bytes::{Bytes, BytesMut},
+    bytes_codec::BytesCodec,
     futures_util::{sink::SinkExt, stream::StreamExt},
     log,
     protobuf::Message as _,
     rendezvous_proto::*,
-    sleep,
-    tcp::FramedStream,
-    timeout,
+    sleep, timeout,
     tokio::{
         self,
-        io::{AsyncReadExt, AsyncWriteExt},
+        io::{AsyncReadExt, AsyncWriteExt, BufReader},
         net::{TcpListener, TcpStream},
-        sync::{Mutex, RwLock},
+        sync::{Mutex, Notify, RwLock},
         time::{interval, Duration},
     },
+    tokio_util::codec::Framed,
     ResultType,
 };
 use sodiumoxide::crypto::sign;
@@ -25,16 +31,29 @@ use std::{
     io::prelude::*,
     io::Error,
     net::{IpAddr, SocketAddr},
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
 type Usage = (usize, usize, usize, usize);
+// a TCP relay connection, the socket is spliced directly on Linux
+type TcpFramed = Framed<TcpStream, BytesCodec>;
+
+// a relay request waiting for its peer
+struct Pending {
+    stream: RelayStream,
+    addr: SocketAddr,
+    id: String,
+    licence_key: String,
+}
 
 lazy_static::lazy_static! {
-    static ref PEERS: Mutex<HashMap<String, Box<dyn StreamTrait>>> = Default::default();
+    static ref PEERS: Mutex<HashMap<String, Pending>> = Default::default();
     static ref USAGE: RwLock<HashMap<String, Usage>> = Default::default();
-    static ref BLACKLIST: RwLock<HashSet<String>> = Default::default();
-    static ref BLOCKLIST: RwLock<HashSet<String>> = Default::default();
+    static ref BLACKLIST: RwLock<IpList> = Default::default();
+    static ref BLOCKLIST: RwLock<IpList> = Default::default();
+    // held while a console edit is written to a list file
+    static ref SAVING: Mutex<()> = Default::default();
+    static ref RELOAD: Notify = Notify::new();
 }
 
 static DOWNGRADE_THRESHOLD_100: AtomicUsize = AtomicUsize::new(66); // 0.66
@@ -42,8 +61,43 @@ static DOWNGRADE_START_CHECK: AtomicUsize = AtomicUsize::new(1_800_000); // in m
 static LIMIT_SPEED: AtomicUsize = AtomicUsize::new(32 * 1024 * 1024); // in bit/s
 static TOTAL_BANDWIDTH: AtomicUsize = AtomicUsize::new(1024 * 1024 * 1024); // in bit/s
 static SINGLE_BANDWIDTH: AtomicUsize = AtomicUsize::new(128 * 1024 * 1024); // in bit/s
+static CONGESTION_POLICY: AtomicBool = AtomicBool::new(false);
+static CONGESTION_WATERMARK_100: AtomicUsize = AtomicUsize::new(80); // 0.8
+static CONGESTED: AtomicBool = AtomicBool::new(false);
+static FAIR_SHARE: AtomicUsize = AtomicUsize::new(usize::MAX); // in bit/s
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static DRAIN_DEADLINE: AtomicU64 = AtomicU64::new(0); // unix time in seconds
+static RELAY_SESSIONS: Counter = Counter::new();
+static RELAYED_BYTES: Counter = Counter::new();
+static SPLICED_SESSIONS: Counter = Counter::new();
+static DOWNGRADED: Counter = Counter::new();
+static PROMOTED: Counter = Counter::new();
+static BLOCKLIST_HITS: Counter = Counter::new();
+static BLACKLIST_HITS: Counter = Counter::new();
+static QUOTA_REFUSED: Counter = Counter::new();
+static QUOTA_THROTTLED: Counter = Counter::new();
 const BLACKLIST_FILE: &str = "blacklist.txt";
 const BLOCKLIST_FILE: &str = "blocklist.txt";
+// congestion ends below this share of the watermark, in percent
+const UNCONGESTED_100: usize = 90;
+// of a session throttled because of congestion before it may be promoted back
+const PROMOTE_AFTER: Duration = Duration::from_secs(10);
+// read from a WebSocket at once, tungstenite itself reads 4096 bytes per call
+const WS_READ_BUFFER: usize = 64 * 1024;
+const DRAIN_TIMEOUT: u64 = 600; // in seconds
+
+// config only read at startup
+const RESTART_ARGS: &[&str] = &[
+    "PORT",
+    "BIND",
+    "KEY",
+    "RELAY_METRICS_PORT",
+    "DB_URL",
+    "MAX_DATABASE_CONNECTIONS",
+    "AUDIT_LOG",
+    "RELAY_ACCOUNTING_FILE",
+    "RELAY_ACCOUNTING_DB",
+];
 
 #[tokio::main(flavor = "multi_thread")]
 pub async fn start_with_bind(
@@ -52,37 +106,44 @@ pub async fn start_with_bind(
     key: &str,
 ) -> ResultType<()> {
     let key = get_server_sk(key);
-    if let Ok(mut file) = std::fs::File::open(BLACKLIST_FILE) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            for x in contents.split('\n') {
-                if let Some(ip) = x.trim().split(' ').next() {
-                    BLACKLIST.write().await.insert(ip.to_owned());
-                }
-            }
-        }
-    }
+    *BLACKLIST.write().await = read_ip_list(BLACKLIST_FILE);
     log::info!(
         "#blacklist({}): {}",
         BLACKLIST_FILE,
         BLACKLIST.read().await.len()
     );
-    if let Ok(mut file) = std::fs::File::open(BLOCKLIST_FILE) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            for x in contents.split('\n') {
-                if let Some(ip) = x.trim().split(' ').next() {
-                    BLOCKLIST.write().await.insert(ip.to_owned());
-                }
-            }
-        }
-    }
+    *BLOCKLIST.write().await = read_ip_list(BLOCKLIST_FILE);
     log::info!(
         "#blocklist({}): {}",
         BLOCKLIST_FILE,
         BLOCKLIST.read().await.len()
     );
+    quota::reload();
+    open_database().await;
+    accounting::start_file();
     let port: u16 = port.parse()?;
+    let metrics_port = crate::common::get_arg("relay-metrics-port")
+        .parse::<u16>()
+        .unwrap_or(0);
+    if metrics_port > 0 {
+        tokio::spawn(async move {
+            let res = crate::metrics::start(bind_addr, metrics_port, render_metrics).await;
+            if let Err(err) = res {
+                log::error!("Failed to run metrics server: {}", err);
+            }
+        });
+    }
+    tokio::spawn(async {
+        allow_err!(listen_drain_signal().await);
+    });
+    tokio::spawn(watch_congestion());
+    let mut files = crate::common::config_files();
+    files.extend([
+        BLACKLIST_FILE.to_owned(),
+        BLOCKLIST_FILE.to_owned(),
+        quota::RULES_FILE.to_owned(),
+    ]);
+    tokio::spawn(crate::common::watch_config(files, || RELOAD.notify_one()));
     log::info!("Listening on tcp :{}", port);
     let port2 = port + 2;
     log::info!("Listening on websocket :{}", port2);
@@ -98,10 +159,234 @@ pub async fn start_with_bind(
         }
     };
     let listen_signal = crate::common::listen_signal();
-    tokio::select!(
+    let res = tokio::select!(
         res = main_task => res,
         res = listen_signal => res,
+        _ = wait_drained() => Ok(()),
+    );
+    audit::flush().await;
+    quota::flush().await;
+    accounting::flush().await;
+    res
+}
+
+/// Opens DB_URL for the audit log, the usage of the bandwidth rules and the
+/// accounting records, the only uses hbbr has for a database, if any is on at
+/// startup. Runs without one if that fails, the usage then only counted by
+/// this relay since it started.
+async fn open_database() {
+    // not the default of hbbs, a stray database or a second writer next to it
+    let Some(url) = crate::common::get_arg_opt("DB_URL").filter(|x| !x.is_empty()) else {
+        log::info!("DB_URL not set, no audit log");
+        if accounting::to_database() {
+            log::warn!("RELAY_ACCOUNTING_DB=Y ignored without DB_URL");
+        }
+        return;
+    };
+    let audit = crate::common::get_arg("AUDIT_LOG").to_uppercase() != "N";
+    if !audit {
+        log::info!("AUDIT_LOG=N");
+    }
+    if !audit && !quota::enabled() && !accounting::to_database() {
+        return;
+    }
+    log::info!("DB_URL={}", database::redact_url(&url));
+    match Database::new(&url).await {
+        Ok(db) => {
+            audit::start(db.clone());
+            quota::start(db.clone());
+            accounting::start(db);
+        }
+        Err(err) => log::error!("Failed to open the database: {}", err),
+    }
+}
+
+fn read_ip_list(file: &str) -> IpList {
+    let mut contents = String::new();
+    if let Ok(mut file) = std::fs::File::open(file) {
+        if file.read_to_string(&mut contents).is_err() {
+            contents.clear();
+        }
+    }
+    IpList::parse(&contents)
+}
+
+async fn reload_ip_list(list: &RwLock<IpList>, file: &str) -> Option<String> {
+    let ips = read_ip_list(file);
+    let mut lock = list.write().await;
+    let nets = |list: &IpList| {
+        list.entries()
+            .into_iter()
+            .map(|x| x.0)
+            .collect::<HashSet<_>>()
+    };
+    let (new, old) = (nets(&ips), nets(&lock));
+    let added = new.difference(&old).count();
+    let removed = old.difference(&new).count();
+    *lock = ips;
+    if added + removed == 0 {
+        return None;
+    }
+    let res = format!("{}: {} added, {} removed", file, added, removed);
+    log::info!("{}", res);
+    Some(res)
+}
+
+/// Re-applies the config files and re-reads the blacklist, blocklist and
+/// bandwidth rules, dropping console edits of the lists.
+async fn reload(limiter: &Limiter) -> String {
+    use std::fmt::Write;
+
+    let mut res = String::new();
+    let changes = match crate::common::reload_config(RESTART_ARGS) {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Failed to reload config: {}", err);
+            let _ = writeln!(res, "{}", err);
+            Vec::new()
+        }
+    };
+    for x in changes.iter() {
+        let _ = writeln!(res, "{}", x);
+    }
+    if changes.iter().any(|x| x.applied) {
+        check_params();
+        limiter.set_speed_limit(TOTAL_BANDWIDTH.load(Ordering::SeqCst) as _);
+    }
+    for (list, file) in [(&*BLACKLIST, BLACKLIST_FILE), (&*BLOCKLIST, BLOCKLIST_FILE)] {
+        if let Some(x) = reload_ip_list(list, file).await {
+            let _ = writeln!(res, "{}", x);
+        }
+    }
+    if let Some(x) = quota::reload() {
+        let _ = writeln!(res, "{}", x);
+    }
+    if res.is_empty() {
+        res = "no change\n".to_owned();
+    }
+    res
+}
+
+fn drain_timeout() -> u64 {
+    crate::common::get_arg("DRAIN_TIMEOUT")
+        .parse()
+        .unwrap_or(DRAIN_TIMEOUT)
+}
+
+fn start_drain(timeout: u64) {
+    DRAIN_DEADLINE.store(crate::common::now() + timeout, Ordering::SeqCst);
+    DRAINING.store(true, Ordering::SeqCst);
+    log::info!("Draining, exit in at most {}s", timeout);
+}
+
+#[cfg(unix)]
+async fn listen_drain_signal() -> ResultType<()> {
+    use hbb_common::tokio::signal::unix::{signal, SignalKind};
+
+    let mut s = signal(SignalKind::user_defined1())?;
+    while s.recv().await.is_some() {
+        log::info!("signal user defined 1");
+        if !DRAINING.load(Ordering::SeqCst) {
+            start_drain(drain_timeout());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn listen_drain_signal() -> ResultType<()> {
+    Ok(())
+}
+
+/// Returns once draining and all relay connections are closed, or the drain
+/// deadline has passed.
+async fn wait_drained() {
+    let mut timer = interval(Duration::from_secs(1));
+    loop {
+        timer.tick().await;
+        if !DRAINING.load(Ordering::SeqCst) {
+            continue;
+        }
+        let n = USAGE.read().await.len() + PEERS.lock().await.len();
+        if n == 0 {
+            log::info!("Drained");
+            return;
+        }
+        if crate::common::now() >= DRAIN_DEADLINE.load(Ordering::SeqCst) {
+            log::info!("Drain deadline reached, closing {} relay connections", n);
+            return;
+        }
+    }
+}
+
+async fn render_metrics() -> String {
+    let mut out = Encoder::default();
+    out.counter(
+        "hbbr_relay_sessions_total",
+        "Relay requests that got paired.",
+        RELAY_SESSIONS.get(),
+    )
+    .counter(
+        "hbbr_spliced_sessions_total",
+        "Relay sessions forwarded with splice(2) within the kernel.",
+        SPLICED_SESSIONS.get(),
+    )
+    .counter(
+        "hbbr_relayed_bytes_total",
+        "Bytes forwarded in both directions.",
+        RELAYED_BYTES.get(),
+    )
+    .counter(
+        "hbbr_downgraded_connections_total",
+        "Relay connections downgraded to LIMIT_SPEED.",
+        DOWNGRADED.get(),
+    )
+    .counter(
+        "hbbr_promoted_connections_total",
+        "Relay connections throttled because of congestion and promoted back.",
+        PROMOTED.get(),
+    )
+    .counter(
+        "hbbr_blocklist_hits_total",
+        "Connections refused or closed because of the blocklist.",
+        BLOCKLIST_HITS.get(),
+    )
+    .counter(
+        "hbbr_blacklist_hits_total",
+        "Relay connections limited because of the blacklist.",
+        BLACKLIST_HITS.get(),
+    )
+    .counter(
+        "hbbr_quota_refused_total",
+        "Relay sessions refused or closed over the quota of a bandwidth rule.",
+        QUOTA_REFUSED.get(),
     )
+    .counter(
+        "hbbr_quota_throttled_total",
+        "Relay sessions throttled to LIMIT_SPEED over the quota of a bandwidth rule.",
+        QUOTA_THROTTLED.get(),
+    )
+    .gauge(
+        "hbbr_active_relays",
+        "Paired relay connections.",
+        USAGE.read().await.len(),
+    )
+    .gauge(
+        "hbbr_pending_relays",
+        "Relay requests waiting for their peer.",
+        PEERS.lock().await.len(),
+    )
+    .gauge(
+        "hbbr_congested",
+        "1 while congested, with DOWNGRADE_POLICY=congestion.",
+        CONGESTED.load(Ordering::SeqCst) as u8,
+    )
+    .gauge(
+        "hbbr_draining",
+        "1 while draining, no new relay requests accepted.",
+        DRAINING.load(Ordering::SeqCst) as u8,
+    );
+    out.finish()
 }
 
 fn check_params() {
@@ -125,6 +410,22 @@ fn check_params() {
         "DOWNGRADE_START_CHECK: {}s",
         DOWNGRADE_START_CHECK.load(Ordering::SeqCst) / 1000
     );
+    let tmp = crate::common::get_arg("DOWNGRADE_POLICY").to_lowercase() == "congestion";
+    CONGESTION_POLICY.store(tmp, Ordering::SeqCst);
+    log::info!(
+        "DOWNGRADE_POLICY: {}",
+        if tmp { "congestion" } else { "average" }
+    );
+    let tmp = crate::common::get_arg("CONGESTION_WATERMARK")
+        .parse::<f64>()
+        .unwrap_or(0.);
+    if tmp > 0. && tmp <= 1. {
+        CONGESTION_WATERMARK_100.store((tmp * 100.) as _, Ordering::SeqCst);
+    }
+    log::info!(
+        "CONGESTION_WATERMARK: {}",
+        CONGESTION_WATERMARK_100.load(Ordering::SeqCst) as f64 / 100.
+    );
     let tmp = crate::common::get_arg("LIMIT_SPEED")
         .parse::<f64>()
         .unwrap_or(0.);
@@ -158,6 +459,94 @@ fn check_params() {
     )
 }
 
+/// Applies `edit` to `file` and then to `list`, in memory only if the file
+/// cannot be written. `list` is only locked once the file is written, so that
+/// the relays checking it meanwhile do not wait for the disk.
+async fn ip_list_edit(list: &RwLock<IpList>, file: &str, edit: ip_list::Edit) -> String {
+    use ip_list::Edit;
+
+    let _saving = SAVING.lock().await;
+    let saved = {
+        let (file, edit) = (file.to_owned(), edit.clone());
+        tokio::task::spawn_blocking(move || ip_list::save(&file, &edit, ip_list::net_key)).await
+    };
+    let res = match saved.map_err(std::io::Error::from).and_then(|x| x) {
+        Ok(_) => "".to_owned(),
+        Err(err) => {
+            log::error!("Failed to write {}: {}", file, err);
+            format!("not saved to {file}: {err}\n")
+        }
+    };
+    let mut lock = list.write().await;
+    match edit {
+        Edit::Add(nets, expires, _) => {
+            for net in nets.iter().filter_map(|x| ip_list::parse_net(x)) {
+                lock.insert(net, expires);
+            }
+        }
+        Edit::Remove(nets) => {
+            for net in nets.iter().filter_map(|x| ip_list::parse_net(x)) {
+                lock.remove(net);
+            }
+        }
+        Edit::Clear => lock.clear(),
+    }
+    res
+}
+
+fn parse_nets(ips: &str) -> Result<Vec<String>, String> {
+    ips.split('|')
+        .map(|ip| ip_list::net_key(ip).ok_or_else(|| format!("invalid IP or CIDR: {ip}\n")))
+        .collect()
+}
+
+/// Adds the `|` separated IPs or ranges, expiring after a duration or at an
+/// RFC 3339 time if given, with an optional comment after `#`.
+async fn ip_list_add<'a>(
+    list: &RwLock<IpList>,
+    file: &str,
+    ips: Option<&str>,
+    args: impl Iterator<Item = &'a str>,
+) -> String {
+    let Some(ips) = ips else {
+        return "".to_owned();
+    };
+    let (expires, comment) = match ip_list::parse_add_args(args, crate::common::now()) {
+        Ok(v) => v,
+        Err(err) => return format!("{err}\n"),
+    };
+    match parse_nets(ips) {
+        Ok(nets) => ip_list_edit(list, file, ip_list::Edit::Add(nets, expires, comment)).await,
+        Err(err) => err,
+    }
+}
+
+async fn ip_list_remove(list: &RwLock<IpList>, file: &str, ips: Option<&str>) -> String {
+    match ips {
+        Some("all") => ip_list_edit(list, file, ip_list::Edit::Clear).await,
+        Some(ips) => match parse_nets(ips) {
+            Ok(nets) => ip_list_edit(list, file, ip_list::Edit::Remove(nets)).await,
+            Err(err) => err,
+        },
+        None => "".to_owned(),
+    }
+}
+
+/// Whether `ip` is in a range of the list, or the whole list.
+async fn ip_list_show(list: &RwLock<IpList>, ip: Option<&str>) -> String {
+    match ip {
+        Some(ip) => match ip.parse() {
+            Ok(ip) => format!("{}\n", list.read().await.contains(ip)),
+            Err(_) => format!("invalid IP: {ip}\n"),
+        },
+        None => {
+            let mut lock = list.write().await;
+            lock.remove_expired(crate::common::now());
+            lock.to_lines()
+        }
+    }
+}
+
 async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
     use std::fmt::Write;
 
@@ -166,74 +555,43 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
     match fds.next() {
         Some("h") => {
             res = format!(
-                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
-                "blacklist-add(ba) <ip>",
-                "blacklist-remove(br) <ip>",
+                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+                "blacklist-add(ba) <ip|cidr> [<duration, e.g. 30m, 12h, 7d>|<expiry>] [# comment]",
+                "blacklist-remove(br) <ip|cidr>",
                 "blacklist(b) <ip>",
-                "blocklist-add(Ba) <ip>",
-                "blocklist-remove(Br) <ip>",
+                "blocklist-add(Ba) <ip|cidr> [<duration, e.g. 30m, 12h, 7d>|<expiry>] [# comment]",
+                "blocklist-remove(Br) <ip|cidr>",
                 "blocklist(B) <ip>",
                 "downgrade-threshold(dt) [value]",
                 "downgrade-start-check(t) [value(second)]",
+                "downgrade-policy(dp) [average|congestion]",
+                "congestion-watermark(cw) [value]",
                 "limit-speed(ls) [value(Mb/s)]",
                 "total-bandwidth(tb) [value(Mb/s)]",
                 "single-bandwidth(sb) [value(Mb/s)]",
-                "usage(u)"
+                "usage(u)",
+                "quota(q)",
+                "drain(dr) [<timeout(second)>|-]",
+                "reload-config(rc)"
             )
         }
         Some("blacklist-add" | "ba") => {
-            if let Some(ip) = fds.next() {
-                for ip in ip.split('|') {
-                    BLACKLIST.write().await.insert(ip.to_owned());
-                }
-            }
+            res = ip_list_add(&BLACKLIST, BLACKLIST_FILE, fds.next(), fds).await;
         }
         Some("blacklist-remove" | "br") => {
-            if let Some(ip) = fds.next() {
-                if ip == "all" {
-                    BLACKLIST.write().await.clear();
-                } else {
-                    for ip in ip.split('|') {
-                        BLACKLIST.write().await.remove(ip);
-                    }
-                }
-            }
+            res = ip_list_remove(&BLACKLIST, BLACKLIST_FILE, fds.next()).await;
         }
         Some("blacklist" | "b") => {
-            if let Some(ip) = fds.next() {
-                res = format!("{}\n", BLACKLIST.read().await.get(ip).is_some());
-            } else {
-                for ip in BLACKLIST.read().await.clone().into_iter() {
-                    let _ = writeln!(res, "{ip}");
-                }
-            }
+            res = ip_list_show(&BLACKLIST, fds.next()).await;
         }
         Some("blocklist-add" | "Ba") => {
-            if let Some(ip) = fds.next() {
-                for ip in ip.split('|') {
-                    BLOCKLIST.write().await.insert(ip.to_owned());
-                }
-            }
+            res = ip_list_add(&BLOCKLIST, BLOCKLIST_FILE, fds.next(), fds).await;
         }
         Some("blocklist-remove" | "Br") => {
-            if let Some(ip) = fds.next() {
-                if ip == "all" {
-                    BLOCKLIST.write().await.clear();
-                } else {
-                    for ip in ip.split('|') {
-                        BLOCKLIST.write().await.remove(ip);
-                    }
-                }
-            }
+            res = ip_list_remove(&BLOCKLIST, BLOCKLIST_FILE, fds.next()).await;
         }
         Some("blocklist" | "B") => {
-            if let Some(ip) = fds.next() {
-                res = format!("{}\n", BLOCKLIST.read().await.get(ip).is_some());
-            } else {
-                for ip in BLOCKLIST.read().await.clone().into_iter() {
-                    let _ = writeln!(res, "{ip}");
-                }
-            }
+            res = ip_list_show(&BLOCKLIST, fds.next()).await;
         }
         Some("downgrade-threshold" | "dt") => {
             if let Some(v) = fds.next() {
@@ -260,6 +618,42 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 res = format!("{}s\n", DOWNGRADE_START_CHECK.load(Ordering::SeqCst) / 1000);
             }
         }
+        Some("downgrade-policy" | "dp") => match fds.next() {
+            Some("average") => CONGESTION_POLICY.store(false, Ordering::SeqCst),
+            Some("congestion") => CONGESTION_POLICY.store(true, Ordering::SeqCst),
+            Some(_) => {}
+            None => {
+                res = if CONGESTION_POLICY.load(Ordering::SeqCst) {
+                    let share = FAIR_SHARE.load(Ordering::SeqCst);
+                    if !CONGESTED.load(Ordering::SeqCst) {
+                        "congestion, not congested\n".to_owned()
+                    } else if share == usize::MAX {
+                        "congestion, congested\n".to_owned()
+                    } else {
+                        format!(
+                            "congestion, congested, fair share {:.2}Mb/s\n",
+                            share as f64 / 1024. / 1024.
+                        )
+                    }
+                } else {
+                    "average\n".to_owned()
+                };
+            }
+        },
+        Some("congestion-watermark" | "cw") => {
+            if let Some(v) = fds.next() {
+                if let Ok(v) = v.parse::<f64>() {
+                    if v > 0. && v <= 1. {
+                        CONGESTION_WATERMARK_100.store((v * 100.) as _, Ordering::SeqCst);
+                    }
+                }
+            } else {
+                res = format!(
+                    "{}\n",
+                    CONGESTION_WATERMARK_100.load(Ordering::SeqCst) as f64 / 100.
+                );
+            }
+        }
         Some("limit-speed" | "ls") => {
             if let Some(v) = fds.next() {
                 if let Ok(v) = v.parse::<f64>() {
@@ -327,6 +721,43 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 );
             }
         }
+        Some("quota" | "q") => {
+            res = quota::show();
+        }
+        Some("reload-config" | "rc") => {
+            res = reload(&limiter).await;
+        }
+        Some("drain" | "dr") => {
+            match fds.next() {
+                Some("-") => {
+                    if DRAINING.swap(false, Ordering::SeqCst) {
+                        log::info!("Draining cancelled");
+                    }
+                }
+                Some(v) => {
+                    if let Ok(v) = v.parse::<u64>() {
+                        start_drain(v);
+                    }
+                }
+                None => {
+                    if !DRAINING.load(Ordering::SeqCst) {
+                        start_drain(drain_timeout());
+                    }
+                }
+            }
+            if DRAINING.load(Ordering::SeqCst) {
+                res = format!(
+                    "draining, {} active, {} pending, {}s left\n",
+                    USAGE.read().await.len(),
+                    PEERS.lock().await.len(),
+                    DRAIN_DEADLINE
+                        .load(Ordering::SeqCst)
+                        .saturating_sub(crate::common::now())
+                );
+            } else {
+                res = "not draining\n".to_owned();
+            }
+        }
         _ => {}
     }
     res
@@ -337,6 +768,9 @@ async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str) {
     let limiter = <Limiter>::new(TOTAL_BANDWIDTH.load(Ordering::SeqCst) as _);
     loop {
         tokio::select! {
+            _ = RELOAD.notified() => {
+                reload(&limiter).await;
+            }
             res = listener.accept() => {
                 match res {
                     Ok((stream, addr))  => {
@@ -387,9 +821,9 @@ async fn handle_connection(
         });
         return;
     }
-    let ip = ip.to_string();
-    if BLOCKLIST.read().await.get(&ip).is_some() {
+    if BLOCKLIST.read().await.contains(ip) {
         log::info!("{} blocked", ip);
+        BLOCKLIST_HITS.inc();
         return;
     }
     let key = key.to_owned();
@@ -432,46 +866,144 @@ async fn make_pair(
             }
             Ok(response)
         };
+        let stream = BufReader::with_capacity(WS_READ_BUFFER, stream);
         let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
-        make_pair_(ws_stream, addr, key, limiter).await;
+        make_pair_(RelayStream::Ws(Box::new(ws_stream)), addr, key, limiter).await;
     } else {
-        make_pair_(FramedStream::from(stream, addr), addr, key, limiter).await;
+        let stream = RelayStream::Tcp(Framed::new(stream, BytesCodec::new()));
+        make_pair_(stream, addr, key, limiter).await;
     }
     Ok(())
 }
 
-async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limiter: Limiter) {
+async fn make_pair_(stream: RelayStream, addr: SocketAddr, key: &str, limiter: Limiter) {
     let mut stream = stream;
     if let Ok(Some(Ok(bytes))) = timeout(30_000, stream.recv()).await {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
-            if let Some(rendezvous_message::Union::RequestRelay(rf)) = msg_in.union {
+            if let Some(rendezvous_message::Union::Hc(hc)) = msg_in.union {
+                if !key.is_empty() && hc.token != key {
+                    log::debug!("Health check from {} - invalid key", addr);
+                    return;
+                }
+                let status = relay_status().await;
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_hc(HealthCheck {
+                    token: status.to_token(),
+                    ..Default::default()
+                });
+                if let Ok(bytes) = msg_out.write_to_bytes() {
+                    allow_err!(stream.send_raw(bytes.into()).await);
+                }
+            } else if let Some(rendezvous_message::Union::RequestRelay(rf)) = msg_in.union {
                 if !key.is_empty() && rf.licence_key != key {
                     log::warn!("Relay authentication failed from {} - invalid key", addr);
+                    audit::record(audit::event(
+                        audit::RELAY_PAIR,
+                        addr,
+                        &rf.id,
+                        "LICENSE_MISMATCH",
+                    ));
                     return;
                 }
                 if !rf.uuid.is_empty() {
-                    let mut peer = PEERS.lock().await.remove(&rf.uuid);
-                    if let Some(peer) = peer.as_mut() {
+                    let peer = PEERS.lock().await.remove(&rf.uuid);
+                    if let Some(mut peer) = peer {
                         log::info!("Relayrequest {} from {} got paired", rf.uuid, addr);
+                        // only the side that asked for the connection sends the ID
+                        let target_id = if rf.id.is_empty() { &peer.id } else { &rf.id };
+                        let event = |kind: &str, outcome: &str| AuditEvent {
+                            peer_ip: Some(hbb_common::try_into_v4(peer.addr).ip().to_string()),
+                            uuid: Some(rf.uuid.clone()),
+                            ..audit::event(kind, addr, target_id, outcome)
+                        };
+                        let quota = quota::Session::new(
+                            [&rf.licence_key, &peer.licence_key],
+                            &rf.uuid,
+                            target_id,
+                        );
+                        if quota.over() == Some(quota::Over::Refuse) {
+                            log::info!(
+                                "Relay request {} from {} refused, over quota",
+                                rf.uuid,
+                                addr
+                            );
+                            QUOTA_REFUSED.inc();
+                            audit::record(event(audit::RELAY_PAIR, "QUOTA_EXCEEDED"));
+                            return;
+                        }
+                        RELAY_SESSIONS.inc();
+                        audit::record(event(audit::RELAY_PAIR, "OK"));
+                        let started = std::time::Instant::now();
+                        let start_time = chrono::Utc::now().naive_utc();
                         let id = format!("{}:{}", addr.ip(), addr.port());
                         USAGE.write().await.insert(id.clone(), Default::default());
-                        if !stream.is_ws() && !peer.is_ws() {
-                            peer.set_raw();
+                        if !stream.is_ws() && !peer.stream.is_ws() {
+                            peer.stream.set_raw();
                             stream.set_raw();
                             log::info!("Both are raw");
                         }
-                        if let Err(err) = relay(addr, &mut stream, peer, limiter, id.clone()).await
-                        {
+                        let mut totals = Totals::default();
+                        let res = relay(
+                            addr,
+                            &mut stream,
+                            &mut peer.stream,
+                            limiter,
+                            id.clone(),
+                            quota,
+                            &mut totals,
+                        )
+                        .await;
+                        let outcome = if let Err(err) = res {
                             log::info!("Relay of {} closed: {}", addr, err);
+                            err.to_string()
                         } else {
                             log::info!("Relay of {} closed", addr);
-                        }
+                            "CLOSED".to_owned()
+                        };
                         USAGE.write().await.remove(&id);
+                        audit::record(AuditEvent {
+                            duration_ms: Some(started.elapsed().as_millis() as _),
+                            bytes: Some((totals.to_peer + totals.from_peer) as _),
+                            ..event(audit::RELAY_CLOSE, &outcome)
+                        });
+                        accounting::record(RelaySession {
+                            start_time,
+                            end_time: chrono::Utc::now().naive_utc(),
+                            uuid: rf.uuid.clone(),
+                            target_id: target_id.clone(),
+                            addr: hbb_common::try_into_v4(addr).to_string(),
+                            peer_addr: hbb_common::try_into_v4(peer.addr).to_string(),
+                            bytes_to_peer: totals.to_peer as _,
+                            bytes_from_peer: totals.from_peer as _,
+                            peak_speed: totals.highest as _,
+                            downgraded: totals.downgraded,
+                            blacklisted: totals.blacklisted,
+                            throttled: totals.throttled,
+                        });
+                    } else if DRAINING.load(Ordering::SeqCst) {
+                        log::info!("Relay request {} from {} refused, draining", rf.uuid, addr);
+                        audit::record(AuditEvent {
+                            uuid: Some(rf.uuid.clone()),
+                            ..audit::event(audit::RELAY_PAIR, addr, &rf.id, "DRAINING")
+                        });
                     } else {
                         log::info!("New relay request {} from {}", rf.uuid, addr);
-                        PEERS.lock().await.insert(rf.uuid.clone(), Box::new(stream));
+                        PEERS.lock().await.insert(
+                            rf.uuid.clone(),
+                            Pending {
+                                stream,
+                                addr,
+                                id: rf.id.clone(),
+                                licence_key: rf.licence_key.clone(),
+                            },
+                        );
                         sleep(30.).await;
-                        PEERS.lock().await.remove(&rf.uuid);
+                        if PEERS.lock().await.remove(&rf.uuid).is_some() {
+                            audit::record(AuditEvent {
+                                uuid: Some(rf.uuid.clone()),
+                                ..audit::event(audit::RELAY_PAIR, addr, &rf.id, "TIMEOUT")
+                            });
+                        }
                     }
                 }
             }
@@ -479,44 +1011,322 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
     }
 }
 
+/// Max-min fair share of `capacity` between sessions relaying at `speeds`: the
+/// most any of them may use for the others to keep what they use, None if all
+/// fit. Sessions at 90% of the `previous` share or more are taken to be held
+/// back by it and to want more.
+fn fair_share(speeds: &mut [usize], capacity: usize, previous: usize) -> Option<usize> {
+    for v in speeds.iter_mut() {
+        if v.saturating_mul(10) >= previous.saturating_mul(9) {
+            *v = usize::MAX;
+        }
+    }
+    speeds.sort_unstable();
+    let mut left = capacity;
+    for (i, v) in speeds.iter().enumerate() {
+        let share = left / (speeds.len() - i);
+        if *v > share {
+            return Some(share);
+        }
+        left -= v;
+    }
+    None
+}
+
+/// With DOWNGRADE_POLICY=congestion, updates once a second whether the
+/// sessions together relay more than CONGESTION_WATERMARK of TOTAL_BANDWIDTH,
+/// until they relay less than UNCONGESTED_100 percent of that, and the fair
+/// share of the watermark that sessions are throttled to meanwhile.
+async fn watch_congestion() {
+    let mut timer = interval(Duration::from_secs(1));
+    loop {
+        timer.tick().await;
+        let policy = CONGESTION_POLICY.load(Ordering::SeqCst);
+        // of the last second, in bit/s
+        let mut speeds: Vec<usize> = if policy {
+            USAGE.read().await.values().map(|x| x.3 * 1000).collect()
+        } else {
+            Vec::new()
+        };
+        let watermark = TOTAL_BANDWIDTH.load(Ordering::SeqCst)
+            * CONGESTION_WATERMARK_100.load(Ordering::SeqCst)
+            / 100;
+        let load: usize = speeds.iter().sum();
+        let congested = policy
+            && if CONGESTED.load(Ordering::SeqCst) {
+                load * 100 >= watermark * UNCONGESTED_100
+            } else {
+                load > watermark
+            };
+        if CONGESTED.swap(congested, Ordering::SeqCst) != congested {
+            let state = if congested {
+                "Congested"
+            } else {
+                "Congestion over"
+            };
+            log::info!("{} at {:.2}Mb/s", state, load as f64 / 1024. / 1024.);
+        }
+        let share = if congested {
+            let previous = FAIR_SHARE.load(Ordering::SeqCst);
+            // all fit at the previous share, lifting it would congest again
+            fair_share(&mut speeds, watermark, previous).unwrap_or(previous)
+        } else {
+            usize::MAX
+        };
+        FAIR_SHARE.store(share, Ordering::SeqCst);
+    }
+}
+
+async fn relay_status() -> RelayStatus {
+    // per connection speed in kbit/s, updated every second while relaying
+    let bandwidth: usize = USAGE.read().await.values().map(|x| x.3).sum();
+    RelayStatus {
+        active: USAGE.read().await.len(),
+        pending: PEERS.lock().await.len(),
+        bandwidth: bandwidth * 1000,
+        total_bandwidth: TOTAL_BANDWIDTH.load(Ordering::SeqCst),
+        draining: DRAINING.load(Ordering::SeqCst),
+    }
+}
+
+/// What a relay session forwarded and how it was limited.
+#[derive(Default)]
+struct Totals {
+    to_peer: usize,   // in bytes, from the connection to its peer
+    from_peer: usize, // in bytes
+    highest: usize,   // in kbit/s, of a second
+    downgraded: bool,
+    blacklisted: bool,
+    throttled: bool,
+}
+
+/// Bandwidth accounting and checks of a relay connection, whichever way its
+/// bytes are forwarded.
+struct Meter<'a> {
+    ip: IpAddr,
+    id: String,
+    tm: std::time::Instant,
+    elapsed: usize,
+    total: usize,
+    total_s: usize,
+    highest_s: usize,
+    downgrade: bool,
+    blacked: bool,
+    throttled: bool,
+    speed: usize, // in bit/s, of limiter unless congested
+    congested: Option<std::time::Instant>,
+    limiter: Limiter,
+    blacklist_limiter: Limiter,
+    total_limiter: Limiter,
+    downgrade_threshold: usize, // in bit/ms
+    last_recv_time: std::time::Instant,
+    quota: quota::Session,
+    totals: &'a mut Totals,
+}
+
+impl<'a> Meter<'a> {
+    fn new(
+        addr: SocketAddr,
+        total_limiter: Limiter,
+        id: String,
+        quota: quota::Session,
+        totals: &'a mut Totals,
+    ) -> Self {
+        let speed = SINGLE_BANDWIDTH.load(Ordering::SeqCst);
+        let speed = quota.speed().map(|x| x.min(speed)).unwrap_or(speed);
+        let sb = speed as f64;
+        let throttled = quota.over() == Some(quota::Over::Throttle);
+        if throttled {
+            QUOTA_THROTTLED.inc();
+        }
+        totals.throttled = throttled;
+        Self {
+            ip: addr.ip(),
+            id,
+            tm: std::time::Instant::now(),
+            elapsed: 0,
+            total: 0,
+            total_s: 0,
+            highest_s: 0,
+            downgrade: false,
+            blacked: false,
+            throttled,
+            speed,
+            congested: None,
+            limiter: <Limiter>::new(sb),
+            blacklist_limiter: <Limiter>::new(LIMIT_SPEED.load(Ordering::SeqCst) as _),
+            total_limiter,
+            downgrade_threshold: (sb * DOWNGRADE_THRESHOLD_100.load(Ordering::SeqCst) as f64
+                / 100.
+                / 1000.) as usize,
+            last_recv_time: std::time::Instant::now(),
+            quota,
+            totals,
+        }
+    }
+
+    /// Waits until `n` more bytes may be forwarded, from the connection to its
+    /// peer or back, and counts them.
+    async fn consume(&mut self, n: usize, to_peer: bool) {
+        self.last_recv_time = std::time::Instant::now();
+        let nb = n * 8;
+        if self.blacked || self.downgrade || self.throttled {
+            self.blacklist_limiter.consume(nb).await;
+        } else {
+            self.limiter.consume(nb).await;
+        }
+        self.total_limiter.consume(nb).await;
+        RELAYED_BYTES.add(n as _);
+        if to_peer {
+            self.totals.to_peer += n;
+        } else {
+            self.totals.from_peer += n;
+        }
+        self.total += nb;
+        self.total_s += nb;
+    }
+
+    /// Nothing received for 30 seconds.
+    fn idle(&self) -> bool {
+        self.last_recv_time.elapsed().as_secs() > 30
+    }
+
+    /// Updates the usage and the limits once a second, false if the IP got
+    /// blocked or a bandwidth rule refuses more.
+    async fn update(&mut self) -> bool {
+        let n = self.tm.elapsed().as_millis() as usize;
+        if n < 1_000 {
+            return true;
+        }
+        let ip = self.ip;
+        if BLOCKLIST.read().await.contains(ip) {
+            log::info!("{} blocked", ip);
+            BLOCKLIST_HITS.inc();
+            return false;
+        }
+        let was_blacked = self.blacked;
+        self.blacked = BLACKLIST.read().await.contains(ip);
+        if self.blacked && !was_blacked {
+            BLACKLIST_HITS.inc();
+            self.totals.blacklisted = true;
+        }
+        self.tm = std::time::Instant::now();
+        let speed = self.total_s / n;
+        if speed > self.highest_s {
+            self.highest_s = speed;
+            self.totals.highest = speed;
+        }
+        self.elapsed += n;
+        USAGE.write().await.insert(
+            self.id.clone(),
+            (
+                self.elapsed as _,
+                self.total as _,
+                self.highest_s as _,
+                speed as _,
+            ),
+        );
+        self.quota.charge((self.total_s / 8) as _);
+        self.total_s = 0;
+        match self.quota.over() {
+            Some(quota::Over::Refuse) => {
+                log::info!("{} closed, over quota", self.id);
+                QUOTA_REFUSED.inc();
+                return false;
+            }
+            Some(quota::Over::Throttle) if !self.throttled => {
+                self.throttled = true;
+                self.totals.throttled = true;
+                QUOTA_THROTTLED.inc();
+                log::info!("Throttle {}, over quota", self.id);
+            }
+            _ => {}
+        }
+        if CONGESTION_POLICY.load(Ordering::SeqCst) || self.congested.is_some() {
+            self.update_congested(speed * 1000);
+        } else if self.elapsed > DOWNGRADE_START_CHECK.load(Ordering::SeqCst)
+            && !self.downgrade
+            && self.total > self.elapsed * self.downgrade_threshold
+        {
+            self.downgrade = true;
+            self.totals.downgraded = true;
+            DOWNGRADED.inc();
+            log::info!(
+                "Downgrade {}, exceed downgrade threshold {}bit/ms in {}ms",
+                self.id,
+                self.downgrade_threshold,
+                self.elapsed
+            );
+        }
+        true
+    }
+
+    /// Throttles the session to the fair share while the relay is congested if
+    /// it relays more, `speed` in bit/s, following the share as it changes, and
+    /// promotes it back once the congestion is over or the policy changed.
+    fn update_congested(&mut self, speed: usize) {
+        let share = FAIR_SHARE.load(Ordering::SeqCst);
+        match self.congested {
+            None if share < self.speed && speed > share => {
+                self.congested = Some(std::time::Instant::now());
+                self.totals.downgraded = true;
+                DOWNGRADED.inc();
+                log::info!(
+                    "Downgrade {}, congested, fair share {}bit/s",
+                    self.id,
+                    share
+                );
+            }
+            None => return,
+            Some(since) if !CONGESTED.load(Ordering::SeqCst) => {
+                if since.elapsed() >= PROMOTE_AFTER {
+                    self.congested = None;
+                    self.limiter.set_speed_limit(self.speed as _);
+                    PROMOTED.inc();
+                    log::info!("Promote {}, congestion over", self.id);
+                }
+                return;
+            }
+            Some(_) => {}
+        }
+        self.limiter.set_speed_limit(share.min(self.speed) as _);
+    }
+}
+
+impl Drop for Meter<'_> {
+    fn drop(&mut self) {
+        // since the last update
+        self.quota.charge((self.total_s / 8) as _);
+    }
+}
+
 async fn relay(
     addr: SocketAddr,
-    stream: &mut impl StreamTrait,
-    peer: &mut Box<dyn StreamTrait>,
+    stream: &mut RelayStream,
+    peer: &mut RelayStream,
     total_limiter: Limiter,
     id: String,
+    quota: quota::Session,
+    totals: &mut Totals,
 ) -> ResultType<()> {
-    let ip = addr.ip().to_string();
-    let mut tm = std::time::Instant::now();
-    let mut elapsed = 0;
-    let mut total = 0;
-    let mut total_s = 0;
-    let mut highest_s = 0;
-    let mut downgrade: bool = false;
-    let mut blacked: bool = false;
-    let sb = SINGLE_BANDWIDTH.load(Ordering::SeqCst) as f64;
-    let limiter = <Limiter>::new(sb);
-    let blacklist_limiter = <Limiter>::new(LIMIT_SPEED.load(Ordering::SeqCst) as _);
-    let downgrade_threshold =
-        (sb * DOWNGRADE_THRESHOLD_100.load(Ordering::SeqCst) as f64 / 100. / 1000.) as usize; // in bit/ms
+    let mut meter = Meter::new(addr, total_limiter, id, quota, totals);
+    #[cfg(target_os = "linux")]
+    if let (RelayStream::Tcp(stream), RelayStream::Tcp(peer)) = (&mut *stream, &mut *peer) {
+        if crate::common::get_arg("RELAY_SPLICE").to_uppercase() != "N" {
+            match crate::splice::Pipe::new().and_then(|x| Ok((x, crate::splice::Pipe::new()?))) {
+                Ok(pipes) => return relay_splice(stream, peer, pipes, &mut meter).await,
+                Err(err) => log::warn!("Failed to create pipes for splice: {}", err),
+            }
+        }
+    }
     let mut timer = interval(Duration::from_secs(3));
-    let mut last_recv_time = std::time::Instant::now();
     loop {
         tokio::select! {
             res = peer.recv() => {
                 if let Some(Ok(bytes)) = res {
-                    last_recv_time = std::time::Instant::now();
-                    let nb = bytes.len() * 8;
-                    if blacked || downgrade {
-                        blacklist_limiter.consume(nb).await;
-                    } else {
-                        limiter.consume(nb).await;
-                    }
-                    total_limiter.consume(nb).await;
-                    total += nb;
-                    total_s += nb;
+                    meter.consume(bytes.len(), false).await;
                     if !bytes.is_empty() {
-                        stream.send_raw(bytes.into()).await?;
+                        stream.send_raw(bytes).await?;
                     }
                 } else {
                     break;
@@ -524,61 +1334,79 @@ async fn relay(
             },
             res = stream.recv() => {
                 if let Some(Ok(bytes)) = res {
-                    last_recv_time = std::time::Instant::now();
-                    let nb = bytes.len() * 8;
-                    if blacked || downgrade {
-                        blacklist_limiter.consume(nb).await;
-                    } else {
-                        limiter.consume(nb).await;
-                    }
-                    total_limiter.consume(nb).await;
-                    total += nb;
-                    total_s += nb;
+                    meter.consume(bytes.len(), true).await;
                     if !bytes.is_empty() {
-                        peer.send_raw(bytes.into()).await?;
+                        peer.send_raw(bytes).await?;
                     }
                 } else {
                     break;
                 }
             },
             _ = timer.tick() => {
-                if last_recv_time.elapsed().as_secs() > 30 {
+                if meter.idle() {
                     bail!("Timeout");
                 }
             }
         }
+        if !meter.update().await {
+            break;
+        }
+    }
+    Ok(())
+}
 
-        let n = tm.elapsed().as_millis() as usize;
-        if n >= 1_000 {
-            if BLOCKLIST.read().await.get(&ip).is_some() {
-                log::info!("{} blocked", ip);
-                break;
-            }
-            blacked = BLACKLIST.read().await.get(&ip).is_some();
-            tm = std::time::Instant::now();
-            let speed = total_s / n;
-            if speed > highest_s {
-                highest_s = speed;
-            }
-            elapsed += n;
-            USAGE.write().await.insert(
-                id.clone(),
-                (elapsed as _, total as _, highest_s as _, speed as _),
-            );
-            total_s = 0;
-            if elapsed > DOWNGRADE_START_CHECK.load(Ordering::SeqCst)
-                && !downgrade
-                && total > elapsed * downgrade_threshold
-            {
-                downgrade = true;
-                log::info!(
-                    "Downgrade {}, exceed downgrade threshold {}bit/ms in {}ms",
-                    id,
-                    downgrade_threshold,
-                    elapsed
-                );
+/// Like the loop of [`relay`] for two raw TCP connections, the bytes spliced
+/// through `pipes` in chunks metered as they are received.
+#[cfg(target_os = "linux")]
+async fn relay_splice(
+    stream: &mut TcpFramed,
+    peer: &mut TcpFramed,
+    (mut to_peer, mut to_stream): (crate::splice::Pipe, crate::splice::Pipe),
+    meter: &mut Meter<'_>,
+) -> ResultType<()> {
+    SPLICED_SESSIONS.inc();
+    // received along with the relay requests
+    let bytes = stream.read_buffer_mut().split().freeze();
+    if !bytes.is_empty() {
+        meter.consume(bytes.len(), true).await;
+        peer.send(bytes).await?;
+    }
+    let bytes = peer.read_buffer_mut().split().freeze();
+    if !bytes.is_empty() {
+        meter.consume(bytes.len(), false).await;
+        stream.send(bytes).await?;
+    }
+    let (stream, peer) = (stream.get_ref(), peer.get_ref());
+    let mut timer = interval(Duration::from_secs(3));
+    loop {
+        tokio::select! {
+            res = to_stream.fill(peer) => {
+                match res {
+                    Ok(n) if n > 0 => {
+                        meter.consume(n, false).await;
+                        to_stream.drain(stream).await?;
+                    }
+                    _ => break,
+                }
+            },
+            res = to_peer.fill(stream) => {
+                match res {
+                    Ok(n) if n > 0 => {
+                        meter.consume(n, true).await;
+                        to_peer.drain(peer).await?;
+                    }
+                    _ => break,
+                }
+            },
+            _ = timer.tick() => {
+                if meter.idle() {
+                    bail!("Timeout");
+                }
             }
         }
+        if !meter.update().await {
+            break;
+        }
     }
     Ok(())
 }
@@ -604,62 +1432,101 @@ fn get_server_sk(key: &str) -> String {
     key
 }
 
-#[async_trait]
-trait StreamTrait: Send + Sync + 'static {
-    async fn recv(&mut self) -> Option<Result<BytesMut, Error>>;
-    async fn send_raw(&mut self, bytes: Bytes) -> ResultType<()>;
-    fn is_ws(&self) -> bool;
-    fn set_raw(&mut self);
+/// A relayed connection, an enum rather than a trait object so that no future
+/// is boxed per frame.
+enum RelayStream {
+    Tcp(TcpFramed),
+    Ws(Box<tokio_tungstenite::WebSocketStream<BufReader<TcpStream>>>),
 }
 
-#[async_trait]
-impl StreamTrait for FramedStream {
-    async fn recv(&mut self) -> Option<Result<BytesMut, Error>> {
-        self.next().await
+impl RelayStream {
+    /// The next frame, handed to the peer as is.
+    async fn recv(&mut self) -> Option<Result<Bytes, Error>> {
+        match self {
+            Self::Tcp(stream) => Some(stream.next().await?.map(BytesMut::freeze)),
+            Self::Ws(stream) => match stream.next().await? {
+                // takes over the buffer of the message
+                Ok(tungstenite::Message::Binary(bytes)) => Some(Ok(bytes.into())),
+                Ok(_) => Some(Ok(Bytes::new())),
+                Err(err) => Some(Err(Error::new(std::io::ErrorKind::Other, err.to_string()))),
+            },
+        }
     }
 
     async fn send_raw(&mut self, bytes: Bytes) -> ResultType<()> {
-        self.send_bytes(bytes).await
+        match self {
+            Self::Tcp(stream) => Ok(stream.send(bytes).await?),
+            // no copy if `bytes` is the only reference to its buffer, e.g.
+            // received from another WebSocket, tungstenite needs a Vec of its own
+            Self::Ws(stream) => Ok(stream
+                .send(tungstenite::Message::Binary(bytes.into()))
+                .await?),
+        }
     }
 
     fn is_ws(&self) -> bool {
-        false
+        matches!(self, Self::Ws(_))
     }
 
     fn set_raw(&mut self) {
-        self.set_raw();
+        if let Self::Tcp(stream) = self {
+            stream.codec_mut().set_raw();
+        }
     }
 }
 
-#[async_trait]
-impl StreamTrait for tokio_tungstenite::WebSocketStream<TcpStream> {
-    async fn recv(&mut self) -> Option<Result<BytesMut, Error>> {
-        if let Some(msg) = self.next().await {
-            match msg {
-                Ok(msg) => {
-                    match msg {
-                        tungstenite::Message::Binary(bytes) => {
-                            Some(Ok(bytes[..].into())) // to-do: poor performance
-                        }
-                        _ => Some(Ok(BytesMut::new())),
-                    }
-                }
-                Err(err) => Some(Err(Error::new(std::io::ErrorKind::Other, err.to_string()))),
-            }
-        } else {
-            None
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    async fn send_raw(&mut self, bytes: Bytes) -> ResultType<()> {
-        Ok(self
-            .send(tungstenite::Message::Binary(bytes.to_vec()))
-            .await?) // to-do: poor performance
+    #[test]
+    fn shares_bandwidth_fairly() {
+        // everything fits
+        assert_eq!(fair_share(&mut [10, 20, 30], 100, usize::MAX), None);
+        // the light sessions keep theirs, the heavy ones split the rest
+        assert_eq!(fair_share(&mut [10, 80, 60], 100, usize::MAX), Some(45));
+        assert_eq!(fair_share(&mut [50, 50, 50, 50], 100, usize::MAX), Some(25));
+        // held back at the previous share of 45, so they may want more
+        assert_eq!(fair_share(&mut [10, 45, 41], 100, 45), Some(45));
+        assert_eq!(fair_share(&mut [30, 45, 45], 100, 45), Some(35));
+        assert_eq!(fair_share(&mut [], 100, usize::MAX), None);
     }
 
-    fn is_ws(&self) -> bool {
-        true
+    #[test]
+    fn follows_the_fair_share() {
+        let mut totals = Totals::default();
+        let addr = "192.0.2.1:50000".parse().unwrap();
+        let (limiter, id) = (<Limiter>::new(f64::INFINITY), "id".to_owned());
+        let mut meter = Meter::new(addr, limiter, id, Default::default(), &mut totals);
+        meter.speed = 100;
+        CONGESTED.store(true, Ordering::SeqCst);
+        FAIR_SHARE.store(200, Ordering::SeqCst);
+        meter.update_congested(80);
+        assert!(meter.congested.is_none());
+        FAIR_SHARE.store(40, Ordering::SeqCst);
+        meter.update_congested(80);
+        assert!(meter.congested.is_some());
+        assert_eq!(meter.limiter.speed_limit(), 40.);
+        // re-applied as the share changes, at most the speed of the session
+        FAIR_SHARE.store(60, Ordering::SeqCst);
+        meter.update_congested(40);
+        assert_eq!(meter.limiter.speed_limit(), 60.);
+        FAIR_SHARE.store(200, Ordering::SeqCst);
+        meter.update_congested(60);
+        assert_eq!(meter.limiter.speed_limit(), 100.);
+        // kept until promoted a while after the congestion
+        FAIR_SHARE.store(40, Ordering::SeqCst);
+        meter.update_congested(100);
+        CONGESTED.store(false, Ordering::SeqCst);
+        FAIR_SHARE.store(usize::MAX, Ordering::SeqCst);
+        meter.update_congested(40);
+        assert!(meter.congested.is_some());
+        assert_eq!(meter.limiter.speed_limit(), 40.);
+        meter.congested = Some(std::time::Instant::now() - PROMOTE_AFTER);
+        meter.update_congested(40);
+        assert!(meter.congested.is_none());
+        assert_eq!(meter.limiter.speed_limit(), 100.);
+        drop(meter);
+        assert!(totals.downgraded);
     }
-
-    fn set_raw(&mut self) {}
 }
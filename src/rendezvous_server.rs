@@ -1,5 +1,12 @@
+use crate::access::{self, List};
+use crate::audit;
 use crate::common::*;
+use crate::database::AuditEvent;
+use crate::geo;
+use crate::janitor;
+use crate::metrics::{Counter, Encoder};
 use crate::peer::*;
+use crate::sharded::Sharded;
 use hbb_common::{
     allow_err, bail,
     bytes::{Bytes, BytesMut},
@@ -13,7 +20,9 @@ use hbb_common::{
     log,
     protobuf::{Message as _, MessageField},
     rendezvous_proto::{
-        register_pk_response::Result::{TOO_FREQUENT, UUID_MISMATCH},
+        register_pk_response::Result::{
+            INVALID_ID_FORMAT, NOT_SUPPORT, TOO_FREQUENT, UUID_MISMATCH,
+        },
         *,
     },
     tcp::FramedStream,
@@ -21,8 +30,8 @@ use hbb_common::{
     tokio::{
         self,
         io::{AsyncReadExt, AsyncWriteExt},
-        net::{TcpListener, TcpStream},
-        sync::{mpsc, Mutex},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::{mpsc, oneshot, watch, Mutex},
         time::{interval, Duration},
     },
     tokio_util::codec::Framed,
@@ -40,35 +49,95 @@ use std::{
     time::Instant,
 };
 
-#[derive(Clone, Debug)]
-enum Data {
+#[derive(Debug)]
+pub(crate) enum Data {
     Msg(Box<RendezvousMessage>, SocketAddr),
     RelayServers0(String),
     RelayServers(RelayServers),
+    QueryRelayServers(oneshot::Sender<(RelayServers, RelayServers)>),
+    ReloadConfig(Option<oneshot::Sender<String>>),
+    ConfigureUpdate(ConfigUpdate),
+    Shutdown,
 }
 
-const REG_TIMEOUT: i64 = 30_000;
 type TcpStreamSink = SplitSink<Framed<TcpStream, BytesCodec>, Bytes>;
 type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, tungstenite::Message>;
 enum Sink {
     TcpStream(TcpStreamSink),
     Ws(WsSink),
 }
-type Sender = mpsc::UnboundedSender<Data>;
+pub(crate) type Sender = mpsc::UnboundedSender<Data>;
 type Receiver = mpsc::UnboundedReceiver<Data>;
 static ROTATION_RELAY_SERVER: AtomicUsize = AtomicUsize::new(0);
-type RelayServers = Vec<String>;
+pub(crate) type RelayServers = Vec<String>;
 const CHECK_RELAY_TIMEOUT: u64 = 3_000;
-static ALWAYS_USE_RELAY: AtomicBool = AtomicBool::new(false);
+const SHUTDOWN_TIMEOUT: u64 = 10; // in seconds
+
+// config only read at startup
+const RESTART_ARGS: &[&str] = &[
+    "PORT",
+    "BIND",
+    "KEY",
+    "RMEM",
+    "SERIAL",
+    "RENDEZVOUS_SERVERS",
+    "SOFTWARE_URL",
+    "TEST_HBBS",
+    "DB_URL",
+    "MAX_DATABASE_CONNECTIONS",
+    "AUDIT_LOG",
+    "LAST_SEEN_FLUSH_INTERVAL",
+    "PEER_REFRESH_INTERVAL",
+    "UDP_WORKERS",
+    "API_SECRET",
+    "API_PORT",
+    "METRICS_PORT",
+];
+const RELAY_SATURATION: f64 = 0.9;
+const MIN_RELAY_WEIGHT: f64 = 0.05;
+// load reported by the relay servers which answered the last health check
+static RELAY_STATUS: Lazy<std::sync::RwLock<HashMap<String, RelayStatus>>> =
+    Lazy::new(Default::default);
+pub(crate) static ALWAYS_USE_RELAY: AtomicBool = AtomicBool::new(false);
+// only provisioned IDs may register, see `PeerMap::is_provisioned`
+static PRIVATE_MODE: AtomicBool = AtomicBool::new(false);
 
 // Store punch hole requests
 use once_cell::sync::Lazy;
 use tokio::sync::Mutex as TokioMutex; // differentiate if needed
 #[derive(Clone)]
-struct PunchReqEntry { tm: Instant, from_ip: String, to_ip: String, to_id: String }
-static PUNCH_REQS: Lazy<TokioMutex<Vec<PunchReqEntry>>> = Lazy::new(|| TokioMutex::new(Vec::new()));
+pub(crate) struct PunchReqEntry {
+    pub(crate) tm: Instant,
+    pub(crate) from_ip: String,
+    pub(crate) to_ip: String,
+    pub(crate) to_id: String,
+}
+pub(crate) static PUNCH_REQS: Lazy<TokioMutex<Vec<PunchReqEntry>>> =
+    Lazy::new(|| TokioMutex::new(Vec::new()));
 const PUNCH_REQ_DEDUPE_SEC: u64 = 60;
 
+static REGISTER_PEER: Counter = Counter::new();
+static REGISTER_PK: Counter = Counter::new();
+static REGISTER_PK_BLOCKED: Counter = Counter::new();
+static REGISTER_PK_DISABLED: Counter = Counter::new();
+static REGISTER_PEER_DENIED: Counter = Counter::new();
+static REGISTER_PK_DENIED: Counter = Counter::new();
+static REGISTER_PK_UNKNOWN: Counter = Counter::new();
+static PUNCH_HOLE_OK: Counter = Counter::new();
+static PUNCH_HOLE_OFFLINE: Counter = Counter::new();
+static PUNCH_HOLE_ID_NOT_EXIST: Counter = Counter::new();
+static PUNCH_HOLE_LICENSE_MISMATCH: Counter = Counter::new();
+static PUNCH_HOLE_DISABLED: Counter = Counter::new();
+static PUNCH_HOLE_DENIED: Counter = Counter::new();
+
+impl PunchReqEntry {
+    pub(crate) fn time_rfc3339(&self) -> String {
+        let event_system = std::time::SystemTime::now() - self.tm.elapsed();
+        chrono::DateTime::<chrono::Utc>::from(event_system)
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    }
+}
+
 #[derive(Clone)]
 struct Inner {
     serial: i32,
@@ -79,15 +148,49 @@ struct Inner {
     sk: Option<sign::SecretKey>,
 }
 
+// UDP messages queued per worker, newer ones are dropped as by a full socket
+// buffer
+const UDP_QUEUE: usize = 4096;
+static UDP_DROPPED: Counter = Counter::new();
+
+// state of `io_loop` used by the UDP workers
+type WorkerState = (Arc<Inner>, Arc<Vec<String>>, Option<Arc<UdpSocket>>);
+
+/// Handle the UDP messages outside `io_loop`, those from one address in order
+/// by the same worker.
+struct UdpWorkers {
+    queues: Sharded<mpsc::Sender<(BytesMut, SocketAddr)>>,
+    state: watch::Sender<WorkerState>,
+}
+
+impl UdpWorkers {
+    #[inline]
+    fn dispatch(&self, bytes: BytesMut, addr: SocketAddr) {
+        if self.queues.get(&addr).try_send((bytes, addr)).is_err() {
+            UDP_DROPPED.inc();
+        }
+    }
+
+    fn sync(&self, rs: &RendezvousServer) {
+        self.state.send_replace((
+            rs.inner.clone(),
+            rs.rendezvous_servers.clone(),
+            rs.udp.clone(),
+        ));
+    }
+}
+
 #[derive(Clone)]
 pub struct RendezvousServer {
     tcp_punch: Arc<Mutex<HashMap<SocketAddr, Sink>>>,
-    pm: PeerMap,
-    tx: Sender,
+    pub(crate) pm: PeerMap,
+    pub(crate) tx: Sender,
     relay_servers: Arc<RelayServers>,
     relay_servers0: Arc<RelayServers>,
     rendezvous_servers: Arc<Vec<String>>,
     inner: Arc<Inner>,
+    // the UDP listener, which the workers reply on
+    udp: Option<Arc<UdpSocket>>,
 }
 
 enum LoopFailure {
@@ -95,6 +198,7 @@ enum LoopFailure {
     Listener3,
     Listener2,
     Listener,
+    Shutdown,
 }
 
 impl RendezvousServer {
@@ -114,6 +218,8 @@ impl RendezvousServer {
         let nat_port = port - 1;
         let ws_port = port + 2;
         let pm = PeerMap::new().await?;
+        audit::start(pm.db.clone());
+        janitor::start(pm.clone());
         log::info!("serial={}", serial);
         let rendezvous_servers = get_servers(&get_arg("rendezvous-servers"), "rendezvous-servers");
         let mut socket = create_udp_listener(bind_addr, port, rmem).await?;
@@ -123,17 +229,7 @@ impl RendezvousServer {
         if !version.is_empty() {
             log::info!("software_url: {}, version: {}", software_url, version);
         }
-        let mask = get_arg("mask").parse().ok();
-        let local_ip = if mask.is_none() {
-            "".to_owned()
-        } else {
-            get_arg_or(
-                "local-ip",
-                local_ip_address::local_ip()
-                    .map(|x| x.to_string())
-                    .unwrap_or_default(),
-            )
-        };
+        let (mask, local_ip) = get_mask_and_local_ip();
         let mut rs = Self {
             tcp_punch: Arc::new(Mutex::new(HashMap::new())),
             pm,
@@ -149,11 +245,36 @@ impl RendezvousServer {
                 mask,
                 local_ip,
             }),
+            udp: udp_sender(&socket),
         };
         log::info!("mask: {:?}", rs.inner.mask);
         log::info!("local-ip: {:?}", rs.inner.local_ip);
         std::env::set_var("PORT_FOR_API", port.to_string());
         rs.parse_relay_servers(&get_arg("relay-servers"));
+        if let Err(err) = geo::reload() {
+            log::error!("{}", err);
+        }
+        access::load();
+        let api_secret = get_arg("api-secret");
+        if !api_secret.is_empty() {
+            let api_port = get_arg_or("api-port", (port - 2).to_string()).parse::<u16>()?;
+            let rs = rs.clone();
+            tokio::spawn(async move {
+                if let Err(err) = crate::api::start(rs, bind_addr, api_port, api_secret).await {
+                    log::error!("Failed to run api server: {}", err);
+                }
+            });
+        }
+        let metrics_port = get_arg("metrics-port").parse::<u16>().unwrap_or(0);
+        if metrics_port > 0 {
+            let pm = rs.pm.clone();
+            tokio::spawn(async move {
+                let render = move || render_metrics(pm.clone());
+                if let Err(err) = crate::metrics::start(bind_addr, metrics_port, render).await {
+                    log::error!("Failed to run metrics server: {}", err);
+                }
+            });
+        }
         let mut listener = create_tcp_listener(bind_addr, port).await?;
         let mut listener2 = create_tcp_listener(bind_addr, nat_port).await?;
         let mut listener3 = create_tcp_listener(bind_addr, ws_port).await?;
@@ -167,6 +288,10 @@ impl RendezvousServer {
         if get_arg("ALWAYS_USE_RELAY").to_uppercase() == "Y" {
             ALWAYS_USE_RELAY.store(true, Ordering::SeqCst);
         }
+        if get_arg("PRIVATE_MODE").to_uppercase() == "Y" {
+            PRIVATE_MODE.store(true, Ordering::SeqCst);
+            log::info!("PRIVATE_MODE=Y");
+        }
         log::info!(
             "ALWAYS_USE_RELAY={}",
             if ALWAYS_USE_RELAY.load(Ordering::SeqCst) {
@@ -197,46 +322,70 @@ impl RendezvousServer {
                 }
             });
         };
-        let main_task = async move {
-            loop {
-                log::info!("Start");
-                match rs
-                    .io_loop(
-                        &mut rx,
-                        &mut listener,
-                        &mut listener2,
-                        &mut listener3,
-                        &mut socket,
-                        &key,
-                    )
-                    .await
-                {
-                    LoopFailure::UdpSocket => {
-                        drop(socket);
-                        socket = create_udp_listener(bind_addr, port, rmem).await?;
-                    }
-                    LoopFailure::Listener => {
-                        drop(listener);
-                        listener = create_tcp_listener(bind_addr, port).await?;
-                    }
-                    LoopFailure::Listener2 => {
-                        drop(listener2);
-                        listener2 = create_tcp_listener(bind_addr, nat_port).await?;
-                    }
-                    LoopFailure::Listener3 => {
-                        drop(listener3);
-                        listener3 = create_tcp_listener(bind_addr, ws_port).await?;
-                    }
+        let tx = rs.tx.clone();
+        let mut files = config_files();
+        files.extend(List::ALL.map(|x| x.file().to_owned()));
+        tokio::spawn(watch_config(files, move || {
+            tx.send(Data::ReloadConfig(None)).ok();
+        }));
+        let tx = rs.tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = listen_signal().await {
+                log::error!("Failed to listen for signals: {}", err);
+                return;
+            }
+            tx.send(Data::Shutdown).ok();
+        });
+        let workers = rs.spawn_udp_workers(&key);
+        loop {
+            log::info!("Start");
+            match rs
+                .io_loop(
+                    &mut rx,
+                    &mut listener,
+                    &mut listener2,
+                    &mut listener3,
+                    &mut socket,
+                    &key,
+                    &workers,
+                )
+                .await
+            {
+                LoopFailure::UdpSocket => {
+                    drop(socket);
+                    socket = create_udp_listener(bind_addr, port, rmem).await?;
+                    rs.udp = udp_sender(&socket);
+                    workers.sync(&rs);
+                }
+                LoopFailure::Listener => {
+                    drop(listener);
+                    listener = create_tcp_listener(bind_addr, port).await?;
+                }
+                LoopFailure::Listener2 => {
+                    drop(listener2);
+                    listener2 = create_tcp_listener(bind_addr, nat_port).await?;
+                }
+                LoopFailure::Listener3 => {
+                    drop(listener3);
+                    listener3 = create_tcp_listener(bind_addr, ws_port).await?;
                 }
+                LoopFailure::Shutdown => break,
             }
-        };
-        let listen_signal = listen_signal();
-        tokio::select!(
-            res = main_task => res,
-            res = listen_signal => res,
-        )
+        }
+        drop((listener, listener2, listener3));
+        let secs = get_arg("shutdown-timeout")
+            .parse::<u64>()
+            .unwrap_or(SHUTDOWN_TIMEOUT);
+        if timeout(secs * 1000, rs.shutdown(&mut rx, &mut socket))
+            .await
+            .is_err()
+        {
+            log::warn!("Shutdown not finished in {}s", secs);
+        }
+        Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn io_loop(
         &mut self,
         rx: &mut Receiver,
@@ -245,6 +394,7 @@ impl RendezvousServer {
         listener3: &mut TcpListener,
         socket: &mut FramedSocket,
         key: &str,
+        workers: &UdpWorkers,
     ) -> LoopFailure {
         let mut timer_check_relay = interval(Duration::from_millis(CHECK_RELAY_TIMEOUT));
         loop {
@@ -253,8 +403,9 @@ impl RendezvousServer {
                     if self.relay_servers0.len() > 1 {
                         let rs = self.relay_servers0.clone();
                         let tx = self.tx.clone();
+                        let key = key.to_owned();
                         tokio::spawn(async move {
-                            check_relay_servers(rs, tx).await;
+                            check_relay_servers(rs, tx, key).await;
                         });
                     }
                 }
@@ -263,15 +414,27 @@ impl RendezvousServer {
                         Data::Msg(msg, addr) => { allow_err!(socket.send(msg.as_ref(), addr).await); }
                         Data::RelayServers0(rs) => { self.parse_relay_servers(&rs); }
                         Data::RelayServers(rs) => { self.relay_servers = Arc::new(rs); }
+                        Data::QueryRelayServers(tx) => {
+                            tx.send(((*self.relay_servers0).clone(), (*self.relay_servers).clone())).ok();
+                        }
+                        Data::ReloadConfig(tx) => {
+                            let res = self.reload_config();
+                            workers.sync(self);
+                            if let Some(tx) = tx {
+                                tx.send(res).ok();
+                            }
+                        }
+                        Data::ConfigureUpdate(cu) => {
+                            self.configure_update(cu);
+                            workers.sync(self);
+                        }
+                        Data::Shutdown => return LoopFailure::Shutdown,
                     }
                 }
                 res = socket.next() => {
                     match res {
                         Some(Ok((bytes, addr))) => {
-                            if let Err(err) = self.handle_udp(&bytes, addr.into(), socket, key).await {
-                                log::error!("udp failure: {}", err);
-                                return LoopFailure::UdpSocket;
-                            }
+                            workers.dispatch(bytes, addr);
                         }
                         Some(Err(err)) => {
                             log::error!("udp failure: {}", err);
@@ -322,12 +485,68 @@ impl RendezvousServer {
         }
     }
 
+    fn spawn_udp_workers(&self, key: &str) -> UdpWorkers {
+        let n = get_arg("udp-workers")
+            .parse::<usize>()
+            .ok()
+            .filter(|x| *x > 0)
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|x| x.get())
+                    .unwrap_or(1)
+            });
+        log::info!("UDP_WORKERS={}", n);
+        let (state, _) = watch::channel((
+            self.inner.clone(),
+            self.rendezvous_servers.clone(),
+            self.udp.clone(),
+        ));
+        let queues = Sharded::new((0..n).map(|_| {
+            let (tx, mut rx) = mpsc::channel::<(BytesMut, SocketAddr)>(UDP_QUEUE);
+            let mut rs = self.clone();
+            let mut state = state.subscribe();
+            let key = key.to_owned();
+            tokio::spawn(async move {
+                while let Some((bytes, addr)) = rx.recv().await {
+                    if state.has_changed().unwrap_or(false) {
+                        (rs.inner, rs.rendezvous_servers, rs.udp) =
+                            state.borrow_and_update().clone();
+                    }
+                    if let Err(err) = rs.handle_udp(&bytes, addr, &key).await {
+                        log::error!("udp failure: {}", err);
+                    }
+                }
+            });
+            tx
+        }));
+        UdpWorkers { queues, state }
+    }
+
+    fn configure_update(&mut self, mut cu: ConfigUpdate) {
+        if cu.serial <= self.inner.serial {
+            return;
+        }
+        let mut inner: Inner = (*self.inner).clone();
+        inner.serial = cu.serial;
+        self.inner = Arc::new(inner);
+        self.rendezvous_servers = Arc::new(
+            cu.rendezvous_servers
+                .drain(..)
+                .filter(|x| !x.is_empty() && test_if_valid_server(x, "rendezvous-server").is_ok())
+                .collect(),
+        );
+        log::info!(
+            "configure updated: serial={} rendezvous-servers={:?}",
+            self.inner.serial,
+            self.rendezvous_servers
+        );
+    }
+
     #[inline]
     async fn handle_udp(
         &mut self,
         bytes: &BytesMut,
         addr: SocketAddr,
-        socket: &mut FramedSocket,
         key: &str,
     ) -> ResultType<()> {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
@@ -336,7 +555,13 @@ impl RendezvousServer {
                     // B registered
                     if !rp.id.is_empty() {
                         log::trace!("New peer registered: {:?} {:?}", &rp.id, &addr);
-                        self.update_addr(rp.id, addr, socket).await?;
+                        REGISTER_PEER.inc();
+                        if access::is_denied(addr.ip(), &rp.id) {
+                            log::debug!("RegisterPeer of {} from {} denied", rp.id, addr);
+                            REGISTER_PEER_DENIED.inc();
+                            return Ok(());
+                        }
+                        self.update_addr(rp.id, addr).await?;
                         if self.inner.serial > rp.serial {
                             let mut msg_out = RendezvousMessage::new();
                             msg_out.set_configure_update(ConfigUpdate {
@@ -344,7 +569,7 @@ impl RendezvousServer {
                                 rendezvous_servers: (*self.rendezvous_servers).clone(),
                                 ..Default::default()
                             });
-                            socket.send(&msg_out, addr).await?;
+                            self.send_udp(msg_out, addr).await;
                         }
                     }
                 }
@@ -352,14 +577,39 @@ impl RendezvousServer {
                     if rk.uuid.is_empty() || rk.pk.is_empty() {
                         return Ok(());
                     }
+                    REGISTER_PK.inc();
                     let id = rk.id;
                     let ip = addr.ip().to_string();
                     if id.len() < 6 {
-                        return send_rk_res(socket, addr, UUID_MISMATCH).await;
+                        return self.send_rk_res(addr, UUID_MISMATCH).await;
                     } else if !self.check_ip_blocker(&ip, &id).await {
-                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                        REGISTER_PK_BLOCKED.inc();
+                        return self.send_rk_res(addr, TOO_FREQUENT).await;
+                    }
+                    if access::is_denied(addr.ip(), &id) {
+                        // NOT_SUPPORT as for disabled IDs below
+                        log::warn!("RegisterPk of {} from {} denied", id, ip);
+                        REGISTER_PK_DENIED.inc();
+                        return self.send_rk_res(addr, NOT_SUPPORT).await;
+                    }
+                    if PRIVATE_MODE.load(Ordering::SeqCst)
+                        && !access::is_allowed_id(&id)
+                        && !self.pm.is_provisioned(&id, &rk.pk).await
+                    {
+                        // hbbs sends INVALID_ID_FORMAT for nothing else, so this
+                        // can be told apart from disabled or denied IDs
+                        log::warn!("RegisterPk of {} from {} refused, not provisioned", id, ip);
+                        REGISTER_PK_UNKNOWN.inc();
+                        return self.send_rk_res(addr, INVALID_ID_FORMAT).await;
                     }
                     let peer = self.pm.get_or(&id).await;
+                    if peer.read().await.disabled {
+                        // no dedicated result code, NOT_SUPPORT keeps the client
+                        // from regenerating its ID as it does on UUID_MISMATCH
+                        log::warn!("Peer {} is disabled, RegisterPk from {} refused", id, ip);
+                        REGISTER_PK_DISABLED.inc();
+                        return self.send_rk_res(addr, NOT_SUPPORT).await;
+                    }
                     let (changed, ip_changed) = {
                         let peer = peer.read().await;
                         if peer.uuid.is_empty() {
@@ -376,7 +626,7 @@ impl RendezvousServer {
                                         peer.pk,
                                     );
                                     drop(peer);
-                                    return send_rk_res(socket, addr, UUID_MISMATCH).await;
+                                    return self.send_rk_res(addr, UUID_MISMATCH).await;
                                 }
                             } else {
                                 log::warn!(
@@ -386,7 +636,7 @@ impl RendezvousServer {
                                     peer.uuid
                                 );
                                 drop(peer);
-                                return send_rk_res(socket, addr, UUID_MISMATCH).await;
+                                return self.send_rk_res(addr, UUID_MISMATCH).await;
                             }
                             let ip_changed = peer.info.ip != ip;
                             (
@@ -399,7 +649,7 @@ impl RendezvousServer {
                     if req_pk.1.elapsed().as_secs() > 6 {
                         req_pk.0 = 0;
                     } else if req_pk.0 > 2 {
-                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                        return self.send_rk_res(addr, TOO_FREQUENT).await;
                     }
                     req_pk.0 += 1;
                     req_pk.1 = Instant::now();
@@ -431,7 +681,7 @@ impl RendezvousServer {
                         result: register_pk_response::Result::OK.into(),
                         ..Default::default()
                     });
-                    socket.send(&msg_out, addr).await?
+                    self.send_udp(msg_out, addr).await;
                 }
                 Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
                     // UDP PunchHoleRequest is intentionally unsupported.
@@ -443,25 +693,10 @@ impl RendezvousServer {
                 Some(rendezvous_message::Union::LocalAddr(la)) => {
                     // UDP LocalAddr is intentionally unsupported to avoid UDP reflection/amplification
                 }
-                Some(rendezvous_message::Union::ConfigureUpdate(mut cu)) => {
+                Some(rendezvous_message::Union::ConfigureUpdate(cu)) => {
                     if try_into_v4(addr).ip().is_loopback() && cu.serial > self.inner.serial {
-                        let mut inner: Inner = (*self.inner).clone();
-                        inner.serial = cu.serial;
-                        self.inner = Arc::new(inner);
-                        self.rendezvous_servers = Arc::new(
-                            cu.rendezvous_servers
-                                .drain(..)
-                                .filter(|x| {
-                                    !x.is_empty()
-                                        && test_if_valid_server(x, "rendezvous-server").is_ok()
-                                })
-                                .collect(),
-                        );
-                        log::info!(
-                            "configure updated: serial={} rendezvous-servers={:?}",
-                            self.inner.serial,
-                            self.rendezvous_servers
-                        );
+                        // applied by `io_loop`, which passes it on to the workers
+                        self.tx.send(Data::ConfigureUpdate(cu)).ok();
                     }
                 }
                 Some(rendezvous_message::Union::SoftwareUpdate(su)) => {
@@ -471,7 +706,7 @@ impl RendezvousServer {
                             url: self.inner.software_url.clone(),
                             ..Default::default()
                         });
-                        socket.send(&msg_out, addr).await?;
+                        self.send_udp(msg_out, addr).await;
                     }
                 }
                 _ => {}
@@ -504,12 +739,34 @@ impl RendezvousServer {
                     if let Some(sink) = sink.take() {
                         self.tcp_punch.lock().await.insert(try_into_v4(addr), sink);
                     }
+                    let event = |outcome: &str| AuditEvent {
+                        uuid: Some(rf.uuid.clone()),
+                        ..audit::event(audit::RELAY_REQUEST, addr, &rf.id, outcome)
+                    };
+                    if access::is_denied(addr.ip(), &rf.id) {
+                        log::warn!("Relay request from {} to {} denied", addr, rf.id);
+                        audit::record(event("DENIED"));
+                        return true;
+                    }
                     if let Some(peer) = self.pm.get_in_memory(&rf.id).await {
+                        if peer.read().await.disabled {
+                            log::warn!("Relay request from {} to disabled peer {}", addr, rf.id);
+                            audit::record(event("DISABLED"));
+                            return true;
+                        }
+                        let peer_addr = peer.read().await.socket_addr;
+                        audit::record(AuditEvent {
+                            peer_ip: Some(try_into_v4(peer_addr).ip().to_string()),
+                            ..event("OK")
+                        });
                         let mut msg_out = RendezvousMessage::new();
                         rf.socket_addr = AddrMangle::encode(addr).into();
                         msg_out.set_request_relay(rf);
-                        let peer_addr = peer.read().await.socket_addr;
                         self.tx.send(Data::Msg(msg_out.into(), peer_addr)).ok();
+                    } else if self.pm.get(&rf.id).await.is_none() {
+                        audit::record(event("ID_NOT_EXIST"));
+                    } else {
+                        audit::record(event("OFFLINE"));
                     }
                     return true;
                 }
@@ -570,12 +827,7 @@ impl RendezvousServer {
     }
 
     #[inline]
-    async fn update_addr(
-        &mut self,
-        id: String,
-        socket_addr: SocketAddr,
-        socket: &mut FramedSocket,
-    ) -> ResultType<()> {
+    async fn update_addr(&mut self, id: String, socket_addr: SocketAddr) -> ResultType<()> {
         let (request_pk, ip_change) = if let Some(old) = self.pm.get_in_memory(&id).await {
             let mut old = old.write().await;
             let ip = socket_addr.ip();
@@ -588,6 +840,7 @@ impl RendezvousServer {
             if !request_pk {
                 old.socket_addr = socket_addr;
                 old.last_reg_time = Instant::now();
+                self.pm.touch(&id, ip).await;
             }
             let ip_change = if ip_change && old.reg_pk.0 <= 2 {
                 Some(if old.socket_addr.port() == 0 {
@@ -610,7 +863,8 @@ impl RendezvousServer {
             request_pk,
             ..Default::default()
         });
-        socket.send(&msg_out, socket_addr).await
+        self.send_udp(msg_out, socket_addr).await;
+        Ok(())
     }
 
     #[inline]
@@ -690,6 +944,8 @@ impl RendezvousServer {
         let mut ph = ph;
         if !key.is_empty() && ph.licence_key != key {
             log::warn!("Authentication failed from {} for peer {} - invalid key", addr, ph.id);
+            PUNCH_HOLE_LICENSE_MISMATCH.inc();
+            audit::record(audit::event(audit::PUNCH_HOLE, addr, &ph.id, "LICENSE_MISMATCH"));
             let mut msg_out = RendezvousMessage::new();
             msg_out.set_punch_hole_response(PunchHoleResponse {
                 failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
@@ -697,6 +953,17 @@ impl RendezvousServer {
             });
             return Ok((msg_out, None));
         }
+        if access::is_denied(addr.ip(), &ph.id) {
+            log::warn!("Punch hole request from {} to {} denied", addr, ph.id);
+            PUNCH_HOLE_DENIED.inc();
+            audit::record(audit::event(audit::PUNCH_HOLE, addr, &ph.id, "DENIED"));
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                other_failure: "Access denied by the server".to_owned(),
+                ..Default::default()
+            });
+            return Ok((msg_out, None));
+        }
         let id = ph.id;
         // punch hole request from A, relay to B,
         // check if in same intranet first,
@@ -704,11 +971,28 @@ impl RendezvousServer {
         // because punch hole won't work if in the same intranet,
         // all routers will drop such self-connections.
         if let Some(peer) = self.pm.get(&id).await {
-            let (elapsed, peer_addr) = {
+            let (elapsed, peer_addr, disabled) = {
                 let r = peer.read().await;
-                (r.last_reg_time.elapsed().as_millis() as i64, r.socket_addr)
+                (
+                    r.last_reg_time.elapsed().as_millis() as i64,
+                    r.socket_addr,
+                    r.disabled,
+                )
             };
+            if disabled {
+                log::warn!("Punch hole request from {} to disabled peer {}", addr, id);
+                PUNCH_HOLE_DISABLED.inc();
+                audit::record(audit::event(audit::PUNCH_HOLE, addr, &id, "DISABLED"));
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_punch_hole_response(PunchHoleResponse {
+                    other_failure: "This ID has been disabled".to_owned(),
+                    ..Default::default()
+                });
+                return Ok((msg_out, None));
+            }
             if elapsed >= REG_TIMEOUT {
+                PUNCH_HOLE_OFFLINE.inc();
+                audit::record(audit::event(audit::PUNCH_HOLE, addr, &id, "OFFLINE"));
                 let mut msg_out = RendezvousMessage::new();
                 msg_out.set_punch_hole_response(PunchHoleResponse {
                     failure: punch_hole_response::Failure::OFFLINE.into(),
@@ -733,6 +1017,11 @@ impl RendezvousServer {
                 if !dup { lock.push(PunchReqEntry { tm: Instant::now(), from_ip, to_ip, to_id: to_id_clone }); }
             }
 
+            PUNCH_HOLE_OK.inc();
+            audit::record(AuditEvent {
+                peer_ip: Some(try_into_v4(peer_addr).ip().to_string()),
+                ..audit::event(audit::PUNCH_HOLE, addr, &id, "OK")
+            });
             let mut msg_out = RendezvousMessage::new();
             let peer_is_lan = self.is_lan(peer_addr);
             let is_lan = self.is_lan(addr);
@@ -781,6 +1070,8 @@ impl RendezvousServer {
             }
             Ok((msg_out, Some(peer_addr)))
         } else {
+            PUNCH_HOLE_ID_NOT_EXIST.inc();
+            audit::record(audit::event(audit::PUNCH_HOLE, addr, &id, "ID_NOT_EXIST"));
             let mut msg_out = RendezvousMessage::new();
             msg_out.set_punch_hole_response(PunchHoleResponse {
                 failure: punch_hole_response::Failure::ID_NOT_EXIST.into(),
@@ -819,6 +1110,84 @@ impl RendezvousServer {
         Ok(())
     }
 
+    /// Answers the TCP connections still waiting for a punch hole or relay
+    /// response, sends the queued UDP messages and flushes the db writes.
+    async fn shutdown(&mut self, rx: &mut Receiver, socket: &mut FramedSocket) {
+        log::info!("Shutting down");
+        let sinks: Vec<_> = self.tcp_punch.lock().await.drain().map(|x| x.1).collect();
+        let n = sinks.len();
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_punch_hole_response(PunchHoleResponse {
+            other_failure: "Server is restarting, please try again".to_owned(),
+            ..Default::default()
+        });
+        join_all(sinks.into_iter().map(|sink| {
+            let msg = msg_out.clone();
+            async move {
+                let mut sink = Some(sink);
+                Self::send_to_sink(&mut sink, msg).await;
+                match sink {
+                    Some(Sink::TcpStream(mut s)) => allow_err!(s.close().await),
+                    Some(Sink::Ws(mut ws)) => allow_err!(ws.close().await),
+                    None => {}
+                }
+            }
+        }))
+        .await;
+        log::info!("{} tcp connections closed", n);
+        let mut n = Self::send_queued(rx, socket).await;
+        self.pm.shutdown().await;
+        audit::flush().await;
+        n += Self::send_queued(rx, socket).await;
+        log::info!("{} queued messages sent", n);
+    }
+
+    async fn send_queued(rx: &mut Receiver, socket: &mut FramedSocket) -> usize {
+        let mut n = 0;
+        while let Ok(data) = rx.try_recv() {
+            if let Data::Msg(msg, addr) = data {
+                allow_err!(socket.send(msg.as_ref(), addr).await);
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// Reply to a UDP message, on the shared socket if there is one.
+    #[inline]
+    async fn send_udp(&self, msg: RendezvousMessage, addr: SocketAddr) {
+        match self.udp.as_ref() {
+            Some(socket) => match msg.write_to_bytes() {
+                Ok(bytes) => {
+                    // Dropped like those `io_loop` sends: send errors are per
+                    // destination (unreachable, refused, firewalled), while a
+                    // broken socket also fails the receive of `io_loop`, which
+                    // then recreates it and hands the new one to the workers.
+                    allow_err!(socket.send_to(&bytes, addr).await);
+                }
+                Err(err) => log::error!("Failed to encode {:?}: {}", msg, err),
+            },
+            None => {
+                self.tx.send(Data::Msg(msg.into(), addr)).ok();
+            }
+        }
+    }
+
+    #[inline]
+    async fn send_rk_res(
+        &self,
+        addr: SocketAddr,
+        res: register_pk_response::Result,
+    ) -> ResultType<()> {
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_register_pk_response(RegisterPkResponse {
+            result: res.into(),
+            ..Default::default()
+        });
+        self.send_udp(msg_out, addr).await;
+        Ok(())
+    }
+
     #[inline]
     async fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) {
         let mut tcp = self.tcp_punch.lock().await.remove(&try_into_v4(addr));
@@ -919,20 +1288,93 @@ impl RendezvousServer {
         true
     }
 
+    /// Re-applies the config files and re-reads the deny and allow lists,
+    /// returns the changes.
+    fn reload_config(&mut self) -> String {
+        use std::fmt::Write as _;
+
+        let mut res = String::new();
+        let changes = match reload_config(RESTART_ARGS) {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Failed to reload config: {}", err);
+                let _ = writeln!(res, "{}", err);
+                Vec::new()
+            }
+        };
+        let changed = |name: &str| changes.iter().any(|x| x.applied && x.name == name);
+        if changed("RELAY-SERVERS") {
+            self.parse_relay_servers(&get_arg("relay-servers"));
+        }
+        if changed("ALWAYS-USE-RELAY") {
+            let v = get_arg("ALWAYS_USE_RELAY").to_uppercase() == "Y";
+            ALWAYS_USE_RELAY.store(v, Ordering::SeqCst);
+            log::info!("ALWAYS_USE_RELAY={}", if v { "Y" } else { "N" });
+        }
+        if changed("PRIVATE-MODE") {
+            let v = get_arg("PRIVATE_MODE").to_uppercase() == "Y";
+            PRIVATE_MODE.store(v, Ordering::SeqCst);
+            log::info!("PRIVATE_MODE={}", if v { "Y" } else { "N" });
+        }
+        if changed("MASK") || changed("LOCAL-IP") {
+            let (mask, local_ip) = get_mask_and_local_ip();
+            log::info!("mask: {:?}", mask);
+            log::info!("local-ip: {:?}", local_ip);
+            self.inner = Arc::new(Inner {
+                mask,
+                local_ip,
+                ..(*self.inner).clone()
+            });
+        }
+        if changed("GEO-FILE") {
+            if let Err(err) = geo::reload() {
+                log::error!("{}", err);
+            }
+        }
+        for x in changes.iter() {
+            let _ = writeln!(res, "{}", x);
+        }
+        for x in access::reload() {
+            let _ = writeln!(res, "{}", x);
+        }
+        if res.is_empty() {
+            res = "no change\n".to_owned();
+        }
+        res
+    }
+
     fn parse_relay_servers(&mut self, relay_servers: &str) {
         let rs = get_servers(relay_servers, "relay-servers");
         self.relay_servers0 = Arc::new(rs);
         self.relay_servers = self.relay_servers0.clone();
     }
 
-    fn get_relay_server(&self, _pa: IpAddr, _pb: IpAddr) -> String {
+    fn get_relay_server(&self, pa: IpAddr, pb: IpAddr) -> String {
         if self.relay_servers.is_empty() {
             return "".to_owned();
         } else if self.relay_servers.len() == 1 {
             return self.relay_servers[0].clone();
         }
-        let i = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst) % self.relay_servers.len();
-        self.relay_servers[i].clone()
+        let (ra, rb) = (geo::region(pa), geo::region(pb));
+        let rs = geo::candidates(
+            &self.relay_servers,
+            ra.as_deref(),
+            rb.as_deref(),
+            geo::relay_region,
+        );
+        let weights: Vec<f64> = {
+            let status = RELAY_STATUS.read().unwrap();
+            rs.iter()
+                .map(|x| {
+                    status
+                        .get(*x)
+                        .map(|x| (1. - x.load()).max(MIN_RELAY_WEIGHT))
+                        .unwrap_or(1.)
+                })
+                .collect()
+        };
+        let n = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst);
+        rs[pick_weighted(&weights, n)].clone()
     }
 
     async fn check_cmd(&self, cmd: &str) -> String {
@@ -943,16 +1385,67 @@ impl RendezvousServer {
         match fds.next() {
             Some("h") => {
                 res = format!(
-                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
                     "relay-servers(rs) <separated by ,>",
+                    "relay-status(rst)",
+                    "reload-config(rc)",
                     "reload-geo(rg)",
                     "ip-blocker(ib) [<ip>|<number>] [-]",
                     "ip-changes(ic) [<id>|<number>] [-]",
                     "punch-requests(pr) [<number>] [-]",
                     "always-use-relay(aur)",
-                    "test-geo(tg) <ip1> <ip2>"
+                    "test-geo(tg) <ip1> <ip2>",
+                    "deny-add(da) <ip|cidr|id> [<duration, e.g. 12h>|<expiry>] [# comment]",
+                    "deny-remove(dr) <ip|cidr|id>",
+                    "deny(d) [<ip>|<id>]",
+                    "allow-add(aa) <ip|cidr|id> [<duration, e.g. 12h>|<expiry>] [# comment]",
+                    "allow-remove(ar) <ip|cidr|id>",
+                    "allow(a) [<ip>|<id>]",
+                    "peer-import(pi) <file of id[,pk[,note]] lines>",
+                    "janitor(jn)"
                 )
             }
+            Some("peer-import" | "pi") => {
+                if let Some(file) = fds.next() {
+                    res = match std::fs::read_to_string(file) {
+                        Ok(content) => {
+                            let res = self
+                                .pm
+                                .import(content.lines().filter_map(Provision::parse_line))
+                                .await;
+                            let mut out = format!(
+                                "{} added, {} existing, {} errors\n",
+                                res.added,
+                                res.existing,
+                                res.errors.len()
+                            );
+                            for err in res.errors {
+                                let _ = writeln!(out, "{err}");
+                            }
+                            out
+                        }
+                        Err(err) => format!("Failed to read {file}: {err}\n"),
+                    };
+                }
+            }
+            Some("deny-add" | "da") => {
+                res = access::console_add(List::Deny, fds.next(), fds).await;
+            }
+            Some("deny-remove" | "dr") => {
+                res = access::console_remove(List::Deny, fds.next()).await;
+            }
+            Some("deny" | "d") => {
+                res = access::console_show(List::Deny, fds.next());
+            }
+            Some("allow-add" | "aa") => {
+                res = access::console_add(List::Allow, fds.next(), fds).await;
+            }
+            Some("allow-remove" | "ar") => {
+                res = access::console_remove(List::Allow, fds.next()).await;
+            }
+            Some("allow" | "a") => {
+                res = access::console_show(List::Allow, fds.next());
+            }
             Some("relay-servers" | "rs") => {
                 if let Some(rs) = fds.next() {
                     self.tx.send(Data::RelayServers0(rs.to_owned())).ok();
@@ -962,6 +1455,36 @@ impl RendezvousServer {
                     }
                 }
             }
+            Some("reload-config" | "rc") => {
+                let (tx, rx) = oneshot::channel();
+                self.tx.send(Data::ReloadConfig(Some(tx))).ok();
+                res = rx.await.unwrap_or_default();
+            }
+            Some("relay-status" | "rst") => {
+                let status = RELAY_STATUS.read().unwrap();
+                for x in self.relay_servers0.iter() {
+                    let alive = if self.relay_servers.contains(x) {
+                        ""
+                    } else {
+                        " (unused)"
+                    };
+                    if let Some(st) = status.get(x) {
+                        let _ = writeln!(
+                            res,
+                            "{}: {} active, {} pending, {:.2}/{}Mb/s{}{}",
+                            x,
+                            st.active,
+                            st.pending,
+                            st.bandwidth as f64 / 1024. / 1024.,
+                            st.total_bandwidth as f64 / 1024. / 1024.,
+                            if st.draining { ", draining" } else { "" },
+                            alive
+                        );
+                    } else {
+                        let _ = writeln!(res, "{}: no status{}", x, alive);
+                    }
+                }
+            }
             Some("ip-blocker" | "ib") => {
                 let mut lock = IP_BLOCKER.lock().await;
                 lock.retain(|&_, (a, b)| {
@@ -1058,14 +1581,13 @@ impl RendezvousServer {
                     let mut page_size = fds.next().and_then(|x| x.parse::<usize>().ok()).unwrap_or(10);
                     if page_size == 0 { page_size = 10; }
                     for (_, e) in lock.iter().enumerate().skip(start).take(page_size) {
-                        let age = e.tm.elapsed();
-                        let event_system = std::time::SystemTime::now() - age;
-                        let event_iso = chrono::DateTime::<chrono::Utc>::from(event_system)
-                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-                        let _ = writeln!(res, "{} {} -> {}@{}", event_iso, e.from_ip, e.to_id, e.to_ip);
+                        let _ = writeln!(res, "{} {} -> {}@{}", e.time_rfc3339(), e.from_ip, e.to_id, e.to_ip);
                     }
                 }
             }
+            Some("janitor" | "jn") => {
+                res = format!("{}\n", janitor::sweep(&self.pm).await);
+            }
             Some("always-use-relay" | "aur") => {
                 if let Some(rs) = fds.next() {
                     if rs.to_uppercase() == "Y" {
@@ -1082,15 +1604,30 @@ impl RendezvousServer {
                     );
                 }
             }
+            Some("reload-geo" | "rg") => {
+                res = match geo::reload() {
+                    Ok(n) => format!("{n} networks loaded\n"),
+                    Err(err) => format!("{err}\n"),
+                };
+            }
             Some("test-geo" | "tg") => {
                 if let Some(rs) = fds.next() {
                     if let Ok(a) = rs.parse::<IpAddr>() {
                         if let Some(rs) = fds.next() {
                             if let Ok(b) = rs.parse::<IpAddr>() {
-                                res = format!("{:?}", self.get_relay_server(a, b));
+                                res = format!(
+                                    "{:?} {:?} {:?}\n",
+                                    geo::region(a),
+                                    geo::region(b),
+                                    self.get_relay_server(a, b)
+                                );
                             }
                         } else {
-                            res = format!("{:?}", self.get_relay_server(a, a));
+                            res = format!(
+                                "{:?} {:?}\n",
+                                geo::region(a),
+                                self.get_relay_server(a, a)
+                            );
                         }
                     }
                 }
@@ -1282,33 +1819,220 @@ impl RendezvousServer {
     }
 }
 
-async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender) {
+/// Probes every relay server, draining ones are dropped, saturated ones too
+/// unless all are. A relay which accepts the connection but does not answer
+/// the health check (older hbbr or different key), or answers without a
+/// status, is used with unknown load.
+async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender, key: String) {
+    geo::resolve_relays(&rs0).await;
+    let saturation = get_arg("relay-saturation")
+        .parse::<f64>()
+        .unwrap_or(RELAY_SATURATION);
     let mut futs = Vec::new();
-    let rs = Arc::new(Mutex::new(Vec::new()));
     for x in rs0.iter() {
         let mut host = x.to_owned();
         if !host.contains(':') {
             host = format!("{}:{}", host, config::RELAY_PORT);
         }
-        let rs = rs.clone();
         let x = x.clone();
+        let key = key.clone();
         futs.push(tokio::spawn(async move {
-            if FramedStream::new(&host, None, CHECK_RELAY_TIMEOUT)
+            let mut stream = FramedStream::new(&host, None, CHECK_RELAY_TIMEOUT)
                 .await
-                .is_ok()
-            {
-                rs.lock().await.push(x);
-            }
+                .ok()?;
+            Some((x, probe_relay(&mut stream, &key).await))
         }));
     }
-    join_all(futs).await;
+    let res = join_all(futs).await;
     log::debug!("check_relay_servers");
-    let rs = std::mem::take(&mut *rs.lock().await);
+    let mut status = HashMap::new();
+    let mut rs = Vec::new();
+    let mut saturated = Vec::new();
+    for (x, st) in res.into_iter().filter_map(|x| x.ok().flatten()) {
+        match st {
+            Some(st) => {
+                if st.draining {
+                    log::debug!("relay server {} is draining", x);
+                } else if st.load() >= saturation {
+                    log::debug!("relay server {} is saturated", x);
+                    saturated.push(x.clone());
+                } else {
+                    rs.push(x.clone());
+                }
+                status.insert(x, st);
+            }
+            None => rs.push(x),
+        }
+    }
+    if rs.is_empty() {
+        rs = saturated;
+    }
+    *RELAY_STATUS.write().unwrap() = status;
     if !rs.is_empty() {
         tx.send(Data::RelayServers(rs)).ok();
     }
 }
 
+/// The status in the HealthCheck reply of the relay, None without one.
+async fn probe_relay(stream: &mut FramedStream, key: &str) -> Option<RelayStatus> {
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_hc(HealthCheck {
+        token: key.to_owned(),
+        ..Default::default()
+    });
+    stream.send(&msg_out).await.ok()?;
+    let bytes = stream.next_timeout(CHECK_RELAY_TIMEOUT).await?.ok()?;
+    match RendezvousMessage::parse_from_bytes(&bytes).ok()?.union {
+        Some(rendezvous_message::Union::Hc(hc)) => {
+            let st = RelayStatus::from_token(&hc.token);
+            if st.is_none() {
+                log::debug!("relay health check reply without status");
+            }
+            st
+        }
+        _ => None,
+    }
+}
+
+/// Index of the n-th pick, consecutive picks are spread over the indexes in
+/// proportion to their weights, round-robin if all weights are equal.
+fn pick_weighted(weights: &[f64], n: usize) -> usize {
+    if weights.windows(2).all(|w| w[0] == w[1]) {
+        return n % weights.len();
+    }
+    let mut x = (n as f64 * 0.618_033_988_749_895).fract() * weights.iter().sum::<f64>();
+    for (i, w) in weights.iter().enumerate() {
+        if x < *w {
+            return i;
+        }
+        x -= w;
+    }
+    weights.len() - 1
+}
+
+async fn render_metrics(pm: PeerMap) -> String {
+    let peers = pm.list_in_memory().await;
+    let mut online = 0;
+    for (_, peer) in peers.iter() {
+        if peer.read().await.is_online() {
+            online += 1;
+        }
+    }
+    let name = "hbbs_punch_hole_requests_total";
+    let evictions = "hbbs_evictions_total";
+    let lookups = "hbbs_peer_cache_lookups_total";
+    let mut out = Encoder::default();
+    out.counter(
+        "hbbs_register_peer_total",
+        "RegisterPeer heartbeats received.",
+        REGISTER_PEER.get(),
+    )
+    .counter(
+        "hbbs_register_pk_total",
+        "RegisterPk requests received.",
+        REGISTER_PK.get(),
+    )
+    .counter(
+        "hbbs_register_pk_blocked_total",
+        "RegisterPk requests rejected by the IP blocker.",
+        REGISTER_PK_BLOCKED.get(),
+    )
+    .counter(
+        "hbbs_register_pk_disabled_total",
+        "RegisterPk requests rejected because the ID is disabled.",
+        REGISTER_PK_DISABLED.get(),
+    )
+    .counter(
+        "hbbs_register_peer_denied_total",
+        "RegisterPeer heartbeats dropped by the deny list.",
+        REGISTER_PEER_DENIED.get(),
+    )
+    .counter(
+        "hbbs_register_pk_denied_total",
+        "RegisterPk requests rejected by the deny list.",
+        REGISTER_PK_DENIED.get(),
+    )
+    .counter(
+        "hbbs_register_pk_unknown_total",
+        "RegisterPk requests rejected in private mode because the ID is not provisioned.",
+        REGISTER_PK_UNKNOWN.get(),
+    )
+    .gauge(
+        "hbbs_online_peers",
+        "Peers registered within the registration timeout.",
+        online,
+    )
+    .gauge(
+        "hbbs_peers_in_memory",
+        "Peers held in the in-memory peer map.",
+        peers.len(),
+    )
+    .counter(
+        "hbbs_udp_dropped_total",
+        "UDP messages dropped because the queue of their worker was full.",
+        UDP_DROPPED.get(),
+    )
+    .header(
+        lookups,
+        "counter",
+        "Peer lookups by whether the peer was cached or loaded from the database.",
+    )
+    .sample(lookups, &[("result", "hit")], CACHE_HITS.get())
+    .sample(lookups, &[("result", "miss")], CACHE_MISSES.get())
+    .header(name, "counter", "Punch hole requests by outcome.")
+    .sample(name, &[("outcome", "OK")], PUNCH_HOLE_OK.get())
+    .sample(name, &[("outcome", "OFFLINE")], PUNCH_HOLE_OFFLINE.get())
+    .sample(
+        name,
+        &[("outcome", "ID_NOT_EXIST")],
+        PUNCH_HOLE_ID_NOT_EXIST.get(),
+    )
+    .sample(
+        name,
+        &[("outcome", "LICENSE_MISMATCH")],
+        PUNCH_HOLE_LICENSE_MISMATCH.get(),
+    )
+    .sample(name, &[("outcome", "DISABLED")], PUNCH_HOLE_DISABLED.get())
+    .sample(name, &[("outcome", "DENIED")], PUNCH_HOLE_DENIED.get())
+    .gauge(
+        "hbbs_ip_blocker_entries",
+        "Entries in the registration rate limiter.",
+        IP_BLOCKER.lock().await.len(),
+    )
+    .gauge(
+        "hbbs_ip_changes_entries",
+        "Entries in the IP change tracker.",
+        IP_CHANGES.lock().await.len(),
+    )
+    .gauge(
+        "hbbs_punch_requests_entries",
+        "Recorded punch hole requests.",
+        PUNCH_REQS.lock().await.len(),
+    )
+    .header(
+        evictions,
+        "counter",
+        "Entries evicted by the janitor, by map.",
+    )
+    .sample(
+        evictions,
+        &[("map", "punch_requests")],
+        janitor::EVICTED_PUNCH_REQS.get(),
+    )
+    .sample(
+        evictions,
+        &[("map", "ip_blocker")],
+        janitor::EVICTED_IP_BLOCKER.get(),
+    )
+    .sample(
+        evictions,
+        &[("map", "ip_changes")],
+        janitor::EVICTED_IP_CHANGES.get(),
+    )
+    .sample(evictions, &[("map", "peers")], janitor::EVICTED_PEERS.get());
+    out.finish()
+}
+
 // temp solution to solve udp socket failure
 async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
     let mut addr = addr;
@@ -1347,18 +2071,27 @@ async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
     }
 }
 
-#[inline]
-async fn send_rk_res(
-    socket: &mut FramedSocket,
-    addr: SocketAddr,
-    res: register_pk_response::Result,
-) -> ResultType<()> {
-    let mut msg_out = RendezvousMessage::new();
-    msg_out.set_register_pk_response(RegisterPkResponse {
-        result: res.into(),
-        ..Default::default()
+/// A second handle of the socket of `socket`, for the UDP workers to send
+/// their replies without waiting for `io_loop`.
+fn udp_sender(socket: &FramedSocket) -> Option<Arc<UdpSocket>> {
+    let FramedSocket::Direct(framed) = socket else {
+        return None;
+    };
+    #[cfg(unix)]
+    let handle = std::os::unix::io::AsFd::as_fd(framed.get_ref()).try_clone_to_owned();
+    #[cfg(windows)]
+    let handle = std::os::windows::io::AsSocket::as_socket(framed.get_ref()).try_clone_to_owned();
+    let socket = handle.map(std::net::UdpSocket::from).and_then(|x| {
+        x.set_nonblocking(true)?;
+        UdpSocket::from_std(x)
     });
-    socket.send(&msg_out, addr).await
+    match socket {
+        Ok(socket) => Some(Arc::new(socket)),
+        Err(err) => {
+            log::error!("Failed to share the udp socket: {}", err);
+            None
+        }
+    }
 }
 
 async fn create_udp_listener(
@@ -1381,6 +2114,21 @@ async fn create_udp_listener(
     Ok(s)
 }
 
+fn get_mask_and_local_ip() -> (Option<Ipv4Network>, String) {
+    let mask = get_arg("mask").parse().ok();
+    let local_ip = if mask.is_none() {
+        "".to_owned()
+    } else {
+        get_arg_or(
+            "local-ip",
+            local_ip_address::local_ip()
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+        )
+    };
+    (mask, local_ip)
+}
+
 #[inline]
 async fn create_tcp_listener(bind_addr: Option<IpAddr>, port: i32) -> ResultType<TcpListener> {
     let s = listen_tcp(bind_addr, port as _).await?;
@@ -1398,4 +2146,28 @@ mod tests {
         let socket = create_udp_listener(Some(bind_addr), 0, 0).await.unwrap();
         assert_eq!(socket.local_addr().unwrap().ip(), bind_addr);
     }
+
+    #[test]
+    fn weighted_relay_pick() {
+        assert_eq!(
+            (0..6)
+                .map(|n| pick_weighted(&[1., 1., 1.], n))
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 0, 1, 2]
+        );
+        let mut hits = [0; 3];
+        for n in 0..1000 {
+            hits[pick_weighted(&[1., 0.5, MIN_RELAY_WEIGHT], n)] += 1;
+        }
+        assert!((640..690).contains(&hits[0]), "{:?}", hits);
+        assert!((310..350).contains(&hits[1]), "{:?}", hits);
+        assert!((20..50).contains(&hits[2]), "{:?}", hits);
+        let st = RelayStatus {
+            bandwidth: 300,
+            total_bandwidth: 1000,
+            ..Default::default()
+        };
+        assert_eq!(st.load(), 0.3);
+        assert_eq!(RelayStatus::default().load(), 0.);
+    }
 }
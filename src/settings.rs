@@ -0,0 +1,319 @@
+//! Typed schema of the settings read by `hbbs` and `hbbr`, used to validate
+//! them whatever their source and to print the effective configuration.
+
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Kind {
+    Str,
+    /// `Y` or `N`, any case
+    Bool,
+    /// integer within the inclusive range
+    Int(i64, i64),
+    /// number greater than the first bound and at most the second
+    Float(f64, f64),
+    Ip,
+    Ipv4Net,
+    /// one of the values, any case
+    Choice(&'static [&'static str]),
+}
+
+pub(crate) struct Setting {
+    /// env var spelling, as in the docs
+    pub name: &'static str,
+    pub kind: Kind,
+    pub default: Option<&'static str>,
+    /// None if read by both binaries
+    pub bin: Option<&'static str>,
+    /// value not printed
+    pub secret: bool,
+}
+
+const fn setting(
+    name: &'static str,
+    kind: Kind,
+    default: Option<&'static str>,
+    bin: Option<&'static str>,
+) -> Setting {
+    Setting {
+        name,
+        kind,
+        default,
+        bin,
+        secret: false,
+    }
+}
+
+const HBBS: Option<&str> = Some("hbbs");
+const HBBR: Option<&str> = Some("hbbr");
+const PORT: Kind = Kind::Int(0, 65535);
+const SECONDS: Kind = Kind::Int(0, u32::MAX as _);
+const COUNT: Kind = Kind::Int(0, u32::MAX as _);
+const MBPS: Kind = Kind::Float(0., f64::MAX);
+
+pub(crate) const SETTINGS: &[Setting] = &[
+    setting("BIND", Kind::Ip, None, None),
+    setting("PORT", Kind::Int(3, 65533), Some("21116"), HBBS),
+    Setting {
+        secret: true,
+        ..setting("KEY", Kind::Str, Some("-"), HBBS)
+    },
+    setting("SERIAL", Kind::Int(0, i32::MAX as _), Some("0"), HBBS),
+    setting("RENDEZVOUS_SERVERS", Kind::Str, None, HBBS),
+    setting("SOFTWARE_URL", Kind::Str, None, HBBS),
+    setting("RELAY_SERVERS", Kind::Str, None, HBBS),
+    setting("RMEM", Kind::Int(0, i32::MAX as _), Some("0"), HBBS),
+    setting("MASK", Kind::Ipv4Net, None, HBBS),
+    setting("LOCAL_IP", Kind::Ip, None, HBBS),
+    setting("TEST_HBBS", Kind::Str, None, HBBS),
+    setting("ALWAYS_USE_RELAY", Kind::Bool, Some("N"), HBBS),
+    setting("PRIVATE_MODE", Kind::Bool, Some("N"), HBBS),
+    Setting {
+        secret: true,
+        ..setting("DB_URL", Kind::Str, Some("./db_v2.sqlite3"), HBBS)
+    },
+    // hbbr only opens a database if given one
+    Setting {
+        secret: true,
+        ..setting("DB_URL", Kind::Str, None, HBBR)
+    },
+    setting(
+        "MAX_DATABASE_CONNECTIONS",
+        Kind::Int(1, 1000),
+        Some("1"),
+        None,
+    ),
+    setting("DB_MIGRATE_DRY_RUN", Kind::Bool, Some("N"), None),
+    setting("AUDIT_LOG", Kind::Bool, Some("Y"), None),
+    setting(
+        "AUDIT_RETENTION_DAYS",
+        Kind::Int(0, 36500),
+        Some("90"),
+        None,
+    ),
+    setting("GEO_FILE", Kind::Str, None, HBBS),
+    setting("RELAY_SATURATION", Kind::Float(0., 1.), Some("0.9"), HBBS),
+    setting(
+        "LAST_SEEN_FLUSH_INTERVAL",
+        Kind::Int(1, u32::MAX as _),
+        Some("60"),
+        HBBS,
+    ),
+    setting(
+        "JANITOR_INTERVAL",
+        Kind::Int(1, u32::MAX as _),
+        Some("60"),
+        HBBS,
+    ),
+    setting("PUNCH_REQS_TTL", SECONDS, Some("86400"), HBBS),
+    setting("PUNCH_REQS_MAX", COUNT, Some("10000"), HBBS),
+    setting("IP_BLOCKER_TTL", SECONDS, Some("86400"), HBBS),
+    setting("IP_BLOCKER_MAX", COUNT, Some("100000"), HBBS),
+    setting("IP_CHANGES_TTL", SECONDS, Some("360"), HBBS),
+    setting("IP_CHANGES_MAX", COUNT, Some("100000"), HBBS),
+    setting("PEER_CACHE_TTL", SECONDS, Some("3600"), HBBS),
+    setting("PEER_CACHE_MAX", COUNT, Some("1000000"), HBBS),
+    setting("PEER_REFRESH_INTERVAL", SECONDS, Some("60"), HBBS),
+    // the number of CPUs if not set
+    setting("UDP_WORKERS", Kind::Int(1, 1024), None, HBBS),
+    setting("SHUTDOWN_TIMEOUT", SECONDS, Some("10"), HBBS),
+    Setting {
+        secret: true,
+        ..setting("API_SECRET", Kind::Str, None, HBBS)
+    },
+    setting("API_PORT", PORT, None, HBBS),
+    setting("METRICS_PORT", PORT, None, HBBS),
+    // hbbr listens on PORT + 1 if not given with -p
+    setting("PORT", Kind::Int(1, 65532), Some("21117"), HBBR),
+    Setting {
+        secret: true,
+        ..setting("KEY", Kind::Str, None, HBBR)
+    },
+    setting("RELAY_METRICS_PORT", PORT, None, HBBR),
+    setting("SINGLE_BANDWIDTH", MBPS, Some("128"), HBBR),
+    setting("TOTAL_BANDWIDTH", MBPS, Some("1024"), HBBR),
+    setting("LIMIT_SPEED", MBPS, Some("32"), HBBR),
+    setting(
+        "DOWNGRADE_THRESHOLD",
+        Kind::Float(0., 1.),
+        Some("0.66"),
+        HBBR,
+    ),
+    setting(
+        "DOWNGRADE_START_CHECK",
+        Kind::Int(1, u32::MAX as _),
+        Some("1800"),
+        HBBR,
+    ),
+    setting(
+        "DOWNGRADE_POLICY",
+        Kind::Choice(&["average", "congestion"]),
+        Some("average"),
+        HBBR,
+    ),
+    setting(
+        "CONGESTION_WATERMARK",
+        Kind::Float(0., 1.),
+        Some("0.8"),
+        HBBR,
+    ),
+    setting("DRAIN_TIMEOUT", SECONDS, Some("600"), HBBR),
+    // only used on Linux
+    setting("RELAY_SPLICE", Kind::Bool, Some("Y"), HBBR),
+    setting("RELAY_ACCOUNTING_FILE", Kind::Str, None, HBBR),
+    setting("RELAY_ACCOUNTING_DB", Kind::Bool, Some("N"), HBBR),
+];
+
+#[inline]
+fn normalize(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// Settings read by `bin`.
+pub(crate) fn settings(bin: &str) -> impl Iterator<Item = &'static Setting> + '_ {
+    SETTINGS
+        .iter()
+        .filter(move |x| x.bin.is_none() || x.bin == Some(bin))
+}
+
+/// The setting `name` of `bin`, in any spelling of the name.
+pub(crate) fn find(bin: &str, name: &str) -> Option<&'static Setting> {
+    let name = normalize(name);
+    settings(bin).find(|x| x.name == name)
+}
+
+/// Whether `name` is read by any binary.
+pub(crate) fn is_known(name: &str) -> bool {
+    let name = normalize(name);
+    SETTINGS.iter().any(|x| x.name == name)
+}
+
+impl Kind {
+    fn check(&self, value: &str) -> Result<(), String> {
+        match *self {
+            Kind::Str => Ok(()),
+            Kind::Bool => {
+                if value.eq_ignore_ascii_case("y") || value.eq_ignore_ascii_case("n") {
+                    Ok(())
+                } else {
+                    Err("expected Y or N".to_owned())
+                }
+            }
+            Kind::Int(min, max) => match value.parse::<i64>() {
+                Ok(v) if v >= min && v <= max => Ok(()),
+                _ => Err(format!("expected an integer from {} to {}", min, max)),
+            },
+            Kind::Float(min, max) => match value.parse::<f64>() {
+                Ok(v) if v > min && v <= max => Ok(()),
+                _ if max == f64::MAX => Err(format!("expected a number greater than {}", min)),
+                _ => Err(format!(
+                    "expected a number greater than {} and at most {}",
+                    min, max
+                )),
+            },
+            Kind::Ip => value
+                .parse::<IpAddr>()
+                .map(|_| ())
+                .map_err(|_| "expected an IPv4 or IPv6 address".to_owned()),
+            Kind::Ipv4Net => value
+                .parse::<ipnetwork::Ipv4Network>()
+                .map(|_| ())
+                .map_err(|_| "expected an IPv4 network, e.g. 192.168.0.0/16".to_owned()),
+            Kind::Choice(values) => {
+                if values.iter().any(|x| x.eq_ignore_ascii_case(value)) {
+                    Ok(())
+                } else {
+                    Err(format!("expected one of {}", values.join(", ")))
+                }
+            }
+        }
+    }
+
+    /// `value` as a TOML value, unquoted if it is a number.
+    pub(crate) fn to_toml(self, value: &str) -> String {
+        match self {
+            Kind::Int(..) | Kind::Float(..) if self.check(value).is_ok() => value.to_owned(),
+            _ => toml::Value::String(value.to_owned()).to_string(),
+        }
+    }
+}
+
+/// Checks `value` of `name` for `bin`, names it does not read are not checked.
+/// An empty value is the same as unset.
+pub(crate) fn check(bin: &str, name: &str, value: &str) -> Result<(), String> {
+    match find(bin, name) {
+        Some(setting) if !value.is_empty() => setting
+            .kind
+            .check(value)
+            .map_err(|err| format!("invalid {} {:?}: {}", setting.name, value, err)),
+        _ => Ok(()),
+    }
+}
+
+/// Converts a TOML value to the string form of the env var.
+pub(crate) fn from_toml(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(v) => Some(v.clone()),
+        toml::Value::Integer(v) => Some(v.to_string()),
+        toml::Value::Float(v) => Some(v.to_string()),
+        toml::Value::Boolean(v) => Some(if *v { "Y" } else { "N" }.to_owned()),
+        toml::Value::Array(v) => v
+            .iter()
+            .map(|x| x.as_str().map(str::to_owned))
+            .collect::<Option<Vec<_>>>()
+            .map(|x| x.join(",")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_values() {
+        assert!(check("hbbs", "port", "21116").is_ok());
+        assert!(check("hbbs", "PORT", "").is_ok());
+        assert_eq!(
+            check("hbbs", "port", "70000").unwrap_err(),
+            "invalid PORT \"70000\": expected an integer from 3 to 65533"
+        );
+        assert!(check("hbbr", "port", "2").is_ok());
+        assert!(check("hbbs", "always-use-relay", "y").is_ok());
+        assert!(check("hbbs", "always_use_relay", "true").is_err());
+        assert!(check("hbbs", "relay_saturation", "0").is_err());
+        assert!(check("hbbs", "relay_saturation", "1").is_ok());
+        assert!(check("hbbr", "limit_speed", "-1").is_err());
+        assert!(check("hbbs", "mask", "192.168.0.0/16").is_ok());
+        assert!(check("hbbs", "mask", "::/0").is_err());
+        assert!(check("hbbs", "bind", "::1").is_ok());
+        assert!(check("hbbr", "downgrade_policy", "Congestion").is_ok());
+        assert_eq!(
+            check("hbbr", "downgrade_policy", "fair").unwrap_err(),
+            "invalid DOWNGRADE_POLICY \"fair\": expected one of average, congestion"
+        );
+        // only read by hbbr
+        assert!(check("hbbs", "limit_speed", "x").is_ok());
+        assert!(is_known("limit-speed"));
+        assert!(!is_known("limit-sped"));
+        assert_eq!(find("hbbr", "key").unwrap().default, None);
+        assert_eq!(find("hbbs", "key").unwrap().default, Some("-"));
+        assert_eq!(find("hbbr", "db_url").unwrap().default, None);
+    }
+
+    #[test]
+    fn converts_toml_values() {
+        let v: toml::Table =
+            toml::from_str("a = 'x'\nb = 2\nc = 0.5\nd = true\ne = ['h1', 'h2:21117']\nf = [1]\n")
+                .unwrap();
+        let get = |k: &str| from_toml(&v[k]);
+        assert_eq!(get("a").as_deref(), Some("x"));
+        assert_eq!(get("b").as_deref(), Some("2"));
+        assert_eq!(get("c").as_deref(), Some("0.5"));
+        assert_eq!(get("d").as_deref(), Some("Y"));
+        assert_eq!(get("e").as_deref(), Some("h1,h2:21117"));
+        assert_eq!(get("f"), None);
+        assert_eq!(Kind::Int(0, 1).to_toml("1"), "1");
+        assert_eq!(Kind::Str.to_toml("a\"b"), "\"a\\\"b\"");
+    }
+}
@@ -0,0 +1,68 @@
+// Locks or queues split by key so that concurrent access to different keys
+// rarely contends, e.g. the peer map touched by every heartbeat.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+};
+
+pub(crate) const SHARDS: usize = 64;
+
+pub(crate) struct Sharded<T> {
+    shards: Box<[T]>,
+    hasher: RandomState,
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Self::new((0..SHARDS).map(|_| T::default()))
+    }
+}
+
+impl<T> Sharded<T> {
+    /// Panics without shards.
+    pub(crate) fn new(shards: impl IntoIterator<Item = T>) -> Self {
+        let shards: Box<[T]> = shards.into_iter().collect();
+        assert!(!shards.is_empty());
+        Self {
+            shards,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// The shard of `key`.
+    #[inline]
+    pub(crate) fn get<K: Hash + ?Sized>(&self, key: &K) -> &T {
+        let i = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[i]
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.shards.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[test]
+    fn spreads_keys() {
+        let sharded = Sharded::<Mutex<HashMap<String, usize>>>::default();
+        assert_eq!(sharded.iter().count(), SHARDS);
+        for i in 0..1000 {
+            let id = format!("{i}");
+            sharded.get(&id).lock().unwrap().insert(id, i);
+        }
+        // looked up by &str as inserted by String, in the same shard
+        for i in 0..1000 {
+            let id = format!("{i}");
+            assert_eq!(sharded.get(id.as_str()).lock().unwrap().get(&id), Some(&i));
+        }
+        let lens: Vec<_> = sharded.iter().map(|x| x.lock().unwrap().len()).collect();
+        assert_eq!(lens.iter().sum::<usize>(), 1000);
+        assert!(lens.iter().filter(|x| **x > 0).count() > SHARDS / 2);
+        assert!(lens.iter().all(|x| *x < 100), "{:?}", lens);
+    }
+}
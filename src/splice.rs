@@ -0,0 +1,128 @@
+// Relaying between two TCP sockets with splice(2): the bytes go from one socket
+// to a pipe and from the pipe to the other socket within the kernel, instead of
+// being copied to userspace and back. Linux only.
+
+use hbb_common::tokio::{io::Interest, net::TcpStream};
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+// the default capacity of a pipe
+const CHUNK: usize = 1 << 16;
+
+/// A pipe holding the bytes spliced from one socket until they are spliced to
+/// the other one.
+pub(crate) struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    // bytes in the pipe
+    len: usize,
+}
+
+impl Pipe {
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: just created and not owned by anything else
+        Ok(unsafe {
+            Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+                len: 0,
+            }
+        })
+    }
+
+    /// Moves up to CHUNK received bytes from `src` into the empty pipe, waiting
+    /// for some. Returns how many, 0 at the end of the stream. Cancel safe.
+    pub(crate) async fn fill(&mut self, src: &TcpStream) -> io::Result<usize> {
+        debug_assert_eq!(self.len, 0);
+        let fd = self.write.as_raw_fd();
+        // the pipe being empty, EAGAIN means that nothing was received
+        let n = src
+            .async_io(Interest::READABLE, || splice(src.as_raw_fd(), fd, CHUNK))
+            .await?;
+        self.len = n;
+        Ok(n)
+    }
+
+    /// Moves the bytes in the pipe to `dst`.
+    pub(crate) async fn drain(&mut self, dst: &TcpStream) -> io::Result<()> {
+        let fd = self.read.as_raw_fd();
+        while self.len > 0 {
+            let len = self.len;
+            let n = dst
+                .async_io(Interest::WRITABLE, || splice(fd, dst.as_raw_fd(), len))
+                .await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.len -= n;
+        }
+        Ok(())
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: no offsets, which sockets and pipes do not have
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            flags,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as _)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(client, listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[hbb_common::tokio::test]
+    async fn splices_between_sockets() {
+        let (mut a, src) = connected().await;
+        let (dst, mut b) = connected().await;
+        let mut pipe = Pipe::new().unwrap();
+        let data: Vec<u8> = (0..CHUNK * 3).map(|i| i as u8).collect();
+        a.write_all(&data).await.unwrap();
+        a.shutdown().await.unwrap();
+        let mut relayed = 0;
+        loop {
+            let n = pipe.fill(&src).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(n <= CHUNK);
+            pipe.drain(&dst).await.unwrap();
+            relayed += n;
+        }
+        assert_eq!(relayed, data.len());
+        drop(dst);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        assert!(received == data);
+    }
+}
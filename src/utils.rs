@@ -14,6 +14,7 @@ fn print_help() {
 Available Commands:
     genkeypair                                   Generate a new keypair
     validatekeypair [public key] [secret key]    Validate an existing keypair
+    genapitoken [api secret] [days(default=30)]  Generate a bearer token for the hbbs API
     doctor [rustdesk-server]                     Check for server connection problems"
     );
     process::exit(0x0001);
@@ -72,6 +73,13 @@ fn validate_keypair(pk: &str, sk: &str) -> ResultType<()> {
     Ok(())
 }
 
+fn gen_api_token(secret: &str, days: &str) -> ResultType<()> {
+    let days: u64 = days.parse()?;
+    let token = hbbs::api::gen_token(secret, &whoami::username(), days)?;
+    println!("{token}");
+    Ok(())
+}
+
 fn doctor_tcp(address: std::net::IpAddr, port: &str, desc: &str) {
     let start = std::time::Instant::now();
     let conn = format!("{address}:{port}");
@@ -159,6 +167,16 @@ fn main() {
             }
             println!("Key pair is VALID");
         }
+        "genapitoken" => {
+            if args.len() <= 2 {
+                error_then_help("You must supply the API secret");
+            }
+            let days = args.get(3).map(|x| x.as_str()).unwrap_or("30");
+            if let Err(e) = gen_api_token(args[2].as_str(), days) {
+                println!("{e}");
+                process::exit(0x0001);
+            }
+        }
         "doctor" => {
             if args.len() <= 2 {
                 error_then_help("You must supply the rustdesk-server address");
@@ -0,0 +1,54 @@
+//! Helpers shared by the benchmarks.
+
+use std::{
+    net::{TcpListener, UdpSocket},
+    process::{Child, Command, Stdio},
+};
+
+pub fn env(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+/// A started hbbs or hbbr, killed when dropped.
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+/// A port free for hbbs, which listens on TCP port - 1, port and port + 2 and
+/// UDP port, and so for hbbr too.
+fn free_port() -> u16 {
+    (30000..60000)
+        .step_by(10)
+        .find(|&port| {
+            TcpListener::bind(("0.0.0.0", port - 1)).is_ok()
+                && TcpListener::bind(("0.0.0.0", port)).is_ok()
+                && TcpListener::bind(("0.0.0.0", port + 2)).is_ok()
+                && UdpSocket::bind(("0.0.0.0", port)).is_ok()
+        })
+        .expect("no free port")
+}
+
+/// Starts the server `bin` on a free port in a temporary directory, with the
+/// settings `envs`, and returns the port.
+pub fn start_server(name: &str, bin: &str, envs: &[(&str, &str)]) -> (Server, u16) {
+    let dir = std::env::temp_dir().join(format!("{}-bench-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let port = free_port();
+    let child = Command::new(bin)
+        .current_dir(&dir)
+        .args(["-p", &port.to_string()])
+        .envs(envs.iter().copied())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|err| panic!("start {}: {}", name, err));
+    println!("{} started on port {} in {}", name, port, dir.display());
+    (Server(child), port)
+}
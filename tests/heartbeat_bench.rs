@@ -0,0 +1,193 @@
+//! Load test of the UDP heartbeats of hbbs, ignored by default:
+//!
+//! ```text
+//! cargo test --release --test heartbeat_bench -- --ignored --nocapture
+//! ```
+//!
+//! Registers `BENCH_PEERS` (10000) IDs from loopback addresses 127.1.x.y, then
+//! sends `RegisterPeer` heartbeats for them for `BENCH_SECS` (10) seconds, one
+//! in flight per socket, and prints how fast hbbs answered. `BENCH_SERVER`
+//! points it at a running hbbs, e.g. `127.0.0.1:21116`, otherwise one is started
+//! in a temporary directory. Each socket registers `PEERS_PER_SOCKET` IDs, raise
+//! the open files limit (`ulimit -n`) for more than about 25000 peers.
+
+use hbb_common::{
+    protobuf::Message,
+    rendezvous_proto::*,
+    tokio::{
+        self,
+        net::UdpSocket,
+        time::{sleep, timeout, Duration, Instant},
+    },
+};
+use std::net::SocketAddr;
+
+mod common;
+use common::{env, start_server};
+
+// the IP blocker of hbbs allows 30 RegisterPk per IP and minute
+const PEERS_PER_SOCKET: usize = 25;
+const TIMEOUT: Duration = Duration::from_secs(1);
+// of the clients
+const HEARTBEAT_INTERVAL: u64 = 12;
+
+fn register_peer(id: &str) -> Vec<u8> {
+    let mut msg = RendezvousMessage::new();
+    msg.set_register_peer(RegisterPeer {
+        id: id.to_owned(),
+        ..Default::default()
+    });
+    msg.write_to_bytes().unwrap()
+}
+
+fn register_pk(id: &str) -> Vec<u8> {
+    let mut msg = RendezvousMessage::new();
+    msg.set_register_pk(RegisterPk {
+        id: id.to_owned(),
+        uuid: id.as_bytes().to_vec().into(),
+        pk: vec![7u8; 32].into(),
+        ..Default::default()
+    });
+    msg.write_to_bytes().unwrap()
+}
+
+async fn request(socket: &UdpSocket, msg: &[u8]) -> Option<RendezvousMessage> {
+    let mut buf = [0u8; 1024];
+    socket.send(msg).await.ok()?;
+    let n = timeout(TIMEOUT, socket.recv(&mut buf)).await.ok()?.ok()?;
+    RendezvousMessage::parse_from_bytes(&buf[..n]).ok()
+}
+
+async fn wait_ready(server: SocketAddr) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server).await.unwrap();
+    for _ in 0..30 {
+        if request(&socket, &register_peer("bench-probe"))
+            .await
+            .is_some()
+        {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("hbbs at {} does not answer", server);
+}
+
+#[derive(Default)]
+struct Stats {
+    answered: u64,
+    lost: u64,
+    // of the answered heartbeats
+    latencies: Vec<Duration>,
+}
+
+/// Registers `ids` from socket number `i`.
+async fn register(i: usize, ids: Vec<String>, server: SocketAddr) -> (UdpSocket, Vec<String>) {
+    let ip = [127, 1, (i / 250) as u8, (i % 250 + 1) as u8];
+    let socket = UdpSocket::bind(SocketAddr::from((ip, 0))).await.unwrap();
+    socket.connect(server).await.unwrap();
+    for id in &ids {
+        let res = request(&socket, &register_pk(id)).await;
+        match res.and_then(|x| x.union) {
+            Some(rendezvous_message::Union::RegisterPkResponse(res))
+                if res.result == register_pk_response::Result::OK.into() => {}
+            res => panic!("RegisterPk of {} failed: {:?}", id, res),
+        }
+    }
+    (socket, ids)
+}
+
+/// Sends the heartbeats of `ids` until `deadline`.
+async fn heartbeat(socket: UdpSocket, ids: Vec<String>, deadline: Instant) -> Stats {
+    let msgs: Vec<_> = ids.iter().map(|id| register_peer(id)).collect();
+    let mut stats = Stats::default();
+    for msg in msgs.iter().cycle() {
+        if Instant::now() >= deadline {
+            break;
+        }
+        let start = Instant::now();
+        match request(&socket, msg).await.and_then(|x| x.union) {
+            Some(rendezvous_message::Union::RegisterPeerResponse(_)) => {
+                stats.answered += 1;
+                stats.latencies.push(start.elapsed());
+            }
+            _ => stats.lost += 1,
+        }
+    }
+    stats
+}
+
+#[test]
+#[ignore]
+fn heartbeat_bench() {
+    bench();
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn bench() {
+    let peers = env("BENCH_PEERS", 10_000) as usize;
+    let secs = env("BENCH_SECS", 10);
+    let (_server, server) = match std::env::var("BENCH_SERVER") {
+        Ok(addr) => (None, addr.parse().expect("BENCH_SERVER")),
+        Err(_) => {
+            let (server, port) =
+                start_server("hbbs", env!("CARGO_BIN_EXE_hbbs"), &[("TEST_HBBS", "no")]);
+            (Some(server), ([127, 0, 0, 1], port).into())
+        }
+    };
+    wait_ready(server).await;
+    let ids: Vec<_> = (0..peers).map(|i| format!("bench{:07}", i)).collect();
+    let start = Instant::now();
+    let tasks: Vec<_> = ids
+        .chunks(PEERS_PER_SOCKET)
+        .enumerate()
+        .map(|(i, ids)| tokio::spawn(register(i, ids.to_vec(), server)))
+        .collect();
+    let mut sockets = Vec::new();
+    for task in tasks {
+        sockets.push(task.await.unwrap());
+    }
+    println!(
+        "{} peers registered from {} sockets in {:.1}s",
+        peers,
+        sockets.len(),
+        start.elapsed().as_secs_f64()
+    );
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(secs);
+    let tasks: Vec<_> = sockets
+        .into_iter()
+        .map(|(socket, ids)| tokio::spawn(heartbeat(socket, ids, deadline)))
+        .collect();
+    let mut total = Stats::default();
+    for task in tasks {
+        let stats = task.await.unwrap();
+        total.answered += stats.answered;
+        total.lost += stats.lost;
+        total.latencies.extend(stats.latencies);
+    }
+    let elapsed = start.elapsed();
+    total.latencies.sort_unstable();
+    let percentile = |p: usize| {
+        total
+            .latencies
+            .get(total.latencies.len() * p / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+    let rate = total.answered as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} heartbeats answered, {} lost in {:.1}s",
+        total.answered,
+        total.lost,
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "{:.0} heartbeats/s, latency p50 {:?} p99 {:?}, enough for {:.0} online devices",
+        rate,
+        percentile(50),
+        percentile(99),
+        rate * HEARTBEAT_INTERVAL as f64
+    );
+    assert!(total.answered > 0);
+}
@@ -0,0 +1,192 @@
+//! Throughput test of the relay of hbbr, ignored by default:
+//!
+//! ```text
+//! cargo test --release --test relay_bench -- --ignored --nocapture
+//! ```
+//!
+//! Pairs a TCP and a WebSocket client through hbbr and streams `BENCH_FRAME`
+//! (16384) byte frames one way for `BENCH_SECS` (5) seconds, for each direction,
+//! for two WebSocket clients and for two TCP clients, and prints how many
+//! arrived. `BENCH_RELAY` points it at a running hbbr, e.g. `192.0.2.1:21117`,
+//! otherwise one is started in a temporary directory with unlimited bandwidth,
+//! and on Linux the CPU time it took per GB is printed as well, which varies
+//! less than the throughput when hbbr and this test share few cores.
+//! `BENCH_HBBR` starts another build of hbbr instead of the one of this tree,
+//! to compare the two.
+//!
+//! hbbr takes TCP connections from loopback addresses for its console, so the
+//! TCP clients connect to the first non-loopback address of this host.
+
+use hbb_common::{
+    bytes::Bytes,
+    futures_util::{SinkExt, StreamExt},
+    protobuf::Message as _,
+    rendezvous_proto::*,
+    tcp::FramedStream,
+    tokio::{
+        self,
+        net::TcpStream,
+        time::{sleep, Duration, Instant},
+    },
+};
+use std::net::SocketAddr;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+mod common;
+use common::{env, start_server};
+
+/// The CPU time used by process `pid` so far.
+fn cpu_time(pid: u32) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // utime and stime in clock ticks, usually 100 per second, after the name
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let ticks: u64 = fields.next()?.parse::<u64>().ok()? + fields.next()?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(ticks * 10))
+}
+
+fn request_relay(uuid: &str) -> Vec<u8> {
+    let mut msg = RendezvousMessage::new();
+    msg.set_request_relay(RequestRelay {
+        uuid: uuid.to_owned(),
+        ..Default::default()
+    });
+    msg.write_to_bytes().unwrap()
+}
+
+enum Client {
+    Tcp(FramedStream),
+    Ws(WebSocketStream<MaybeTlsStream<TcpStream>>),
+}
+
+impl Client {
+    async fn connect(ws: bool, server: SocketAddr) -> Self {
+        if ws {
+            let url = format!("ws://{}:{}", server.ip(), server.port() + 2);
+            let (stream, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            Self::Ws(stream)
+        } else {
+            Self::Tcp(FramedStream::from(
+                TcpStream::connect(server).await.unwrap(),
+                server,
+            ))
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Tcp(_) => "tcp",
+            Self::Ws(_) => "websocket",
+        }
+    }
+
+    async fn send(&mut self, bytes: &Bytes) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.send_bytes(bytes.clone()).await.is_ok(),
+            Self::Ws(stream) => stream.send(Message::Binary(bytes.to_vec())).await.is_ok(),
+        }
+    }
+
+    /// The length of the next frame.
+    async fn recv(&mut self) -> Option<usize> {
+        match self {
+            Self::Tcp(stream) => Some(stream.next().await?.ok()?.len()),
+            Self::Ws(stream) => Some(stream.next().await?.ok()?.len()),
+        }
+    }
+}
+
+/// Two clients relayed to each other.
+async fn pair(server: SocketAddr, from_ws: bool, to_ws: bool) -> (Client, Client) {
+    let uuid = format!("bench-{}-{}", from_ws, to_ws);
+    let mut to = Client::connect(to_ws, server).await;
+    to.send(&request_relay(&uuid).into()).await;
+    // the second request of a uuid is paired with the first one
+    sleep(Duration::from_millis(200)).await;
+    let mut from = Client::connect(from_ws, server).await;
+    from.send(&request_relay(&uuid).into()).await;
+    (from, to)
+}
+
+/// Streams frames from one of a pair to the other for `secs` and prints the
+/// rate they arrived at.
+async fn run(
+    server: SocketAddr,
+    pid: Option<u32>,
+    (from_ws, to_ws): (bool, bool),
+    frame: usize,
+    secs: u64,
+) {
+    let (mut from, mut to) = pair(server, from_ws, to_ws).await;
+    let name = format!("{} -> {}", from.name(), to.name());
+    let bytes = Bytes::from(vec![7u8; frame]);
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    let sender = tokio::spawn(async move {
+        while Instant::now() < deadline && from.send(&bytes).await {}
+        // keeps the connection open until the receiver is done
+        from
+    });
+    let start = Instant::now();
+    let cpu = pid.and_then(cpu_time);
+    let (mut received, mut frames) = (0, 0);
+    while let Ok(Some(n)) = tokio::time::timeout_at(deadline, to.recv()).await {
+        received += n;
+        frames += 1;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let cpu = pid.and_then(cpu_time).zip(cpu).map(|(b, a)| b - a);
+    sender.await.ok();
+    print!(
+        "{:<24}{:>10.1} MB/s{:>10.0} frames/s",
+        name,
+        received as f64 / elapsed / 1e6,
+        frames as f64 / elapsed
+    );
+    match cpu {
+        Some(cpu) => println!(
+            "{:>8.2} CPU s/GB",
+            cpu.as_secs_f64() / received as f64 * 1e9
+        ),
+        None => println!(),
+    }
+    assert!(received > 0, "nothing relayed {}", name);
+}
+
+#[test]
+#[ignore]
+fn relay_bench() {
+    bench();
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn bench() {
+    let frame = env("BENCH_FRAME", 16384) as usize;
+    let secs = env("BENCH_SECS", 5);
+    let (child, server) = match std::env::var("BENCH_RELAY") {
+        Ok(addr) => (None, addr.parse().expect("BENCH_RELAY")),
+        Err(_) => {
+            let ip = local_ip_address::local_ip().expect("no non-loopback address");
+            let bin = std::env::var("BENCH_HBBR").unwrap_or(env!("CARGO_BIN_EXE_hbbr").to_owned());
+            let (server, port) = start_server(
+                "hbbr",
+                &bin,
+                &[
+                    ("SINGLE_BANDWIDTH", "1000000"),
+                    ("TOTAL_BANDWIDTH", "1000000"),
+                    ("AUDIT_LOG", "N"),
+                ],
+            );
+            (Some(server), (ip, port).into())
+        }
+    };
+    for _ in 0..30 {
+        if TcpStream::connect(server).await.is_ok() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let pid = child.as_ref().map(|x| x.0.id());
+    println!("{} byte frames for {}s each", frame, secs);
+    for pair in [(true, false), (false, true), (true, true), (false, false)] {
+        run(server, pid, pair, frame, secs).await;
+    }
+}